@@ -0,0 +1,48 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::Rng;
+
+use songrec::fingerprinting::algorithm::SignatureGenerator;
+
+/// Compare the time taken for generating signatures at each supported sample
+/// rate, with a new generator for each signature (as the recognition of files
+/// does) and with a generator reused from one signature to the next (as the
+/// microphone thread does):
+///
+///  cargo bench --bench signature
+
+const SAMPLE_RATES: [u32; 6] = [8000, 11025, 16000, 32000, 44100, 48000];
+
+// Signatures are made from 12 seconds of audio when listening
+const SIGNATURE_SECONDS: u32 = 12;
+
+fn make_white_noise(sample_rate: u32) -> Vec<i16> {
+    let mut rng = rand::thread_rng();
+
+    (0..sample_rate * SIGNATURE_SECONDS).map(|_| rng.gen_range(-8192, 8192)).collect()
+}
+
+fn bench_signature(c: &mut Criterion) {
+    let mut group = c.benchmark_group("signature");
+    group.sample_size(20);
+
+    for sample_rate in SAMPLE_RATES.iter() {
+        let samples = make_white_noise(*sample_rate);
+
+        group.throughput(Throughput::Elements(samples.len() as u64));
+
+        group.bench_with_input(BenchmarkId::new("new_generator", sample_rate), &samples, |b, samples| {
+            b.iter(|| SignatureGenerator::make_signature_from_buffer_at_sample_rate(samples, *sample_rate).unwrap())
+        });
+
+        let mut generator = SignatureGenerator::new_at_sample_rate(*sample_rate).unwrap();
+
+        group.bench_with_input(BenchmarkId::new("reused_generator", sample_rate), &samples, |b, samples| {
+            b.iter(|| generator.make_signature(samples))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_signature);
+criterion_main!(benches);
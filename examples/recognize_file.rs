@@ -0,0 +1,39 @@
+use std::error::Error;
+
+use songrec::core::recognizer::{Recognizer, RecognizerEvent};
+
+/// Recognize an audio file through the "Recognizer" facade, the way an
+/// application embedding SongRec would:
+///
+///  cargo run --example recognize_file -- song.mp3
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let file_path = std::env::args().nth(1).ok_or("Usage: recognize_file AUDIO_FILE")?;
+
+    let builder = Recognizer::builder();
+    let events = builder.subscribe();
+    let recognizer = builder.build()?;
+
+    recognizer.recognize_file(&file_path);
+
+    for event in events {
+        match event {
+            RecognizerEvent::SongRecognized(song) => {
+                println!("{} - {}", song.artist_name, song.song_name);
+                return Ok(());
+            },
+            RecognizerEvent::ErrorMessage(error) => {
+                return Err(error.into());
+            },
+            RecognizerEvent::NoMatch => {
+                return Err("No match for this song".into());
+            },
+            RecognizerEvent::NetworkStatus(false) => {
+                return Err("The network is unreachable".into());
+            },
+            _ => { }
+        }
+    }
+
+    Err("The recognizer stopped".into())
+}
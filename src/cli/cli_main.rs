@@ -1,23 +1,27 @@
 use std::error::Error;
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::sync::{Arc, mpsc};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use glib;
-use glib::clone;
 use gettextrs::gettext;
 use chrono::Local;
 
-use mpris_player::PlaybackStatus;
 
-use crate::core::microphone_thread::microphone_thread;
-use crate::core::processing_thread::processing_thread;
-use crate::core::http_thread::http_thread;
-use crate::core::thread_messages::{GUIMessage, MicrophoneMessage, ProcessingMessage};
+use crate::core::recognizer::{Recognizer, RecognizerEvent};
+use crate::cli::http_api::HttpApi;
+use crate::core::capture_config::CaptureConfig;
+use crate::fingerprinting::preprocessing::SourcePreprocessing;
 
+use crate::utils::application_capture::APPLICATION_DEVICE_PREFIX;
 use crate::utils::csv_song_history::SongHistoryRecord;
-use crate::utils::mpris_player::{get_player, update_song};
-use crate::utils::thread::spawn_big_thread;
+use crate::utils::dbus_service::{start_dbus_service, DBusControl};
+use crate::utils::hooks::{HooksConfig, HookRunner};
+use crate::utils::mpris_player::{get_player, update_song, MprisControl};
+
+// Input levels are received at about 24 Hz, but printed less often in JSON
+// mode, so that they don't drown the recognized songs
+
+const JSON_LEVEL_INTERVAL: Duration = Duration::from_secs(1);
 
 pub enum CLIOutputType {
     SongName,
@@ -27,35 +31,24 @@ pub enum CLIOutputType {
 
 pub struct CLIParameters {
     pub enable_mpris: bool,
+    pub enable_dbus_service: bool,
     pub recognize_once: bool,
     pub audio_device: Option<String>,
     pub input_file: Option<String>,
-    pub output_type: CLIOutputType
+    pub output_type: CLIOutputType,
+    pub hooks: HooksConfig,
+    pub capture_config: CaptureConfig,
+    pub preprocessing: SourcePreprocessing,
+    pub http_address: Option<String> // Address of the optional HTTP API, such as "127.0.0.1:8080"
 }
 
 pub fn cli_main(parameters: CLIParameters) -> Result<(), Box<dyn Error>> {
-    glib::MainContext::default().acquire();
-    let main_loop = Arc::new(glib::MainLoop::new(None, false));
-
-    let (gui_tx, gui_rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
-    let (microphone_tx, microphone_rx) = mpsc::channel();
-    let (processing_tx, processing_rx) = mpsc::channel();
-    let (http_tx, http_rx) = mpsc::channel();
-
-    let processing_microphone_tx = processing_tx.clone();
-    let microphone_http_tx = microphone_tx.clone();
+    let recognizer_builder = Recognizer::builder()
+        .capture_config(parameters.capture_config.clone())
+        .preprocessing(parameters.preprocessing.clone());
 
-    spawn_big_thread(clone!(@strong gui_tx => move || { // microphone_rx, processing_tx
-        microphone_thread(microphone_rx, processing_microphone_tx, gui_tx);
-    }));
-    
-    spawn_big_thread(clone!(@strong gui_tx => move || { // processing_rx, http_tx
-        processing_thread(processing_rx, http_tx, gui_tx);
-    }));
-    
-    spawn_big_thread(clone!(@strong gui_tx => move || { // http_rx
-        http_thread(http_rx, gui_tx, microphone_http_tx);
-    }));
+    let events = recognizer_builder.subscribe();
+    let recognizer = recognizer_builder.build()?;
 
     // recognize once if an input file is provided
     let do_recognize_once = parameters.recognize_once || parameters.input_file.is_some();
@@ -63,83 +56,272 @@ pub fn cli_main(parameters: CLIParameters) -> Result<(), Box<dyn Error>> {
     // do not enable mpris if recognizing one song
     let do_enable_mpris = parameters.enable_mpris && !do_recognize_once;
 
-    let mpris_player = if do_enable_mpris { get_player() } else { None };
-    let last_track: Rc<RefCell<Option<String>>> = Rc::new(RefCell::new(None));
+    // The device used for listening, remembered so that listening can be
+    // restarted through MPRIS
+    let current_device_name: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+
+    let mpris_recognizer = recognizer.clone();
+    let mpris_device_name = current_device_name.clone();
+
+    let mpris_player = if do_enable_mpris {
+        get_player(move |control| {
+            match control {
+                MprisControl::StartListening => {
+                    if let Some(device_name) = mpris_device_name.lock().unwrap().clone() {
+                        mpris_recognizer.start_listening(&device_name);
+                    }
+                },
+                MprisControl::StopListening => {
+                    mpris_recognizer.stop_listening();
+                }
+            }
+        })
+    } else { None };
+
+    // Requests from the D-Bus control service are directly translated into
+    // requests to the recognizer (the reply of the recognition of a file goes
+    // through the usual "SongRecognized", "NoMatch" or "ErrorMessage" events)
+
+    let dbus_recognizer = recognizer.clone();
+    let dbus_device_name = current_device_name.clone();
+
+    let dbus_service = if parameters.enable_dbus_service && !do_recognize_once {
+        start_dbus_service(None, move |control| {
+            match control {
+                DBusControl::StartListening(device_name) => {
+                    *dbus_device_name.lock().unwrap() = Some(device_name.clone());
+                    dbus_recognizer.start_listening(&device_name);
+                },
+                DBusControl::StopListening => {
+                    dbus_recognizer.stop_listening();
+                },
+                DBusControl::SelectDevice(device_name, restart_listening) => {
+                    *dbus_device_name.lock().unwrap() = Some(device_name.clone());
+                    if restart_listening {
+                        dbus_recognizer.restart_listening(&device_name);
+                    }
+                },
+                DBusControl::RecognizeFile(file_path) => {
+                    dbus_recognizer.recognize_file(&file_path);
+                }
+            }
+        })
+    } else { None };
+
+    let http_api = match parameters.http_address {
+        Some(ref address) => Some(HttpApi::start(address, current_device_name.clone(), &parameters.capture_config.source_id, recognizer.clone())?),
+        None => None
+    };
+
+    // The last track recognized from each capture source (None for files)
+    let mut last_tracks: HashMap<Option<String>, String> = HashMap::new();
 
-    let main_loop_cli = main_loop.clone();
+    // Results are tagged with their capture source when there are several
+    let several_sources = !parameters.capture_config.sources.is_empty();
 
     let audio_dev_name = parameters.audio_device.as_ref().map(|dev| dev.to_string());
     let input_file_name = parameters.input_file.as_ref().map(|dev| dev.to_string());
 
     if let Some(ref filename) = parameters.input_file {
-        processing_tx.send(ProcessingMessage::ProcessAudioFile(filename.to_string())).unwrap();
+        recognizer.recognize_file(filename);
     }
     
     let mut csv_writer = csv::Writer::from_writer(std::io::stdout());
 
-    gui_rx.attach(None, move |gui_message| {
-        match gui_message {
-            GUIMessage::DevicesList(device_names) => {
+    let mut hook_runner = HookRunner::new(parameters.hooks.clone());
+
+    // The device list is sent again when devices are plugged or unplugged
+    let mut devices_listed_once = false;
+
+    // Warn about input level issues only when they appear (by capture source)
+    let mut clipping_sources: HashSet<String> = HashSet::new();
+    let mut too_quiet_sources: HashSet<String> = HashSet::new();
+
+    // When the level of each capture source was last printed in JSON mode
+    let mut last_level_outputs: HashMap<String, Instant> = HashMap::new();
+
+    for event in events {
+        if let Some(http_api) = &http_api {
+            http_api.handle_gui_message(&event);
+        }
+
+        match event {
+            RecognizerEvent::DevicesList(device_names) => {
                 // no need to start a microphone if recognizing from file
                 if input_file_name.is_some() {
-                    return glib::Continue(true);
+                    continue;
                 }
+                if let Some(dbus_service) = &dbus_service {
+                    dbus_service.set_devices(&device_names);
+                }
+                if devices_listed_once {
+                    continue;
+                }
+                devices_listed_once = true;
                 let dev_name = if let Some(dev) = &audio_dev_name {
-                    if !device_names.contains(dev) {
+                    // Applications are matched by the microphone thread
+                    // using a part of their name
+                    if !device_names.contains(dev) && !dev.starts_with(APPLICATION_DEVICE_PREFIX) {
                         eprintln!("{}", gettext("Exiting: audio device not found"));
-                        main_loop_cli.quit();
-                        return glib::Continue(false);
+                        break;
                     }
                     dev
                 } else {
                     if device_names.is_empty() {
                         eprintln!("{}", gettext("Exiting: no audio devices found!"));
-                        main_loop_cli.quit();
-                        return glib::Continue(false);
+                        break;
                     }
                     &device_names[0]
                 };
                 eprintln!("{} {}", gettext("Using device"), dev_name);
-                microphone_tx.send(MicrophoneMessage::MicrophoneRecordStart(dev_name.to_owned())).unwrap();
+                *current_device_name.lock().unwrap() = Some(dev_name.to_owned());
+                if let Some(dbus_service) = &dbus_service {
+                    dbus_service.set_current_device(dev_name);
+                }
+                recognizer.start_listening(dev_name);
             },
-            GUIMessage::NetworkStatus(reachable) => {
-                let mpris_status = if reachable { PlaybackStatus::Playing } else { PlaybackStatus::Paused };
-                mpris_player.as_ref().map(|p| p.set_playback_status(mpris_status));
+            RecognizerEvent::NetworkStatus(reachable) => {
+                if let Some(hook_runner) = &mut hook_runner {
+                    hook_runner.network_status(reachable);
+                }
+
+                if let Some(mpris_player) = &mpris_player {
+                    mpris_player.set_network_reachable(reachable);
+                }
 
                 if !reachable {
                     if input_file_name.is_some() {
                         eprintln!("{}", gettext("Error: Network unreachable"));
-                        main_loop_cli.quit();
-                        return glib::Continue(false);
+                        break;
                     }
                     else {
                         eprintln!("{}", gettext("Warning: Network unreachable"));
                     }
                 }
             },
-            GUIMessage::ErrorMessage(string) => {
-                if !(string == gettext("No match for this song") && !input_file_name.is_some()) {
-                    eprintln!("{} {}", gettext("Error:"), string);
+            RecognizerEvent::ErrorMessage(string) => {
+                if let Some(dbus_service) = &dbus_service {
+                    dbus_service.error(&string);
+                }
+                eprintln!("{} {}", gettext("Error:"), string);
+                if input_file_name.is_some() {
+                    break;
+                }
+            },
+            RecognizerEvent::NoMatch => {
+                if let Some(hook_runner) = &mut hook_runner {
+                    hook_runner.no_match();
+                }
+                if let Some(dbus_service) = &dbus_service {
+                    dbus_service.error(&gettext("No match for this song"));
                 }
+                // no match is only an error when recognizing a file
                 if input_file_name.is_some() {
-                    main_loop_cli.quit();
-                    return glib::Continue(false);
+                    eprintln!("{} {}", gettext("Error:"), gettext("No match for this song"));
+                    break;
                 }
             },
-            GUIMessage::MicrophoneRecording => {
+            RecognizerEvent::MicrophoneError(error) => {
+                if let Some(dbus_service) = &dbus_service {
+                    dbus_service.error(&error.to_string());
+                }
+                eprintln!("{} {}", gettext("Error:"), error);
+            },
+            RecognizerEvent::MicrophoneRecording => {
+                if let Some(mpris_player) = &mpris_player {
+                    mpris_player.set_listening(true);
+                }
+                if let Some(dbus_service) = &dbus_service {
+                    // listening may have been started with another device
+                    // through D-Bus, MPRIS or the HTTP API
+                    if let Some(device_name) = current_device_name.lock().unwrap().as_ref() {
+                        dbus_service.set_current_device(device_name);
+                    }
+                    dbus_service.set_listening(true);
+                }
                 if !do_recognize_once {
                     eprintln!("{}", gettext("Recording started!"));
                 }
             },
-            GUIMessage::SongRecognized(message) => {
-                let mut last_track_borrow = last_track.borrow_mut();
-                let track_key = Some(message.track_key.clone());
+            // One-shot commands print a single JSON document, so levels are
+            // only printed as JSON lines when listening continuously
+            RecognizerEvent::MicrophoneInputLevel(source_id, level) if matches!(parameters.output_type, CLIOutputType::JSON) && !do_recognize_once => {
+                let printed_recently = last_level_outputs.get(&source_id)
+                    .is_some_and(|last_output| last_output.elapsed() < JSON_LEVEL_INTERVAL);
+                if !printed_recently {
+                    println!("{}", serde_json::json!({
+                        "type": "level",
+                        "source": source_id,
+                        "rms_dbfs": level.rms_dbfs,
+                        "true_peak_dbfs": level.true_peak_dbfs,
+                        "peak_hold_dbfs": level.peak_hold_dbfs,
+                        "clipped_samples": level.clipped_samples,
+                        "too_quiet": level.too_quiet
+                    }));
+                    last_level_outputs.insert(source_id, Instant::now());
+                }
+            },
+            RecognizerEvent::MicrophoneInputLevel(source_id, level) => {
+                let source_prefix = match several_sources {
+                    true => format!("[{}] ", source_id),
+                    false => "".to_string()
+                };
+                if level.clipped_samples > 0 && !clipping_sources.contains(&source_id) {
+                    eprintln!("{}{}", source_prefix, gettext("Warning: the audio input is clipping, lower its volume"));
+                }
+                if level.too_quiet && !too_quiet_sources.contains(&source_id) {
+                    eprintln!("{}{}", source_prefix, gettext("Warning: the audio input is too quiet to recognize songs"));
+                }
+                // the held peak stays near 0 dBFS for a while after clipping
+                if level.clipped_samples > 0 || level.peak_hold_dbfs >= -0.1 {
+                    clipping_sources.insert(source_id.clone());
+                }
+                else {
+                    clipping_sources.remove(&source_id);
+                }
+                if level.too_quiet {
+                    too_quiet_sources.insert(source_id);
+                }
+                else {
+                    too_quiet_sources.remove(&source_id);
+                }
+            },
+            RecognizerEvent::MicrophoneStopped => {
+                if let Some(mpris_player) = &mpris_player {
+                    mpris_player.set_listening(false);
+                }
+                if let Some(dbus_service) = &dbus_service {
+                    dbus_service.set_listening(false);
+                }
+                eprintln!("{}", gettext("Recording stopped!"));
+                // nothing can restart listening when recognizing one song
+                if do_recognize_once {
+                    break;
+                }
+            },
+            RecognizerEvent::SongRecognized(message) => {
                 let song_name = format!("{} - {}", message.artist_name, message.song_name);
 
-                if *last_track_borrow != track_key {
-                    mpris_player.as_ref().map(|p| update_song(p, &message));
-                    *last_track_borrow = track_key;
+                // the same track is usually recognized several times in a row
+                // from a given source
+                if last_tracks.get(&message.source_id) != Some(&message.track_key) {
+                    if let Some(mpris_player) = &mpris_player {
+                        update_song(mpris_player, &message);
+                    }
+                    if let Some(hook_runner) = &mut hook_runner {
+                        hook_runner.song_recognized(&message);
+                    }
+                    if let Some(dbus_service) = &dbus_service {
+                        dbus_service.song_recognized(&message);
+                    }
+                    last_tracks.insert(message.source_id.clone(), message.track_key.clone());
+                    let source_id = message.source_id.clone().unwrap_or_default();
                     match parameters.output_type {
+                        CLIOutputType::JSON if several_sources => {
+                            let mut json_object: serde_json::Value = serde_json::from_str(&message.shazam_json).unwrap_or(serde_json::Value::Null);
+                            json_object["source"] = serde_json::json!(source_id);
+                            println!("{}", json_object);
+                        },
                         CLIOutputType::JSON => {
                             println!("{}", message.shazam_json);
                         },
@@ -151,24 +333,25 @@ pub fn cli_main(parameters: CLIParameters) -> Result<(), Box<dyn Error>> {
                                 track_key: message.track_key,
                                 release_year: message.release_year.as_ref().unwrap_or(&"".to_string()).to_string(),
                                 genre: message.genre.as_ref().unwrap_or(&"".to_string()).to_string(),
+                                source: source_id
                             }).unwrap();
                             csv_writer.flush().unwrap();
                         },
+                        CLIOutputType::SongName if several_sources => {
+                            println!("[{}] {}", source_id, song_name);
+                        },
                         CLIOutputType::SongName => {
                             println!("{}", song_name);
                         }
                     };
                 }
                 if do_recognize_once {
-                    main_loop_cli.quit();
-                    return glib::Continue(false);
+                    break;
                 }
             },
             _ => { }
         }
-        glib::Continue(true)
-    });
+    }
 
-    main_loop.run();
     Ok(())
 }
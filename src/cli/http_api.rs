@@ -0,0 +1,382 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::io::Write;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+
+use chrono::Local;
+use gettextrs::gettext;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::core::level_meter::InputLevel;
+use crate::core::recognizer::Recognizer;
+use crate::core::thread_messages::{GUIMessage, SongRecognizedMessage};
+use crate::fingerprinting::signature_format::DecodedSignature;
+
+/// This module contains an optional embedded HTTP server for the "listen"
+/// subcommand (enabled with "--http 127.0.0.1:8080"), allowing dashboards
+/// and scripts to follow what SongRec is hearing:
+///
+///  - GET /api/current: the last recognized track, or null
+///  - GET /api/history: the tracks recognized since the launch (with the
+///    capture source they were heard from)
+///  - GET /api/volume: the current microphone level (as a percentage, and as
+///    RMS and true peak levels in dBFS, with clipping counters), along with
+///    the levels of each capture source
+///  - GET /api/devices: the audio devices, and the one used for listening
+///  - GET /api/status: whether SongRec is listening, and whether the
+///    network is reachable
+///  - GET /api/events: a Server-Sent Events stream, with one event for each
+///    inter-thread message received by the main loop
+///  - POST /api/start, with an optional {"device": "..."} body
+///  - POST /api/stop
+///  - POST /api/recording/save: save the audio currently heard (see
+///    "audio_recorder.rs")
+///  - POST /api/recognize/file, with a {"path": "..."} body
+///  - POST /api/recognize/fingerprint, with a {"fingerprint": "data:..."} body
+///
+/// Recognitions requested through POST are asynchronous: their results are
+/// published through the event stream and the "current" endpoint.
+///
+/// There is no authentication, so the server should only be bound to a
+/// trusted interface. POST requests must carry a JSON content type, so that
+/// web pages from other origins can't trigger them without a CORS preflight
+/// (which is never granted).
+
+const MAX_HISTORY_LENGTH: usize = 100;
+
+struct ApiState {
+    current_track: Option<Value>,
+    history: VecDeque<Value>,
+    input_levels: BTreeMap<String, InputLevel>, // By capture source id
+    devices: Vec<String>,
+    listening: bool,
+    network_reachable: bool
+}
+
+struct ApiContext {
+    state: Mutex<ApiState>,
+    current_device_name: Arc<Mutex<Option<String>>>,
+    selected_source_id: String,
+    event_subscribers: Mutex<Vec<mpsc::Sender<String>>>,
+
+    recognizer: Mutex<Recognizer>
+}
+
+/// Handle kept by the CLI main loop, which passes it every GUIMessage it
+/// receives.
+
+pub struct HttpApi {
+    context: Arc<ApiContext>
+}
+
+impl HttpApi {
+
+    /// Bind the server to the given address, and handle requests from a
+    /// dedicated thread. The device name is shared with the main loop, which
+    /// sets it when choosing the initial device, and the level of its capture
+    /// source is the one reported first.
+
+    pub fn start(address: &str,
+            current_device_name: Arc<Mutex<Option<String>>>,
+            selected_source_id: &str,
+            recognizer: Recognizer) -> Result<Self, Box<dyn Error>> {
+
+        let server = Server::http(address).map_err(|error| format!("{} {}: {}", gettext("Unable to listen on"), address, error))?;
+
+        let context = Arc::new(ApiContext {
+            state: Mutex::new(ApiState {
+                current_track: None,
+                history: VecDeque::new(),
+                input_levels: BTreeMap::new(),
+                devices: vec![],
+                listening: false,
+                network_reachable: true
+            }),
+            current_device_name,
+            selected_source_id: selected_source_id.to_string(),
+            event_subscribers: Mutex::new(vec![]),
+            recognizer: Mutex::new(recognizer)
+        });
+
+        let server_context = context.clone();
+
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let request_context = server_context.clone();
+
+                // The event stream keeps its connection open, so that
+                // each request gets its own thread
+
+                thread::spawn(move || {
+                    handle_request(&request_context, request);
+                });
+            }
+        });
+
+        Ok(HttpApi {
+            context
+        })
+    }
+
+    pub fn handle_gui_message(self: &Self, message: &GUIMessage) {
+        {
+            let mut state = self.context.state.lock().unwrap();
+
+            match message {
+                GUIMessage::DevicesList(device_names) => {
+                    state.devices = device_names.to_vec();
+                },
+                GUIMessage::NetworkStatus(reachable) => {
+                    state.network_reachable = *reachable;
+                },
+                GUIMessage::MicrophoneRecording => {
+                    state.listening = true;
+                },
+                GUIMessage::MicrophoneStopped => {
+                    state.listening = false;
+                },
+                GUIMessage::MicrophoneInputLevel(source_id, level) => {
+                    state.input_levels.insert(source_id.to_string(), *level);
+                },
+                GUIMessage::SongRecognized(song) => {
+                    let is_new_track = state.history.iter()
+                        .find(|track| track["source"] == json!(song.source_id))
+                        .map(|track| track["track_key"] != json!(song.track_key))
+                        .unwrap_or(true);
+
+                    // The same track is usually recognized several times
+                    // in a row while listening (from a given capture source)
+
+                    if is_new_track {
+                        let track = track_to_json(song);
+
+                        state.history.push_front(track.clone());
+                        state.history.truncate(MAX_HISTORY_LENGTH);
+                        state.current_track = Some(track);
+                    }
+                },
+                _ => { }
+            };
+        }
+
+        if let Some((event_name, data)) = gui_message_to_event(message) {
+            let event = format!("event: {}\ndata: {}\n\n", event_name, data);
+
+            // Forget about the clients which disconnected
+
+            self.context.event_subscribers.lock().unwrap().retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        }
+    }
+
+}
+
+fn track_to_json(song: &SongRecognizedMessage) -> Value {
+    json!({
+        "artist_name": song.artist_name,
+        "song_name": song.song_name,
+        "album_name": song.album_name,
+        "track_key": song.track_key,
+        "release_year": song.release_year,
+        "genre": song.genre,
+        "track_url": song.track_url,
+        "recognition_date": Local::now().to_rfc3339(),
+        "source": song.source_id,
+        "shazam_json": serde_json::from_str::<Value>(&song.shazam_json).unwrap_or(Value::Null)
+    })
+}
+
+fn level_to_json(level: Option<&InputLevel>) -> Value {
+    match level {
+        Some(level) => {
+            let mut value = json!(level);
+            value["percent"] = json!(level.bar_fraction() * 100.0);
+            value
+        },
+        None => json!({ "percent": 0.0 })
+    }
+}
+
+fn gui_message_to_event(message: &GUIMessage) -> Option<(&'static str, Value)> {
+    match message {
+        GUIMessage::ErrorMessage(string) => Some(("error", json!({ "message": string }))),
+        GUIMessage::NoMatch => Some(("no_match", json!({}))),
+        GUIMessage::MicrophoneError(error) => Some(("microphone_error", json!({ "message": error.to_string() }))),
+        GUIMessage::DevicesList(device_names) => Some(("devices", json!({ "devices": device_names.to_vec() }))),
+        GUIMessage::NetworkStatus(reachable) => Some(("network_status", json!({ "reachable": reachable }))),
+        GUIMessage::MicrophoneRecording => Some(("recording_started", json!({}))),
+        GUIMessage::MicrophoneStopped => Some(("recording_stopped", json!({}))),
+        GUIMessage::MicrophoneInputLevel(source_id, level) => {
+            let mut value = level_to_json(Some(level));
+            value["source"] = json!(source_id);
+            Some(("volume", value))
+        },
+        GUIMessage::SongRecognized(song) => Some(("song_recognized", track_to_json(song))),
+        GUIMessage::WipeSongHistory => None
+    }
+}
+
+fn json_response(status_code: u16, body: &Value) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(body.to_string())
+        .with_status_code(status_code)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap())
+}
+
+fn error_response(status_code: u16, message: &str) -> Response<std::io::Cursor<Vec<u8>>> {
+    json_response(status_code, &json!({ "error": message }))
+}
+
+fn read_json_body(request: &mut Request) -> Result<Value, String> {
+    let has_json_content_type = request.headers().iter().any(|header| {
+        header.field.equiv("Content-Type") && header.value.as_str().starts_with("application/json")
+    });
+
+    if !has_json_content_type {
+        return Err(gettext("The request should have the \"application/json\" content type"));
+    }
+
+    let mut body = String::new();
+    request.as_reader().read_to_string(&mut body).map_err(|error| error.to_string())?;
+
+    if body.trim().is_empty() {
+        return Ok(json!({}));
+    }
+
+    serde_json::from_str(&body).map_err(|error| error.to_string())
+}
+
+fn handle_request(context: &ApiContext, mut request: Request) {
+    let path = request.url().split('?').next().unwrap_or("").to_string();
+
+    let response = match (request.method(), path.as_str()) {
+        (Method::Get, "/api/events") => {
+            serve_event_stream(context, request);
+            return;
+        },
+        (Method::Get, _) => {
+            let state = context.state.lock().unwrap();
+
+            let body = match path.as_str() {
+                "/api/current" => Some(json!(state.current_track)),
+                "/api/history" => Some(json!(state.history)),
+                "/api/volume" => {
+                    let mut value = level_to_json(state.input_levels.get(&context.selected_source_id));
+                    value["sources"] = state.input_levels.iter()
+                        .map(|(source_id, level)| (source_id.to_string(), level_to_json(Some(level))))
+                        .collect::<serde_json::Map<String, Value>>().into();
+                    Some(value)
+                },
+                "/api/devices" => Some(json!({
+                    "devices": state.devices,
+                    "current_device": *context.current_device_name.lock().unwrap()
+                })),
+                "/api/status" => Some(json!({
+                    "listening": state.listening,
+                    "network_reachable": state.network_reachable,
+                    "current_device": *context.current_device_name.lock().unwrap()
+                })),
+                _ => None
+            };
+
+            match body {
+                Some(body) => json_response(200, &body).with_header(Header::from_bytes(&b"Access-Control-Allow-Origin"[..], &b"*"[..]).unwrap()),
+                None => error_response(404, &gettext("Not found"))
+            }
+        },
+        (Method::Post, _) => {
+            match read_json_body(&mut request) {
+                Ok(body) => handle_post_request(context, &path, &body),
+                Err(error) => error_response(400, &error)
+            }
+        },
+        _ => error_response(405, &gettext("Method not allowed"))
+    };
+
+    request.respond(response).ok();
+}
+
+fn handle_post_request(context: &ApiContext, path: &str, body: &Value) -> Response<std::io::Cursor<Vec<u8>>> {
+    match path {
+        "/api/start" => {
+            let device_name = match body["device"].as_str() {
+                Some(device_name) => {
+                    if !context.state.lock().unwrap().devices.iter().any(|device| device == device_name) {
+                        return error_response(400, &gettext("Audio device not found"));
+                    }
+                    device_name.to_string()
+                },
+                None => match context.current_device_name.lock().unwrap().clone() {
+                    Some(device_name) => device_name,
+                    None => return error_response(409, &gettext("No audio device available"))
+                }
+            };
+
+            *context.current_device_name.lock().unwrap() = Some(device_name.clone());
+
+            context.recognizer.lock().unwrap().restart_listening(&device_name);
+
+            json_response(202, &json!({ "device": device_name }))
+        },
+        "/api/stop" => {
+            context.recognizer.lock().unwrap().stop_listening();
+
+            json_response(202, &json!({}))
+        },
+        "/api/recording/save" => {
+            context.recognizer.lock().unwrap().save_recording();
+
+            json_response(202, &json!({}))
+        },
+        "/api/recognize/file" => {
+            let file_path = match body["path"].as_str() {
+                Some(file_path) if std::path::Path::new(file_path).is_file() => file_path,
+                _ => return error_response(400, &gettext("The \"path\" field should be the path of an existing file"))
+            };
+
+            context.recognizer.lock().unwrap().recognize_file(file_path);
+
+            json_response(202, &json!({}))
+        },
+        "/api/recognize/fingerprint" => {
+            let signature = match body["fingerprint"].as_str().map(DecodedSignature::decode_from_uri) {
+                Some(Ok(signature)) => signature,
+                Some(Err(error)) => return error_response(400, &error.to_string()),
+                None => return error_response(400, &gettext("The \"fingerprint\" field is missing"))
+            };
+
+            context.recognizer.lock().unwrap().recognize_signature(signature);
+
+            json_response(202, &json!({}))
+        },
+        _ => error_response(404, &gettext("Not found"))
+    }
+}
+
+/// The events are written directly to the connection rather than through a
+/// tiny_http "Response", as the chunked encoder of the latter buffers its
+/// output, which would delay events.
+
+fn serve_event_stream(context: &ApiContext, request: Request) {
+    let (events_tx, events_rx) = mpsc::channel();
+
+    context.event_subscribers.lock().unwrap().push(events_tx);
+
+    let mut writer = request.into_writer();
+
+    let headers = "HTTP/1.1 200 OK\r\n\
+        Content-Type: text/event-stream\r\n\
+        Cache-Control: no-cache\r\n\
+        Access-Control-Allow-Origin: *\r\n\
+        Connection: close\r\n\r\n";
+
+    if writer.write_all(headers.as_bytes()).and_then(|_| writer.flush()).is_err() {
+        return;
+    }
+
+    for event in events_rx.iter() {
+        if writer.write_all(event.as_bytes()).and_then(|_| writer.flush()).is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use std::error::Error;
+use gettextrs::gettext;
+use serde::{Serialize, Deserialize};
+
+use crate::fingerprinting::preprocessing::{PreprocessingConfig, SourcePreprocessing};
+use crate::utils::audio_recorder::RecorderConfig;
+
+/// This module contains the parameters of the audio capture done by the
+/// microphone thread, which are shared by the command-line interface (through
+/// options such as "--sample-rate"), the preferences file (through a
+/// "[capture]" section, read by both the GUI and the command-line interface)
+/// and library users (which pass them to the recognizer).
+///
+/// The recording is always converted to 16 KHz mono before fingerprinting,
+/// these parameters only affect how it is obtained from the audio device and
+/// how much of it is fingerprinted at once.
+///
+/// Example "preferences.toml" section:
+///
+/// ```toml
+/// [capture]
+/// buffer_seconds = 12
+/// recognition_interval_seconds = 4
+/// sample_rate = 48000
+/// channels = 2
+/// sample_format = "f32"
+/// downmix = "left"
+/// ```
+///
+/// The recorder of the captured audio is configured in a "[capture.recorder]"
+/// sub-section, see "audio_recorder.rs".
+///
+/// Other devices may be listened to simultaneously with the selected one
+/// (for example, to monitor several rooms from the same machine), each with
+/// its own buffer and recognition cadence. Recognized songs are tagged with
+/// the identifier of the source they were heard from:
+///
+/// ```toml
+/// [capture]
+/// source_id = "living-room"
+///
+/// [[capture.sources]]
+/// id = "kitchen"
+/// device = "hw:CARD=Device,DEV=0"
+/// recognition_interval_seconds = 8
+/// ```
+///
+/// Each source uses the "[preprocessing.microphone]" chain of the
+/// preferences file (see "preprocessing.rs"), unless its entry has its own
+/// (for a noisier room, for example):
+///
+/// ```toml
+/// [capture.sources.preprocessing]
+/// high_pass_hz = 150.0
+/// noise_reduction = 0.8
+/// ```
+
+/// The identifier of the selected device, unless configured otherwise.
+
+pub const DEFAULT_SOURCE_ID: &str = "microphone";
+
+/// How multi-channel recordings are converted to mono.
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DownmixStrategy {
+    Average,
+    Left,
+    Right
+}
+
+impl DownmixStrategy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "average" => Some(DownmixStrategy::Average),
+            "left" => Some(DownmixStrategy::Left),
+            "right" => Some(DownmixStrategy::Right),
+            _ => None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureSampleFormat {
+    F32,
+    I16,
+    U16
+}
+
+impl CaptureSampleFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "f32" => Some(CaptureSampleFormat::F32),
+            "i16" => Some(CaptureSampleFormat::I16),
+            "u16" => Some(CaptureSampleFormat::U16),
+            _ => None
+        }
+    }
+
+    pub fn to_cpal(self: &Self) -> cpal::SampleFormat {
+        match self {
+            CaptureSampleFormat::F32 => cpal::SampleFormat::F32,
+            CaptureSampleFormat::I16 => cpal::SampleFormat::I16,
+            CaptureSampleFormat::U16 => cpal::SampleFormat::U16
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct CaptureConfig {
+    // Length of the recording which is fingerprinted, in seconds
+    pub buffer_seconds: u32,
+    // Delay between two fingerprints, in seconds (a new one is only made
+    // when the previous one has been processed)
+    pub recognition_interval_seconds: u32,
+
+    // Preferences for opening the audio device, the default configuration
+    // of the device is used for those which are unset or unsupported
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u16>,
+    pub sample_format: Option<CaptureSampleFormat>,
+
+    pub downmix: DownmixStrategy,
+
+    // Identifier of the selected device in the results
+    pub source_id: String,
+
+    pub recorder: RecorderConfig,
+
+    // Other devices listened to at the same time as the selected one
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<CaptureSourceConfig>
+}
+
+/// A device listened to in addition to the selected one. The buffer length
+/// and the recognition interval default to those of the "[capture]" section,
+/// and the preprocessing chain to the one for the microphone.
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CaptureSourceConfig {
+    pub id: String,
+    pub device: String,
+    pub buffer_seconds: Option<u32>,
+    pub recognition_interval_seconds: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preprocessing: Option<PreprocessingConfig>
+}
+
+impl CaptureSourceConfig {
+
+    /// Parse a "--source" command-line option, such as "kitchen=hw:1,0".
+
+    pub fn from_argument(argument: &str) -> Option<Self> {
+        let (id, device) = argument.split_at(argument.find('=')?);
+
+        Some(CaptureSourceConfig {
+            id: id.to_string(),
+            device: device[1..].to_string(),
+            buffer_seconds: None,
+            recognition_interval_seconds: None,
+            preprocessing: None
+        })
+    }
+
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        CaptureConfig {
+            buffer_seconds: 12,
+            recognition_interval_seconds: 4,
+            sample_rate: None,
+            channels: None,
+            sample_format: None,
+            downmix: DownmixStrategy::Average,
+            source_id: DEFAULT_SOURCE_ID.to_string(),
+            recorder: RecorderConfig::default(),
+            sources: vec![]
+        }
+    }
+}
+
+impl CaptureConfig {
+
+    pub fn validate(self: &Self) -> Result<(), Box<dyn Error>> {
+        if self.buffer_seconds < 3 || self.buffer_seconds > 60 {
+            return Err(gettext("The capture buffer length should be between 3 and 60 seconds").into());
+        }
+        if self.recognition_interval_seconds < 1 || self.recognition_interval_seconds > self.buffer_seconds {
+            return Err(gettext("The recognition interval should be between 1 second and the capture buffer length").into());
+        }
+        if self.channels == Some(0) || self.sample_rate == Some(0) {
+            return Err(gettext("The sample rate and channel count should be positive").into());
+        }
+        if self.recorder.max_recordings == Some(0) {
+            return Err(gettext("At least one recording should be kept").into());
+        }
+
+        let mut source_ids: Vec<&str> = vec![&self.source_id];
+
+        for source in self.sources.iter() {
+            if source.id.is_empty() || source.device.is_empty() || source_ids.contains(&source.id.as_str()) {
+                return Err(format!("{} {}", gettext("Each audio source should have a device and a distinct identifier:"), source.id).into());
+            }
+            source_ids.push(&source.id);
+
+            self.for_source(source).validate()?;
+        }
+        Ok(())
+    }
+
+    /// The capture parameters of an additional source.
+
+    pub fn for_source(self: &Self, source: &CaptureSourceConfig) -> CaptureConfig {
+        CaptureConfig {
+            buffer_seconds: source.buffer_seconds.unwrap_or(self.buffer_seconds),
+            recognition_interval_seconds: source.recognition_interval_seconds.unwrap_or(self.recognition_interval_seconds),
+            source_id: source.id.clone(),
+            sources: vec![],
+            ..self.clone()
+        }
+    }
+
+    /// The preprocessing chain of the audio of a source: the one of its
+    /// entry, if any, and the one for the microphone otherwise.
+
+    pub fn get_preprocessing<'a>(self: &'a Self, source_id: &str, preprocessing: &'a SourcePreprocessing) -> &'a PreprocessingConfig {
+        self.sources.iter()
+            .find(|source| source.id == source_id)
+            .and_then(|source| source.preprocessing.as_ref())
+            .unwrap_or(&preprocessing.microphone)
+    }
+
+    /// Pick the first configuration supported by the device matching the
+    /// preferences, if any.
+
+    pub fn select_stream_config(self: &Self, supported_configs: impl Iterator<Item = cpal::SupportedStreamConfigRange>) -> Option<cpal::SupportedStreamConfig> {
+        if self.sample_rate.is_none() && self.channels.is_none() && self.sample_format.is_none() {
+            return None;
+        }
+
+        for supported_config in supported_configs {
+            if let Some(channels) = self.channels {
+                if supported_config.channels() != channels {
+                    continue;
+                }
+            }
+            if let Some(sample_format) = self.sample_format {
+                if supported_config.sample_format() != sample_format.to_cpal() {
+                    continue;
+                }
+            }
+            match self.sample_rate {
+                Some(sample_rate) => {
+                    if supported_config.min_sample_rate().0 <= sample_rate && sample_rate <= supported_config.max_sample_rate().0 {
+                        return Some(supported_config.with_sample_rate(cpal::SampleRate(sample_rate)));
+                    }
+                },
+                None => {
+                    return Some(supported_config.with_max_sample_rate());
+                }
+            };
+        }
+
+        None
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sources_can_override_the_preprocessing() {
+        let capture_config: CaptureConfig = toml::from_str(r#"
+            [[sources]]
+            id = "kitchen"
+            device = "hw:1,0"
+
+            [sources.preprocessing]
+            noise_reduction = 0.8
+
+            [[sources]]
+            id = "bedroom"
+            device = "hw:2,0"
+        "#).unwrap();
+
+        let preprocessing = SourcePreprocessing {
+            microphone: PreprocessingConfig::recommended(),
+            file: PreprocessingConfig::default()
+        };
+
+        assert_eq!(capture_config.get_preprocessing("kitchen", &preprocessing), &PreprocessingConfig {
+            noise_reduction: Some(0.8),
+            ..PreprocessingConfig::default()
+        });
+        assert_eq!(capture_config.get_preprocessing("bedroom", &preprocessing), &preprocessing.microphone);
+        assert_eq!(capture_config.get_preprocessing(DEFAULT_SOURCE_ID, &preprocessing), &preprocessing.microphone);
+
+        // The override is kept when the preferences are saved again
+
+        let saved_config: CaptureConfig = toml::from_str(&toml::to_string(&capture_config).unwrap()).unwrap();
+
+        assert_eq!(saved_config, capture_config);
+    }
+
+}
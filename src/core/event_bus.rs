@@ -0,0 +1,91 @@
+use std::sync::{Arc, Mutex, mpsc};
+
+use crate::core::thread_messages::GUIMessage;
+
+/// This module carries the events of the microphone, processing and HTTP
+/// threads (the "GUIMessage" messages) to whoever is interested, without
+/// depending on a given toolkit or async runtime: the threads send events to
+/// an "EventBus", which passes them to each of its sinks.
+///
+/// A sink can be a standard channel, a GLib channel (for the GTK interface,
+/// with the "gui" feature), or a callback. Channels of other libraries (such
+/// as those of Tokio or Crossbeam) can be plugged in through a callback
+/// sending each event to them.
+
+pub trait EventSink: Send + Sync {
+
+    /// Deliver an event, returning false once nothing can receive events
+    /// anymore, in which case the sink is removed from the bus.
+
+    fn send_event(self: &Self, event: GUIMessage) -> bool;
+}
+
+impl EventSink for mpsc::Sender<GUIMessage> {
+    fn send_event(self: &Self, event: GUIMessage) -> bool {
+        self.send(event).is_ok()
+    }
+}
+
+#[cfg(feature = "gui")]
+impl EventSink for glib::Sender<GUIMessage> {
+    fn send_event(self: &Self, event: GUIMessage) -> bool {
+        self.send(event).is_ok()
+    }
+}
+
+/// A function called with each event, from the thread which sent it (so it
+/// should return quickly).
+
+pub struct EventCallback<F: FnMut(&GUIMessage) + Send> {
+    callback: Mutex<F>
+}
+
+impl<F: FnMut(&GUIMessage) + Send> EventCallback<F> {
+    pub fn new(callback: F) -> Self {
+        EventCallback { callback: Mutex::new(callback) }
+    }
+}
+
+impl<F: FnMut(&GUIMessage) + Send> EventSink for EventCallback<F> {
+    fn send_event(self: &Self, event: GUIMessage) -> bool {
+        (self.callback.lock().unwrap())(&event);
+        true
+    }
+}
+
+/// The sending end of events, which can be cloned and passed to other
+/// threads. Events sent while the bus has no sink are dropped.
+
+#[derive(Clone, Default)]
+pub struct EventBus {
+    sinks: Arc<Mutex<Vec<Arc<dyn EventSink>>>>
+}
+
+impl EventBus {
+
+    pub fn new() -> Self {
+        EventBus::default()
+    }
+
+    pub fn add_sink<S: EventSink + 'static>(self: &Self, sink: S) {
+        self.sinks.lock().unwrap().push(Arc::new(sink));
+    }
+
+    pub fn send(self: &Self, event: GUIMessage) {
+
+        // Don't hold the lock while delivering, so that a callback may add
+        // sinks to the bus
+
+        let sinks: Vec<Arc<dyn EventSink>> = self.sinks.lock().unwrap().clone();
+
+        let closed_sinks: Vec<Arc<dyn EventSink>> = sinks.into_iter()
+            .filter(|sink| !sink.send_event(event.clone()))
+            .collect();
+
+        if !closed_sinks.is_empty() {
+            self.sinks.lock().unwrap().retain(|sink| !closed_sinks.iter().any(|closed_sink| Arc::ptr_eq(sink, closed_sink)));
+        }
+
+    }
+
+}
@@ -1,15 +1,49 @@
 use std::sync::mpsc;
-use std::error::Error;
-use gettextrs::gettext;
 use regex::Regex;
 use serde_json::{Value, to_string_pretty};
 
+use crate::core::event_bus::EventBus;
 use crate::core::thread_messages::*;
 
 use crate::fingerprinting::signature_format::DecodedSignature;
 use crate::fingerprinting::communication::{recognize_song_from_signature, obtain_raw_cover_image};
+use crate::fingerprinting::comparison::SignatureComparison;
 
-fn try_recognize_song(signature: DecodedSignature) -> Result<SongRecognizedMessage, Box<dyn Error>> {
+/// A signature from the microphone is not sent to Shazam when it duplicates
+/// the last one that was, coming from another capture source (for example,
+/// when several capture sources hear the same broadcast): the outcome of the
+/// last recognition is reused instead. The consecutive windows of a single
+/// source are always sent, as the song may have changed since.
+
+const DUPLICATE_MINIMUM_SIMILARITY: f32 = 0.6;
+const DUPLICATE_MINIMUM_MATCHED_PEAKS: usize = 30;
+const DUPLICATE_MINIMUM_OVERLAP: f32 = 0.5; // Proportion of the duration of the new signature
+
+fn is_duplicate_signature(signature: &DecodedSignature, last_signature: &DecodedSignature) -> bool {
+    let comparison = SignatureComparison::new(signature, last_signature);
+    
+    comparison.similarity >= DUPLICATE_MINIMUM_SIMILARITY &&
+        comparison.matched_peak_count >= DUPLICATE_MINIMUM_MATCHED_PEAKS &&
+        comparison.overlap_seconds >= DUPLICATE_MINIMUM_OVERLAP * signature.number_samples as f32 / signature.sample_rate_hz as f32
+}
+
+/// Something that recognizes songs from signatures, Shazam by default (see
+/// "recognizer.rs"). When no song matches, the error should be
+/// "RecognitionError::NoMatch", other errors are considered network errors.
+
+pub trait RecognitionBackend: Send {
+    fn recognize(self: &mut Self, signature: DecodedSignature) -> Result<SongRecognizedMessage, RecognitionError>;
+}
+
+pub struct ShazamBackend;
+
+impl RecognitionBackend for ShazamBackend {
+    fn recognize(self: &mut Self, signature: DecodedSignature) -> Result<SongRecognizedMessage, RecognitionError> {
+        try_recognize_song(signature)
+    }
+}
+
+fn try_recognize_song(signature: DecodedSignature) -> Result<SongRecognizedMessage, RecognitionError> {
     let json_object = recognize_song_from_signature(&signature)?;
     
     let mut album_name: Option<String> = None;
@@ -46,12 +80,12 @@ fn try_recognize_song(signature: DecodedSignature) -> Result<SongRecognizedMessa
     Ok(SongRecognizedMessage {
         artist_name: match &json_object["track"]["subtitle"] {
             Value::String(string) => string.to_string(),
-            _ => { return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, gettext("No match for this song").as_str()))) }
+            _ => { return Err(RecognitionError::NoMatch) }
         },
         album_name: album_name,
         song_name: match &json_object["track"]["title"] {
             Value::String(string) => string.to_string(),
-            _ => { return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, gettext("No match for this song").as_str()))) }
+            _ => { return Err(RecognitionError::NoMatch) }
         },
         cover_image: match &json_object["track"]["images"]["coverart"] {
             Value::String(string) => Some(obtain_raw_cover_image(string)?),
@@ -60,7 +94,11 @@ fn try_recognize_song(signature: DecodedSignature) -> Result<SongRecognizedMessa
         signature: Box::new(signature),
         track_key: match &json_object["track"]["key"] {
             Value::String(string) => string.to_string(),
-            _ => { return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, gettext("No match for this song").as_str()))) }
+            _ => { return Err(RecognitionError::NoMatch) }
+        },
+        track_url: match &json_object["track"]["url"] {
+            Value::String(string) => Some(string.to_string()),
+            _ => None
         },
         release_year: release_year,
         genre: match &json_object["track"]["genres"]["primary"] {
@@ -70,36 +108,142 @@ fn try_recognize_song(signature: DecodedSignature) -> Result<SongRecognizedMessa
         shazam_json: Regex::new("\n *").unwrap().replace_all(&
             Regex::new("([,:])\n *").unwrap().replace_all(&
                 to_string_pretty(&json_object).unwrap(), "$1 ").into_owned(),
-            "").into_owned()
+            "").into_owned(),
+        source_id: None // Set by the caller
     })
 }
 
-pub fn http_thread(http_rx: mpsc::Receiver<HTTPMessage>, gui_tx: glib::Sender<GUIMessage>, microphone_tx: mpsc::Sender<MicrophoneMessage>) {
+pub fn http_thread(http_rx: mpsc::Receiver<HTTPMessage>, gui_tx: EventBus, microphone_tx: mpsc::Sender<MicrophoneMessage>, mut backend: Box<dyn RecognitionBackend>) {
+    
+    // The last microphone signature sent to Shazam, the capture source it
+    // came from, and the outcome of its recognition (unless it failed because
+    // of the network)
+    
+    let mut last_recognition: Option<(DecodedSignature, String, Result<SongRecognizedMessage, RecognitionError>)> = None;
     
     for message in http_rx.iter() {
-        match message {
-            HTTPMessage::RecognizeSignature(signature) => {
-                match try_recognize_song(*signature) {
-                    Ok(recognized_song) => {
-                        gui_tx.send(GUIMessage::SongRecognized(Box::new(recognized_song))).unwrap();
-                        gui_tx.send(GUIMessage::NetworkStatus(true)).unwrap();
-                    },
-                    Err(error) => {
-                        match error.to_string().as_str() {
-                            a if a == gettext("No match for this song") => {
-                                gui_tx.send(GUIMessage::ErrorMessage(error.to_string())).unwrap();
-                                gui_tx.send(GUIMessage::NetworkStatus(true)).unwrap();
-                            }
-                            _ => {
-                                gui_tx.send(GUIMessage::NetworkStatus(false)).unwrap();
-                            }
-                        }
+        let (signature, source_id) = match message {
+            HTTPMessage::RecognizeSignature(signature) => (signature, None),
+            HTTPMessage::RecognizeMicrophoneSignature(source_id, signature) => (signature, Some(source_id))
+        };
+        
+        // Keep what the recorder of the microphone thread needs
+        
+        let mut outcome = RecognitionOutcome {
+            signature_uri: source_id.as_ref().and_then(|_| signature.encode_to_uri().ok()),
+            shazam_json: None,
+            error: None
+        };
+        
+        let result = match (&source_id, &last_recognition) {
+            (Some(source_id), Some((last_signature, last_source_id, last_result)))
+                    if source_id != last_source_id && is_duplicate_signature(&signature, last_signature) => {
+                last_result.clone().map(|mut recognized_song| {
+                    recognized_song.signature = signature;
+                    recognized_song
+                })
+            },
+            _ => {
+                let last_signature = source_id.as_ref().map(|_| (*signature).clone());
+                
+                let result = backend.recognize(*signature);
+                
+                if let (Some(last_signature), Some(source_id)) = (last_signature, &source_id) {
+                    last_recognition = match &result {
+                        Err(RecognitionError::NoMatch) | Ok(_) => Some((last_signature, source_id.clone(), result.clone())),
+                        Err(_) => None
+                    };
+                }
+                
+                result
+            }
+        };
+        
+        match result {
+            Ok(mut recognized_song) => {
+                recognized_song.source_id = source_id.clone();
+                
+                outcome.shazam_json = Some(recognized_song.shazam_json.clone());
+                
+                gui_tx.send(GUIMessage::SongRecognized(Box::new(recognized_song)));
+                gui_tx.send(GUIMessage::NetworkStatus(true));
+            },
+            Err(error) => {
+                match error {
+                    RecognitionError::NoMatch => {
+                        gui_tx.send(GUIMessage::NoMatch);
+                        gui_tx.send(GUIMessage::NetworkStatus(true));
                     }
-                };
+                    _ => {
+                        gui_tx.send(GUIMessage::NetworkStatus(false));
+                    }
+                }
                 
-                microphone_tx.send(MicrophoneMessage::ProcessingDone).unwrap();
+                outcome.error = Some(error);
             }
+        };
+        
+        // The microphone thread may have been shut down since
+        
+        if let Some(source_id) = source_id {
+            microphone_tx.send(MicrophoneMessage::ProcessingDone(source_id, Box::new(outcome))).ok();
         }
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::fingerprinting::algorithm::SignatureGenerator;
+    use crate::fingerprinting::test_fixtures::AUDIO_FILE_PATH;
+
+    struct CountingBackend {
+        calls: Arc<Mutex<usize>>
+    }
+
+    impl RecognitionBackend for CountingBackend {
+        fn recognize(self: &mut Self, _signature: DecodedSignature) -> Result<SongRecognizedMessage, RecognitionError> {
+            *self.calls.lock().unwrap() += 1;
+            Err(RecognitionError::NoMatch)
+        }
+    }
+
+    #[test]
+    fn duplicates_across_sources_only() {
+        let signature = SignatureGenerator::make_signature_from_file(AUDIO_FILE_PATH).unwrap();
+
+        let calls = Arc::new(Mutex::new(0));
+
+        let (http_tx, http_rx) = mpsc::channel();
+        let (microphone_tx, microphone_rx) = mpsc::channel();
+
+        let backend = Box::new(CountingBackend { calls: calls.clone() });
+        let thread = std::thread::spawn(move || http_thread(http_rx, EventBus::new(), microphone_tx, backend));
+
+        // The same audio heard twice by one source, then by another source
+        for source_id in &["first", "first", "second"] {
+            http_tx.send(HTTPMessage::RecognizeMicrophoneSignature(source_id.to_string(), Box::new(signature.clone()))).unwrap();
+
+            match microphone_rx.recv().unwrap() {
+                MicrophoneMessage::ProcessingDone(done_source_id, outcome) => {
+                    assert_eq!(&done_source_id, source_id);
+                    assert!(matches!(outcome.error, Some(RecognitionError::NoMatch)));
+                },
+                _ => panic!("Unexpected message")
+            }
+        }
+
+        assert_eq!(*calls.lock().unwrap(), 2);
+
+        // Signatures from files are always sent
+        http_tx.send(HTTPMessage::RecognizeSignature(Box::new(signature))).unwrap();
+
+        drop(http_tx);
+        thread.join().unwrap();
+
+        assert_eq!(*calls.lock().unwrap(), 3);
+    }
+
+}
@@ -0,0 +1,195 @@
+use serde::Serialize;
+
+/// This module contains the input level meter of the microphone thread,
+/// which measures the audio as it is received from the device (before it is
+/// downmixed and resampled for fingerprinting), so that clipping can be
+/// noticed.
+///
+/// For each measurement window (a 24th of second, so that the display can
+/// be refreshed at 24 FPS), it reports:
+///  - The RMS level and the true peak level (measured on a signal
+///    oversampled 4 times, as described in ITU-R BS.1770, so that the peaks
+///    located between two samples are caught), in dBFS
+///  - The highest true peak of the last two seconds ("peak hold")
+///  - The number of clipped samples, in the window and since listening started
+///  - Whether the input has been too quiet for recognition during the last
+///    two seconds
+///
+/// The levels are sent as "MicrophoneInputLevel" messages, along with the
+/// identifier of their capture source.
+
+/// The level reported for silence, in dBFS.
+
+pub const SILENCE_DBFS: f32 = -100.0;
+
+const CLIPPING_THRESHOLD: f32 = 0.999; // About -0.01 dBFS
+const TOO_QUIET_THRESHOLD_DBFS: f32 = -50.0;
+const PEAK_HOLD_SECONDS: f32 = 2.0;
+const TOO_QUIET_SECONDS: f32 = 2.0;
+
+// Polyphase coefficients of the 4x oversampling filter given in ITU-R
+// BS.1770-4, Annex 2 (written with the digits needed in single precision)
+
+const OVERSAMPLING_TAPS: usize = 12;
+
+const OVERSAMPLING_FILTER: [[f32; OVERSAMPLING_TAPS]; 4] = [
+    [0.0017089844, 0.010986328, -0.01965332, 0.033203125, -0.059448242, 0.1373291,
+     0.97216797, -0.10229492, 0.047607422, -0.026611328, 0.014892578, -0.008300781],
+    [-0.029174805, 0.029296875, -0.051757813, 0.08911133, -0.1665039, 0.4650879,
+     0.77978516, -0.20031738, 0.1015625, -0.05822754, 0.033081055, -0.018920898],
+    [-0.018920898, 0.033081055, -0.05822754, 0.1015625, -0.20031738, 0.77978516,
+     0.4650879, -0.1665039, 0.08911133, -0.051757813, 0.029296875, -0.029174805],
+    [-0.008300781, 0.014892578, -0.026611328, 0.047607422, -0.10229492, 0.97216797,
+     0.1373291, -0.059448242, 0.033203125, -0.01965332, 0.010986328, 0.0017089844]
+];
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct InputLevel {
+    pub rms_dbfs: f32,
+    pub true_peak_dbfs: f32,
+    pub peak_hold_dbfs: f32,
+    pub clipped_samples: u64, // In the measurement window
+    pub total_clipped_samples: u64, // Since listening started
+    pub too_quiet: bool
+}
+
+impl InputLevel {
+
+    /// The level shown by volume bars, between 0 and 1 (the dBFS level is
+    /// mapped linearly from -60 dBFS, so that quiet inputs remain visible).
+
+    pub fn bar_fraction(self: &Self) -> f64 {
+        ((self.true_peak_dbfs as f64 + 60.0) / 60.0).clamp(0.0, 1.0)
+    }
+
+}
+
+pub struct LevelMeter {
+    channels: usize,
+    sample_rate: u32,
+
+    // Most recent samples of each channel, newest first, for oversampling
+    histories: Vec<[f32; OVERSAMPLING_TAPS]>,
+
+    // Accumulators of the current measurement window
+    window_frames: usize,
+    window_square_sum: f64,
+    window_peak: f32,
+    window_clipped_samples: u64,
+
+    total_clipped_samples: u64,
+
+    peak_hold: f32,
+    frames_since_peak_hold: usize,
+    frames_since_loud_enough: usize
+}
+
+impl LevelMeter {
+
+    pub fn new(channels: u16, sample_rate: u32) -> Self {
+        let channels = channels.max(1) as usize;
+
+        LevelMeter {
+            channels,
+            sample_rate,
+            histories: vec![[0.0; OVERSAMPLING_TAPS]; channels],
+            window_frames: 0,
+            window_square_sum: 0.0,
+            window_peak: 0.0,
+            window_clipped_samples: 0,
+            total_clipped_samples: 0,
+            peak_hold: 0.0,
+            frames_since_peak_hold: 0,
+            frames_since_loud_enough: 0
+        }
+    }
+
+    /// Measure interleaved samples, returning the levels of the measurement
+    /// windows completed meanwhile (usually zero or one).
+
+    pub fn process<T: cpal::Sample>(self: &mut Self, input_samples: &[T]) -> Vec<InputLevel> {
+        let mut levels: Vec<InputLevel> = vec![];
+
+        let window_length = (self.sample_rate as usize / 24).max(1);
+
+        for frame in input_samples.chunks_exact(self.channels) {
+            for (channel, sample) in frame.iter().enumerate() {
+                let sample = cpal::Sample::to_f32(sample);
+
+                if sample.abs() >= CLIPPING_THRESHOLD {
+                    self.window_clipped_samples += 1;
+                }
+
+                self.window_square_sum += (sample as f64) * (sample as f64);
+
+                let history = &mut self.histories[channel];
+                history.copy_within(.. OVERSAMPLING_TAPS - 1, 1);
+                history[0] = sample;
+
+                for phase in OVERSAMPLING_FILTER.iter() {
+                    let interpolated: f32 = phase.iter().zip(history.iter()).map(|(coefficient, sample)| coefficient * sample).sum();
+
+                    if interpolated.abs() > self.window_peak {
+                        self.window_peak = interpolated.abs();
+                    }
+                }
+            }
+
+            self.window_frames += 1;
+
+            if self.window_frames >= window_length {
+                levels.push(self.finish_window());
+            }
+        }
+
+        levels
+    }
+
+    fn finish_window(self: &mut Self) -> InputLevel {
+        let rms = (self.window_square_sum / (self.window_frames * self.channels) as f64).sqrt() as f32;
+        let rms_dbfs = to_dbfs(rms);
+
+        // Hold the highest peak for a while, then follow the current one
+
+        self.frames_since_peak_hold += self.window_frames;
+
+        if self.window_peak >= self.peak_hold || self.frames_since_peak_hold as f32 >= PEAK_HOLD_SECONDS * self.sample_rate as f32 {
+            self.peak_hold = self.window_peak;
+            self.frames_since_peak_hold = 0;
+        }
+
+        if rms_dbfs >= TOO_QUIET_THRESHOLD_DBFS {
+            self.frames_since_loud_enough = 0;
+        }
+        else {
+            self.frames_since_loud_enough += self.window_frames;
+        }
+
+        self.total_clipped_samples += self.window_clipped_samples;
+
+        let level = InputLevel {
+            rms_dbfs,
+            true_peak_dbfs: to_dbfs(self.window_peak),
+            peak_hold_dbfs: to_dbfs(self.peak_hold),
+            clipped_samples: self.window_clipped_samples,
+            total_clipped_samples: self.total_clipped_samples,
+            too_quiet: self.frames_since_loud_enough as f32 >= TOO_QUIET_SECONDS * self.sample_rate as f32
+        };
+
+        self.window_frames = 0;
+        self.window_square_sum = 0.0;
+        self.window_peak = 0.0;
+        self.window_clipped_samples = 0;
+
+        level
+    }
+
+}
+
+fn to_dbfs(amplitude: f32) -> f32 {
+    if amplitude <= 0.0 {
+        return SILENCE_DBFS;
+    }
+
+    (20.0 * amplitude.log10()).max(SILENCE_DBFS)
+}
@@ -1,25 +1,193 @@
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+#[cfg(target_os = "linux")]
+use std::collections::HashMap;
 
 #[cfg(target_os = "linux")]
 use gag::Gag;
 
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use gettextrs::gettext;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use crate::core::event_bus::EventBus;
 use crate::core::thread_messages::{*, MicrophoneMessage::*};
+use crate::core::capture_config::{CaptureConfig, DownmixStrategy};
+use crate::core::level_meter::LevelMeter;
+use crate::fingerprinting::resampler::{Resampler, to_i16_samples};
+use crate::utils::audio_recorder::AudioRecorder;
+#[cfg(target_os = "linux")]
+use crate::utils::pulseaudio_loopback::{PulseaudioEvents, PulseaudioLoopback, PulseaudioSource};
+#[cfg(target_os = "linux")]
+use crate::utils::application_capture::{ApplicationCapture, APPLICATION_DEVICE_PREFIX};
+
+// How often inter-thread messages and stream errors are checked for, and
+// how often reconnecting is tried after the stream failed (along with
+// refreshing the device list, when devices being plugged or unplugged can't
+// be noticed through PulseAudio)
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const RECONNECTION_INTERVAL: Duration = Duration::from_secs(2);
+
+// How long the stream of the "pulse" ALSA plug-in is waited for, before
+// moving it to the selected PulseAudio source
+
+#[cfg(target_os = "linux")]
+const PULSEAUDIO_STREAM_ATTEMPTS: usize = 10;
+#[cfg(target_os = "linux")]
+const PULSEAUDIO_STREAM_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Audio windows are handed by the CPAL thread, which records them, to the
+/// recorder of the microphone thread, which saves them to the disk.
+
+#[derive(Clone)]
+struct RecorderLink {
+    windows_tx: mpsc::Sender<RecordedWindow>,
+    save_requested: Arc<AtomicBool>,
+    record_each_attempt: bool
+}
+
+struct RecordedWindow {
+    source_id: String,
+    samples: Vec<i16>,
+    recognition_attempt: bool
+}
+
+/// What the CPAL thread of a capture source keeps from one chunk of samples
+/// to the next, along with the settings of the stream.
+
+struct SourceState {
+    source_id: String,
+    processing_tx: mpsc::Sender<ProcessingMessage>,
+    gui_tx: EventBus,
+    
+    channels: u16,
+    downmix: DownmixStrategy,
+    resampler: Resampler, // Kept across the chunks of samples received
+    
+    samples_buffer: Vec<i16>,
+    number_unprocessed_samples: usize, // Sample count for the interval of doing Shazam recognition (every 4 seconds by default)
+    recognition_interval_samples: usize,
+    
+    level_meter: LevelMeter,
+    processing_already_ongoing: Arc<Mutex<bool>>,
+    recorder_link: RecorderLink
+}
+
+/// A device listened to, with its own stream, buffer and recognition
+/// cadence. The first capture source is the device selected by the user,
+/// the others are the additional sources of the capture configuration.
+
+struct CaptureSource {
+    id: String,
+    capture_config: CaptureConfig, // With the buffer length and recognition interval of this source
+    
+    // The device of the source (for the selected device, the one that
+    // listening was requested with, while listening)
+    device_name: Option<String>,
+    
+    // Only the selected device falls back to the default device when it
+    // has disappeared, switching back to it when it reappears
+    is_selected_device: bool,
+    using_default_device: bool,
+    
+    stream: Option<cpal::Stream>,
+    
+    processing_already_ongoing: Arc<Mutex<bool>>, // Whether our data is already being processed in other threads (pointer to a bool shared between this thread and the CPAL thread, hence the Arc<Mutex>)
+    
+    recorder_link: RecorderLink,
+    pending_recording: Option<PathBuf>, // The description of the recording whose recognition is ongoing
+    
+    reconnection_pending: bool,
+    last_reconnection_attempt: Instant
+}
+
+impl CaptureSource {
+    
+    fn new(id: &str, capture_config: CaptureConfig, device_name: Option<String>, recorded_windows_tx: &mpsc::Sender<RecordedWindow>) -> Self {
+        CaptureSource {
+            id: id.to_string(),
+            // The device of the selected one is only known once listening
+            // is requested
+            is_selected_device: device_name.is_none(),
+            device_name,
+            using_default_device: false,
+            stream: None,
+            processing_already_ongoing: Arc::new(Mutex::new(false)),
+            recorder_link: RecorderLink {
+                windows_tx: recorded_windows_tx.clone(),
+                save_requested: Arc::new(AtomicBool::new(false)),
+                record_each_attempt: capture_config.recorder.enabled
+            },
+            capture_config,
+            pending_recording: None,
+            reconnection_pending: false,
+            last_reconnection_attempt: Instant::now()
+        }
+    }
+    
+    fn is_listening(self: &Self) -> bool {
+        self.stream.is_some() || self.reconnection_pending
+    }
+    
+}
 
+/// State related to the PulseAudio/PipeWire sources and application
+/// captures, which are recorded from through the "pulse" ALSA plug-in.
 
-pub fn microphone_thread(microphone_rx: mpsc::Receiver<MicrophoneMessage>, processing_tx: mpsc::Sender<ProcessingMessage>, gui_tx: glib::Sender<GUIMessage>) {
+struct PulseaudioState {
+    #[cfg(target_os = "linux")]
+    sources: Vec<PulseaudioSource>,
+    
+    // When recording from a specific application, the null sink and loopback
+    // set up for it are removed when this is dropped (by capture source id)
+    
+    #[cfg(target_os = "linux")]
+    application_captures: HashMap<String, ApplicationCapture>,
+    
+    // Devices appearing or disappearing, when PulseAudio (or PipeWire) is
+    // there to tell
+    
+    #[cfg(target_os = "linux")]
+    events: Option<PulseaudioEvents>
+}
+
+pub fn microphone_thread(microphone_rx: mpsc::Receiver<MicrophoneMessage>, processing_tx: mpsc::Sender<ProcessingMessage>, gui_tx: EventBus, capture_config: CaptureConfig) {
 
     // Use the default host for working with audio devices.
     
     let host = cpal::default_host();
-
-    // Run the input stream on a separate thread.
     
-    let mut stream: Option<cpal::Stream> = None;
+    // Errors of the current streams are reported from the CPAL thread, along
+    // with the id of their capture source
     
-    let processing_already_ongoing: Arc<Mutex<bool>> = Arc::new(Mutex::new(false)); // Whether our data is already being processed in other threads (pointer to a bool shared between this thread and the CPAL thread, hence the Arc<Mutex>)
+    let (stream_error_tx, stream_error_rx) = mpsc::channel::<(String, String)>();
+    
+    // The recorder saves the audio windows sent for recognition (if enabled),
+    // or the current ones on demand
+    
+    let recorder = AudioRecorder::new(capture_config.recorder.clone(), gui_tx.clone());
+    
+    let (recorded_windows_tx, recorded_windows_rx) = mpsc::channel::<RecordedWindow>();
+    
+    // Run the input streams on separate threads, one for the selected device
+    // and one for each additional source
+    
+    let mut sources: Vec<CaptureSource> = vec![CaptureSource::new(&capture_config.source_id, capture_config.clone(), None, &recorded_windows_tx)];
+    
+    for source in capture_config.sources.iter() {
+        sources.push(CaptureSource::new(&source.id, capture_config.for_source(source), Some(source.device.clone()), &recorded_windows_tx));
+    }
+    
+    let mut pulseaudio_state = PulseaudioState {
+        #[cfg(target_os = "linux")]
+        sources: vec![],
+        #[cfg(target_os = "linux")]
+        application_captures: HashMap::new(),
+        #[cfg(target_os = "linux")]
+        events: PulseaudioEvents::start()
+    };
 
     // Send a list of the active microphone-alike devices to the GUI thread
     // (the combo box will be filed with device names when a "DevicesList"
@@ -28,171 +196,560 @@ pub fn microphone_thread(microphone_rx: mpsc::Receiver<MicrophoneMessage>, proce
     // from the same thread as the microphone thread under Windows, see:
     //  - https://github.com/RustAudio/rodio/issues/270
     //  - https://github.com/RustAudio/rodio/issues/214 )
-
-    // Avoid having alsalib polluting stderr (https://github.com/RustAudio/cpal/issues/384)
-    // through disabling stderr temporarily
-    
-    #[cfg(target_os = "linux")]
-    let print_gag = Gag::stderr().unwrap();
-
-    let mut device_names: Vec<String> = vec![];
-
-    for device in host.input_devices().unwrap() {
-        let device_name = device.name().unwrap();
-        
-        // Selecting the "upmix" or "vdownmix" input
-        // source on an ALSA-based configuration may
-        // crash our underlying sound library.
-        
-        if device_name.contains("upmix") || device_name.contains("downmix") {
-            continue;
-        }
-
-        device_names.push(device_name);
-    }
     
-    gui_tx.send(GUIMessage::DevicesList(Box::new(device_names))).unwrap();
+    let mut device_names = list_device_names(&host, &mut pulseaudio_state);
+    let mut last_devices_rescan = Instant::now();
     
-    #[cfg(target_os = "linux")]
-    drop(print_gag);
+    gui_tx.send(GUIMessage::DevicesList(Box::new(device_names.clone())));
     
     // Process ingress inter-thread messages (stopping or starting
     // recording from the microphone, and knowing from which device
     // in particular)
 
-    for message in microphone_rx.iter() {
+    loop {
+        let message = microphone_rx.recv_timeout(POLL_INTERVAL);
+        
+        // Save the audio windows before handling the end of their
+        // processing (the CPAL threads hand them over before sending
+        // the samples for processing)
+        
+        for window in recorded_windows_rx.try_iter() {
+            let source = match sources.iter_mut().find(|source| source.id == window.source_id) {
+                Some(source) => source,
+                None => { continue }
+            };
+            
+            match recorder.save(window.samples, &source.id, source.device_name.as_deref(), window.recognition_attempt) {
+                Ok(metadata_path) => {
+                    if window.recognition_attempt {
+                        source.pending_recording = Some(metadata_path);
+                    }
+                },
+                Err(error) => {
+                    gui_tx.send(GUIMessage::ErrorMessage(format!("{} {}", gettext("Unable to save the recording:"), error)));
+                }
+            };
+        }
+        
         match message {
-            MicrophoneRecordStart(device_name) => {
-                let processing_tx_2 = processing_tx.clone();
-                let gui_tx_2 = gui_tx.clone();
-                let gui_tx_3 = gui_tx.clone();
-                let gui_tx_4 = gui_tx.clone();
-    
-                let err_fn = move |error| {
-                    gui_tx_2.send(GUIMessage::ErrorMessage(format!("{} {}", gettext("Microphone error:"), error))).unwrap();
-                };
+            Ok(MicrophoneRecordStart(device_name)) => {
                 
-                let mut device: cpal::Device = host.default_input_device().unwrap();
+                // Release the previous streams, if any, before a possible
+                // application capture is undone
                 
-                // Avoid having alsalib polluting stderr (https://github.com/RustAudio/cpal/issues/384)
-                // through disabling stderr temporarily
-        
-                #[cfg(target_os = "linux")]
-                let print_gag = Gag::stderr().unwrap();
-
-                for possible_device in host.input_devices().unwrap() {
-                    
-                    if possible_device.name().unwrap() == device_name {
+                for source in sources.iter_mut() {
+                    stop_source(source, &mut pulseaudio_state);
+                }
+                
+                sources[0].device_name = Some(device_name);
+                
+                match start_source(&host, &mut sources[0], &mut pulseaudio_state, &processing_tx, &gui_tx, &stream_error_tx) {
+                    Ok(()) => {
+                        gui_tx.send(GUIMessage::MicrophoneRecording);
                         
-                        device = possible_device;
-                        break;
+                        // Start the additional sources along, those which
+                        // can't be opened are retried later
                         
+                        for source in sources[1..].iter_mut() {
+                            if let Err(error) = start_source(&host, source, &mut pulseaudio_state, &processing_tx, &gui_tx, &stream_error_tx) {
+                                gui_tx.send(GUIMessage::MicrophoneError(error));
+                                
+                                source.reconnection_pending = true;
+                                source.last_reconnection_attempt = Instant::now();
+                            }
+                        }
+                    },
+                    Err(error) => {
+                        sources[0].device_name = None;
+                        
+                        gui_tx.send(GUIMessage::MicrophoneError(error));
+                        gui_tx.send(GUIMessage::MicrophoneStopped);
                     }
+                };
+
+            },
+            
+            Ok(MicrophoneRecordStop) => {
+                
+                let was_listening = sources.iter().any(CaptureSource::is_listening);
+                
+                for source in sources.iter_mut() {
+                    stop_source(source, &mut pulseaudio_state);
                 }
                 
-                #[cfg(target_os = "linux")]
-                drop(print_gag);
-                                
-                let config = device.default_input_config().expect(&gettext("Failed to get default input config"));
+                sources[0].device_name = None;
+                
+                // Stopping may also be requested through MPRIS or D-Bus
+                // while we weren't recording
                 
-                let channels = config.channels();
-                let sample_rate = config.sample_rate().0;
+                if was_listening {
+                    gui_tx.send(GUIMessage::MicrophoneStopped);
+                }
+
+            },
+            
+            Ok(SaveRecording) => {
                 
-                let mut twelve_seconds_buffer: [i16; 16000 * 12] = [0; 16000 * 12];
-                let mut number_unprocessed_samples: usize = 0; // Sample count for the interval of doing Shazam recognition (every 4 seconds)
-                let mut number_unmeasured_samples: usize = 0; // Sample count for doing volume measurement (every 24th of second)
+                // The current windows are handed over by the CPAL threads
+                // when they receive more samples
                 
-                let processing_already_ongoing_2 = processing_already_ongoing.clone();
+                let mut listening = false;
                 
-                stream = Some(match config.sample_format() {
-                    cpal::SampleFormat::F32 => device.build_input_stream(&config.into(), move |data, _: &_| write_data::<f32, f32>(data, &processing_tx_2, gui_tx_3.clone(), channels, sample_rate, &mut twelve_seconds_buffer, &mut number_unprocessed_samples, &mut number_unmeasured_samples, &processing_already_ongoing_2), err_fn).unwrap(),
-                    cpal::SampleFormat::I16 => device.build_input_stream(&config.into(), move |data, _: &_| write_data::<i16, i16>(data, &processing_tx_2, gui_tx_3.clone(), channels, sample_rate, &mut twelve_seconds_buffer, &mut number_unprocessed_samples, &mut number_unmeasured_samples, &processing_already_ongoing_2), err_fn).unwrap(),
-                    cpal::SampleFormat::U16 => device.build_input_stream(&config.into(), move |data, _: &_| write_data::<u16, i16>(data, &processing_tx_2, gui_tx_3.clone(), channels, sample_rate, &mut twelve_seconds_buffer, &mut number_unprocessed_samples, &mut number_unmeasured_samples, &processing_already_ongoing_2), err_fn).unwrap(),
-                });
+                for source in sources.iter().filter(|source| source.stream.is_some()) {
+                    source.recorder_link.save_requested.store(true, Ordering::SeqCst);
+                    listening = true;
+                }
                 
-                stream.as_ref().unwrap().play().unwrap();
+                if !listening {
+                    gui_tx.send(GUIMessage::ErrorMessage(gettext("Nothing to save, as SongRec isn't listening")));
+                }
                 
-                gui_tx_4.send(GUIMessage::MicrophoneRecording).unwrap();
-
             },
             
-            MicrophoneRecordStop => {
-    
-                drop(stream.unwrap());
+            Ok(ProcessingDone(source_id, outcome)) => {
+                
+                if let Some(source) = sources.iter_mut().find(|source| source.id == source_id) {
+                    let mut processing_already_ongoing_borrow = source.processing_already_ongoing.lock().unwrap();
+                    *processing_already_ongoing_borrow = false;
+                    
+                    if let Some(metadata_path) = source.pending_recording.take() {
+                        if let Err(error) = recorder.complete(metadata_path, outcome) {
+                            eprintln!("{} {}", gettext("Unable to save the recording:"), error);
+                        }
+                    }
+                }
                 
-                stream = None;
-
             },
             
-            ProcessingDone => {
+            Ok(Shutdown) | Err(mpsc::RecvTimeoutError::Disconnected) => { break },
+            
+            Err(mpsc::RecvTimeoutError::Timeout) => { }
+        };
+        
+        // Handle errors of the current streams (for example, an USB
+        // microphone being unplugged) by dropping them and trying to
+        // reconnect
+        
+        let mut stream_failed = false;
+        
+        for (source_id, error) in stream_error_rx.try_iter() {
+            let source = match sources.iter_mut().find(|source| source.id == source_id) {
+                Some(source) => source,
+                None => { continue }
+            };
+            
+            // Several errors may be reported for the same stream
+            
+            if source.stream.is_some() {
+                drop(source.stream.take());
+                release_pulseaudio_capture(&mut pulseaudio_state, &source.id);
                 
-                let mut processing_already_ongoing_borrow = processing_already_ongoing.lock().unwrap();
-                *processing_already_ongoing_borrow = false;
+                let device_name = source.device_name.clone().unwrap_or_default();
                 
+                gui_tx.send(GUIMessage::MicrophoneError(MicrophoneError::StreamFailed(device_name, error)));
+                
+                source.reconnection_pending = true;
+                source.last_reconnection_attempt = Instant::now();
+                
+                stream_failed = true;
+            }
+        }
+        
+        // Notice devices being plugged or unplugged, as reported by
+        // PulseAudio, or otherwise by rescanning them periodically (and
+        // right after a stream failed)
+        
+        let devices_changed = match take_pulseaudio_devices_changed(&pulseaudio_state) {
+            Some(devices_changed) => devices_changed || stream_failed,
+            None => stream_failed || last_devices_rescan.elapsed() >= RECONNECTION_INTERVAL
+        };
+        
+        if devices_changed {
+            let new_device_names = list_device_names(&host, &mut pulseaudio_state);
+            last_devices_rescan = Instant::now();
+            
+            if new_device_names != device_names {
+                device_names = new_device_names;
+                
+                gui_tx.send(GUIMessage::DevicesList(Box::new(device_names.clone())));
+                
+                // Switch back to the selected device if it has reappeared
+                
+                for source in sources.iter_mut() {
+                    if let Some(ref device_name) = source.device_name {
+                        if source.using_default_device && device_names.contains(device_name) {
+                            drop(source.stream.take());
+                            source.reconnection_pending = true;
+                        }
+                    }
+                }
+            }
+        }
+        
+        // Silently retry reconnecting, as the error was already reported
+        
+        for source in sources.iter_mut() {
+            if source.reconnection_pending && source.last_reconnection_attempt.elapsed() >= RECONNECTION_INTERVAL {
+                source.last_reconnection_attempt = Instant::now();
+                
+                let reconnected = start_source(&host, source, &mut pulseaudio_state, &processing_tx, &gui_tx, &stream_error_tx).is_ok();
+                
+                if reconnected && source.is_selected_device {
+                    gui_tx.send(GUIMessage::MicrophoneRecording);
+                }
             }
         }
     }
     
+    // Undo the application captures, which would outlive SongRec otherwise
+    
+    let was_listening = sources.iter().any(CaptureSource::is_listening);
+    
+    for source in sources.iter_mut() {
+        stop_source(source, &mut pulseaudio_state);
+    }
+    
+    if was_listening {
+        gui_tx.send(GUIMessage::MicrophoneStopped);
+    }
+    
 }
 
-fn write_data<T, U>(input_samples: &[T], processing_tx: &mpsc::Sender<ProcessingMessage>, gui_tx: glib::Sender<GUIMessage>, channels: u16, sample_rate: u32, twelve_seconds_buffer: &mut [i16], number_unprocessed_samples: &mut usize, number_unmeasured_samples: &mut usize, processing_already_ongoing: &Arc<Mutex<bool>>)
-where
-    T: cpal::Sample + rodio::Sample,
-    U: cpal::Sample,
-{
+/// Open the stream of a capture source on its device. The selected device
+/// is replaced by the default device if it isn't there (anymore).
+
+fn start_source(host: &cpal::Host, source: &mut CaptureSource, pulseaudio_state: &mut PulseaudioState, processing_tx: &mpsc::Sender<ProcessingMessage>, gui_tx: &EventBus, stream_error_tx: &mpsc::Sender<(String, String)>) -> Result<(), MicrophoneError> {
     
-    // Reassemble data into a 12-samples buffer, and do recognition
-    // every 4 seconds if the queue to "processing_tx" is empty
+    let device_name = match source.device_name.clone() {
+        Some(device_name) => device_name,
+        None => return Err(MicrophoneError::NoInputDevice)
+    };
     
-    let input_buffer = rodio::buffer::SamplesBuffer::new::<&[T]>(channels, sample_rate, input_samples);
+    release_pulseaudio_capture(pulseaudio_state, &source.id);
     
-    let converted_file = rodio::source::UniformSourceIterator::new(input_buffer, 1, 16000);
+    let result = match start_stream(host, source, Some(&device_name), pulseaudio_state, processing_tx, gui_tx, stream_error_tx) {
+        Err(MicrophoneError::DeviceNotFound(_)) if source.is_selected_device => {
+            source.using_default_device = true;
+            start_stream(host, source, None, pulseaudio_state, processing_tx, gui_tx, stream_error_tx)
+        },
+        result => {
+            source.using_default_device = false;
+            result
+        }
+    };
+    
+    source.stream = Some(result?);
+    source.reconnection_pending = false;
     
-    let raw_pcm_samples: Vec<i16> = converted_file.collect();
+    Ok(())
+}
+
+fn stop_source(source: &mut CaptureSource, pulseaudio_state: &mut PulseaudioState) {
+    drop(source.stream.take());
+    release_pulseaudio_capture(pulseaudio_state, &source.id);
     
-    if raw_pcm_samples.len() >= 16000 * 12 {
-        twelve_seconds_buffer[.. 16000 * 12].copy_from_slice(&raw_pcm_samples[raw_pcm_samples.len() - 16000 * 12 ..]);
+    source.reconnection_pending = false;
+}
+
+fn list_device_names(host: &cpal::Host, pulseaudio_state: &mut PulseaudioState) -> Vec<String> {
+    
+    // Avoid having alsalib polluting stderr (https://github.com/RustAudio/cpal/issues/384)
+    // through disabling stderr temporarily
+    
+    #[cfg(target_os = "linux")]
+    let print_gag = Gag::stderr().ok();
+
+    let mut device_names: Vec<String> = vec![];
+
+    if let Ok(devices) = host.input_devices() {
+        for device in devices {
+            let device_name = match device.name() {
+                Ok(device_name) => device_name,
+                Err(_) => { continue }
+            };
+            
+            // Selecting the "upmix" or "vdownmix" input
+            // source on an ALSA-based configuration may
+            // crash our underlying sound library.
+            
+            if device_name.contains("upmix") || device_name.contains("downmix") {
+                continue;
+            }
+
+            device_names.push(device_name);
+        }
     }
-    else {
-        let latter_data = twelve_seconds_buffer[raw_pcm_samples.len() ..].to_vec();
+    
+    #[cfg(target_os = "linux")]
+    drop(print_gag);
+    
+    // Also list the PulseAudio or PipeWire sources, including the monitors
+    // of the outputs, so that any of them can be picked directly. These are
+    // recorded from through the "pulse" ALSA plug-in, whose stream is moved
+    // to the source once it is opened
+    
+    #[cfg(target_os = "linux")]
+    {
+        pulseaudio_state.sources = PulseaudioLoopback::list_sources().unwrap_or_default();
+        
+        for source in pulseaudio_state.sources.iter() {
+            device_names.push(source.device_name());
+        }
         
-        twelve_seconds_buffer[.. 16000 * 12 - raw_pcm_samples.len()].copy_from_slice(&latter_data);
-        twelve_seconds_buffer[16000 * 12 - raw_pcm_samples.len() ..].copy_from_slice(&raw_pcm_samples);
+        for application_name in ApplicationCapture::list_application_names() {
+            device_names.push(format!("{}{}", APPLICATION_DEVICE_PREFIX, application_name));
+        }
     }
     
-    *number_unprocessed_samples += raw_pcm_samples.len();
+    #[cfg(not(target_os = "linux"))]
+    let _ = pulseaudio_state;
     
-    let mut processing_already_ongoing_borrow = processing_already_ongoing.lock().unwrap();
+    device_names
+}
 
-    if *number_unprocessed_samples >= 16000 * 4 && *processing_already_ongoing_borrow == false {
-        processing_tx.send(ProcessingMessage::ProcessAudioSamples(Box::new(twelve_seconds_buffer.to_vec()))).unwrap();
-        
-        *number_unprocessed_samples = 0;
-        *processing_already_ongoing_borrow = true;
+/// Whether PulseAudio has reported devices appearing or disappearing since
+/// this was last called, None if this can't be known.
+
+fn take_pulseaudio_devices_changed(pulseaudio_state: &PulseaudioState) -> Option<bool> {
+    #[cfg(target_os = "linux")]
+    return pulseaudio_state.events.as_ref().and_then(PulseaudioEvents::take_devices_changed);
+    
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = pulseaudio_state;
+        None
     }
+}
+
+fn release_pulseaudio_capture(pulseaudio_state: &mut PulseaudioState, source_id: &str) {
+    #[cfg(target_os = "linux")]
+    drop(pulseaudio_state.application_captures.remove(source_id));
     
-    // Do microphone volume measurement every 24th of second (so that we can
-    // update it at 24 FPS) and over the last two 100th of second (so that we
-    // can be sure to measure volume for at most 100 Hz)
+    #[cfg(not(target_os = "linux"))]
+    let _ = (pulseaudio_state, source_id);
+}
+
+/// Select the PulseAudio source to record from, if the device name designates
+/// a PulseAudio source or an application, returning the name of the CPAL
+/// device to open and the name of the PulseAudio source to move its stream
+/// to.
+
+#[cfg(target_os = "linux")]
+fn select_pulseaudio_source(source_id: &str, device_name: Option<&str>, pulseaudio_state: &mut PulseaudioState) -> Result<(Option<String>, Option<String>), MicrophoneError> {
+    let device_name = match device_name {
+        Some(device_name) => device_name,
+        None => return Ok((None, None))
+    };
     
-    *number_unmeasured_samples += raw_pcm_samples.len();
+    let mut pulse_source_name = pulseaudio_state.sources.iter()
+        .find(|source| source.device_name() == device_name)
+        .map(|source| source.name.clone());
     
-    if *number_unmeasured_samples >= 16000 / 24 {
+    if let Some(application_name) = device_name.strip_prefix(APPLICATION_DEVICE_PREFIX) {
+        let capture = ApplicationCapture::start(application_name)
+            .map_err(|error| MicrophoneError::ApplicationCaptureFailed(application_name.to_string(), error.to_string()))?;
         
-        let mut max_s16le_amplitude = 1;
+        pulse_source_name = Some(capture.source_name());
+        pulseaudio_state.application_captures.insert(source_id.to_string(), capture);
+    }
+    
+    match pulse_source_name {
+        Some(pulse_source_name) => Ok((Some("pulse".to_string()), Some(pulse_source_name))),
+        None => Ok((Some(device_name.to_string()), None))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn select_pulseaudio_source(_source_id: &str, device_name: Option<&str>, _pulseaudio_state: &mut PulseaudioState) -> Result<(Option<String>, Option<String>), MicrophoneError> {
+    Ok((device_name.map(str::to_string), None))
+}
+
+/// Move the stream just opened through the "pulse" ALSA plug-in, which
+/// records from the default source, to the selected PulseAudio source. It is
+/// told apart from the other streams of SongRec (those of the other capture
+/// sources) as the one which wasn't there before it was opened.
+
+#[cfg(target_os = "linux")]
+fn move_to_pulseaudio_source(previous_source_outputs: &[u64], pulse_source_name: &str) -> Result<(), String> {
+    
+    // The plug-in may connect its stream shortly after starting
+    
+    for _ in 0..PULSEAUDIO_STREAM_ATTEMPTS {
+        let source_output = PulseaudioLoopback::list_songrec_source_outputs().unwrap_or_default().into_iter()
+            .find(|source_output| !previous_source_outputs.contains(source_output));
         
-        for index in 16000 * 12 - 16000 / 100 * 2 .. 16000 * 12 {
-            if twelve_seconds_buffer[index] > max_s16le_amplitude {
-                max_s16le_amplitude = twelve_seconds_buffer[index];
-            }
+        if let Some(source_output) = source_output {
+            return PulseaudioLoopback::move_source_output(source_output, pulse_source_name).map_err(|error| error.to_string());
         }
         
-        let max_s16le_volume_fraction = max_s16le_amplitude as f32 / 32767.0; // 32767 is the maximum value for an i16 (2**15 - 1)
-        
-        gui_tx.send(GUIMessage::MicrophoneVolumePercent(max_s16le_volume_fraction * 100.0)).unwrap();
+        std::thread::sleep(PULSEAUDIO_STREAM_POLL_INTERVAL);
+    }
+    
+    Err(gettext("The PulseAudio stream of SongRec was not found"))
+}
+
+/// Open and start a stream for a capture source from the given device (or
+/// from the default device if None is passed).
+
+fn start_stream(host: &cpal::Host, source: &CaptureSource, device_name: Option<&str>, pulseaudio_state: &mut PulseaudioState, processing_tx: &mpsc::Sender<ProcessingMessage>, gui_tx: &EventBus, stream_error_tx: &mpsc::Sender<(String, String)>) -> Result<cpal::Stream, MicrophoneError> {
+    
+    let capture_config = &source.capture_config;
+    
+    let (cpal_device_name, pulse_source_name) = select_pulseaudio_source(&source.id, device_name, pulseaudio_state)?;
+    
+    #[cfg(target_os = "linux")]
+    let previous_source_outputs: Vec<u64> = match pulse_source_name {
+        Some(_) => PulseaudioLoopback::list_songrec_source_outputs().unwrap_or_default(),
+        None => vec![]
+    };
+    
+    let display_device_name = device_name.unwrap_or("default").to_string();
+    
+    // Avoid having alsalib polluting stderr (https://github.com/RustAudio/cpal/issues/384)
+    // through disabling stderr temporarily
+
+    #[cfg(target_os = "linux")]
+    let print_gag = Gag::stderr().ok();
+    
+    let device: Option<cpal::Device> = match cpal_device_name {
+        Some(ref cpal_device_name) => host.input_devices().ok().and_then(|mut devices| {
+            devices.find(|possible_device| possible_device.name().ok().as_ref() == Some(cpal_device_name))
+        }),
+        None => host.default_input_device()
+    };
+    
+    #[cfg(target_os = "linux")]
+    drop(print_gag);
+    
+    let device = match (device, cpal_device_name) {
+        (Some(device), _) => device,
+        (None, Some(_)) => return Err(MicrophoneError::DeviceNotFound(display_device_name)),
+        (None, None) => return Err(MicrophoneError::NoInputDevice)
+    };
+    
+    // Use the sample rate, format and channel count from the capture
+    // configuration if the device supports them, and its default
+    // configuration otherwise
+    
+    let preferred_config = device.supported_input_configs().ok()
+        .and_then(|supported_configs| capture_config.select_stream_config(supported_configs));
+    
+    let config = match preferred_config {
+        Some(config) => config,
+        None => device.default_input_config()
+            .map_err(|error| MicrophoneError::ConfigUnavailable(display_device_name.clone(), error.to_string()))?
+    };
+    
+    let channels = config.channels();
+    let sample_rate = config.sample_rate().0;
+    
+    let mut source_state = SourceState {
+        source_id: source.id.clone(),
+        processing_tx: processing_tx.clone(),
+        gui_tx: gui_tx.clone(),
+        channels,
+        downmix: capture_config.downmix,
+        resampler: Resampler::new(sample_rate, 16000),
+        samples_buffer: vec![0; 16000 * capture_config.buffer_seconds as usize],
+        number_unprocessed_samples: 0,
+        recognition_interval_samples: 16000 * capture_config.recognition_interval_seconds as usize,
+        level_meter: LevelMeter::new(channels, sample_rate),
+        processing_already_ongoing: source.processing_already_ongoing.clone(),
+        recorder_link: source.recorder_link.clone()
+    };
+    
+    let stream_error_tx_2 = stream_error_tx.clone();
+    
+    let error_source_id = source.id.clone();
+    
+    let err_fn = move |error: cpal::StreamError| {
+        stream_error_tx_2.send((error_source_id.clone(), error.to_string())).ok();
+    };
+    
+    let stream = match config.sample_format() {
+        cpal::SampleFormat::F32 => device.build_input_stream(&config.into(), move |data, _: &_| write_data::<f32>(data, &mut source_state), err_fn),
+        cpal::SampleFormat::I16 => device.build_input_stream(&config.into(), move |data, _: &_| write_data::<i16>(data, &mut source_state), err_fn),
+        cpal::SampleFormat::U16 => device.build_input_stream(&config.into(), move |data, _: &_| write_data::<u16>(data, &mut source_state), err_fn),
+    }.map_err(|error| MicrophoneError::StreamBuildFailed(display_device_name.clone(), error.to_string()))?;
+    
+    stream.play().map_err(|error| MicrophoneError::StreamBuildFailed(display_device_name.clone(), error.to_string()))?;
+    
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(ref pulse_source_name) = pulse_source_name {
+            move_to_pulseaudio_source(&previous_source_outputs, pulse_source_name)
+                .map_err(|error| MicrophoneError::StreamBuildFailed(display_device_name.clone(), error))?;
+        }
+    }
+    
+    #[cfg(not(target_os = "linux"))]
+    let _ = pulse_source_name;
+    
+    Ok(stream)
+}
+
+fn write_data<T>(input_samples: &[T], state: &mut SourceState)
+where
+    T: cpal::Sample,
+{
+    
+    // Reassemble data into a 12-seconds buffer (by default), and do recognition
+    // every 4 seconds (by default) if the queue to "processing_tx" is empty
+    
+    let mono_samples: Vec<f32> = downmix_samples(input_samples, state.channels, state.downmix);
+    
+    let raw_pcm_samples: Vec<i16> = to_i16_samples(&state.resampler.process(&mono_samples));
+    
+    let buffer_length = state.samples_buffer.len();
+    
+    if raw_pcm_samples.len() >= buffer_length {
+        state.samples_buffer.copy_from_slice(&raw_pcm_samples[raw_pcm_samples.len() - buffer_length ..]);
+    }
+    else {
+        state.samples_buffer.copy_within(raw_pcm_samples.len() .., 0);
+        state.samples_buffer[buffer_length - raw_pcm_samples.len() ..].copy_from_slice(&raw_pcm_samples);
+    }
+    
+    state.number_unprocessed_samples += raw_pcm_samples.len();
+    
+    let mut processing_already_ongoing_borrow = state.processing_already_ongoing.lock().unwrap();
+
+    let mut window_recorded = false;
+    
+    if state.number_unprocessed_samples >= state.recognition_interval_samples && !*processing_already_ongoing_borrow {
+        if state.recorder_link.record_each_attempt {
+            state.recorder_link.windows_tx.send(RecordedWindow { source_id: state.source_id.clone(), samples: state.samples_buffer.to_vec(), recognition_attempt: true }).ok();
+            window_recorded = true;
+        }
         
-        *number_unmeasured_samples = 0;
+        state.processing_tx.send(ProcessingMessage::ProcessAudioSamples(state.source_id.clone(), Box::new(state.samples_buffer.to_vec()))).unwrap();
         
+        state.number_unprocessed_samples = 0;
+        *processing_already_ongoing_borrow = true;
+    }
+    
+    if state.recorder_link.save_requested.swap(false, Ordering::SeqCst) && !window_recorded {
+        state.recorder_link.windows_tx.send(RecordedWindow { source_id: state.source_id.clone(), samples: state.samples_buffer.to_vec(), recognition_attempt: false }).ok();
+    }
+    
+    // Measure the input level every 24th of second (so that we can update
+    // it at 24 FPS), on the samples as received from the device
+    
+    for level in state.level_meter.process(input_samples) {
+        state.gui_tx.send(GUIMessage::MicrophoneInputLevel(state.source_id.clone(), level));
     }
 }
 
+/// Convert interleaved samples to mono, following the capture configuration.
+
+fn downmix_samples<T: cpal::Sample>(input_samples: &[T], channels: u16, downmix: DownmixStrategy) -> Vec<f32> {
+    let channels = channels.max(1) as usize;
+    
+    input_samples.chunks_exact(channels).map(|frame| {
+        match downmix {
+            DownmixStrategy::Average => frame.iter().map(cpal::Sample::to_f32).sum::<f32>() / channels as f32,
+            DownmixStrategy::Left => cpal::Sample::to_f32(&frame[0]),
+            DownmixStrategy::Right => cpal::Sample::to_f32(&frame[channels.min(2) - 1])
+        }
+    }).collect()
+}
@@ -1,24 +1,53 @@
 use std::sync::mpsc;
 
+use crate::core::capture_config::CaptureConfig;
+use crate::core::event_bus::EventBus;
 use crate::core::thread_messages::{*, ProcessingMessage::*};
 
 use crate::fingerprinting::algorithm::SignatureGenerator;
+use crate::fingerprinting::preprocessing::SourcePreprocessing;
+use crate::fingerprinting::quality::SignatureQuality;
 
-pub fn processing_thread(processing_rx: mpsc::Receiver<ProcessingMessage>, http_tx: mpsc::Sender<HTTPMessage>, gui_tx: glib::Sender<GUIMessage>) {
+pub fn processing_thread(processing_rx: mpsc::Receiver<ProcessingMessage>, http_tx: mpsc::Sender<HTTPMessage>, gui_tx: EventBus, microphone_tx: mpsc::Sender<MicrophoneMessage>, capture_config: CaptureConfig, preprocessing: SourcePreprocessing) {
+    
+    // Kept across recognitions, so that its buffers are allocated only once
+    
+    let mut signature_generator = SignatureGenerator::new();
     
     for message in processing_rx.iter() {
         
-        let signature = match message {
-            ProcessAudioFile(input_file_string) => SignatureGenerator::make_signature_from_file(&input_file_string),
-            ProcessAudioSamples(audio_samples) => Ok(SignatureGenerator::make_signature_from_buffer(&audio_samples))
+        let http_message = match message {
+            ProcessAudioFile(input_file_string) => SignatureGenerator::make_signature_from_file_with_preprocessing(&input_file_string, &preprocessing.file)
+                .map(|signature| HTTPMessage::RecognizeSignature(Box::new(signature))),
+            ProcessAudioSamples(source_id, audio_samples) => {
+                let signature = signature_generator.make_signature(&capture_config.get_preprocessing(&source_id, &preprocessing).process(&audio_samples));
+                
+                // Don't spend a request on audio that Shazam is unlikely to
+                // recognize (such as silence), but let the microphone thread
+                // send more audio from the same source
+                
+                if !SignatureQuality::new(&signature).is_likely_recognizable() {
+                    // The microphone thread may have been shut down since
+                    
+                    microphone_tx.send(MicrophoneMessage::ProcessingDone(source_id, Box::new(RecognitionOutcome {
+                        signature_uri: signature.encode_to_uri().ok(),
+                        shazam_json: None,
+                        error: Some(RecognitionError::Unrecognizable)
+                    }))).ok();
+                    
+                    continue;
+                }
+                
+                Ok(HTTPMessage::RecognizeMicrophoneSignature(source_id, Box::new(signature)))
+            }
         };
         
-        match signature {
-            Ok(signature) => {
-                http_tx.send(HTTPMessage::RecognizeSignature(Box::new(signature))).unwrap();
+        match http_message {
+            Ok(http_message) => {
+                http_tx.send(http_message).unwrap();
             },
             Err(error) => {
-                gui_tx.send(GUIMessage::ErrorMessage(error.to_string())).unwrap();
+                gui_tx.send(GUIMessage::ErrorMessage(error.to_string()));
             }
         };
             
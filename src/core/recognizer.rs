@@ -0,0 +1,305 @@
+use std::error::Error;
+use std::sync::{Arc, mpsc};
+use std::time::Duration;
+
+use crate::core::capture_config::CaptureConfig;
+use crate::core::event_bus::{EventBus, EventCallback, EventSink};
+use crate::core::http_thread::{http_thread, RecognitionBackend, ShazamBackend};
+use crate::core::microphone_thread::microphone_thread;
+use crate::core::processing_thread::processing_thread;
+use crate::core::thread_messages::{GUIMessage, HTTPMessage, MicrophoneMessage, ProcessingMessage};
+use crate::fingerprinting::preprocessing::SourcePreprocessing;
+use crate::fingerprinting::signature_format::DecodedSignature;
+use crate::utils::thread::spawn_big_thread;
+
+/// This module is the entry point for applications embedding SongRec: a
+/// "Recognizer" runs the microphone, processing and HTTP threads, and takes
+/// requests (listening to a device, recognizing a file or a fingerprint),
+/// the outcome of which is reported through events. The command-line
+/// interface and the GUI are built upon it.
+///
+/// Events are the inter-thread messages of "thread_messages.rs" (songs
+/// recognized, errors, devices lists, input levels...). They go through an
+/// event bus (see "event_bus.rs") to the callbacks and sinks registered on
+/// the builder, and to each subscriber, which reads them as a blocking
+/// iterator (see the "recognize_file" example). No main loop is needed.
+///
+/// The threads are stopped when the last handle to the recognizer is
+/// dropped (once they have finished the work they were given, such as a
+/// pending request to Shazam), after which the iterators of subscribers end.
+
+pub type RecognizerEvent = GUIMessage;
+
+pub struct RecognizerBuilder {
+    capture_config: CaptureConfig,
+    preprocessing: SourcePreprocessing,
+    backend: Box<dyn RecognitionBackend>,
+    event_bus: EventBus
+}
+
+impl Default for RecognizerBuilder {
+    fn default() -> Self {
+        RecognizerBuilder {
+            capture_config: CaptureConfig::default(),
+            preprocessing: SourcePreprocessing::default(),
+            backend: Box::new(ShazamBackend),
+            event_bus: EventBus::new()
+        }
+    }
+}
+
+impl RecognizerBuilder {
+
+    pub fn new() -> Self {
+        RecognizerBuilder::default()
+    }
+
+    pub fn capture_config(self: Self, capture_config: CaptureConfig) -> Self {
+        RecognizerBuilder { capture_config, ..self }
+    }
+
+    pub fn preprocessing(self: Self, preprocessing: SourcePreprocessing) -> Self {
+        RecognizerBuilder { preprocessing, ..self }
+    }
+
+    /// Recognize signatures with something else than Shazam.
+
+    pub fn backend<B: RecognitionBackend + 'static>(self: Self, backend: B) -> Self {
+        RecognizerBuilder { backend: Box::new(backend), ..self }
+    }
+
+    /// Call the given function for each event, from the thread which sent
+    /// it (so it should return quickly).
+
+    pub fn on_event<F: FnMut(&RecognizerEvent) + Send + 'static>(self: Self, callback: F) -> Self {
+        self.event_bus.add_sink(EventCallback::new(callback));
+        self
+    }
+
+    /// Send the events to the given sink, such as a channel.
+
+    pub fn event_sink<S: EventSink + 'static>(self: Self, sink: S) -> Self {
+        self.event_bus.add_sink(sink);
+        self
+    }
+
+    /// Receive the events of the recognizer, from its start (so that the
+    /// first devices list isn't missed).
+
+    pub fn subscribe(self: &Self) -> RecognizerEvents {
+        subscribe(&self.event_bus)
+    }
+
+    /// Validate the capture parameters, and start the threads.
+
+    pub fn build(self: Self) -> Result<Recognizer, Box<dyn Error>> {
+
+        self.capture_config.validate()?;
+
+        let gui_tx = self.event_bus.clone();
+
+        let (microphone_tx, microphone_rx) = mpsc::channel();
+        let (processing_tx, processing_rx) = mpsc::channel();
+        let (http_tx, http_rx) = mpsc::channel();
+
+        let processing_microphone_tx = processing_tx.clone();
+        let processing_http_tx = http_tx.clone();
+        let microphone_processing_tx = microphone_tx.clone();
+        let microphone_http_tx = microphone_tx.clone();
+
+        let microphone_gui_tx = gui_tx.clone();
+        let processing_gui_tx = gui_tx.clone();
+
+        let capture_config = self.capture_config;
+        let processing_capture_config = capture_config.clone();
+        let preprocessing = self.preprocessing;
+        let backend = self.backend;
+
+        spawn_big_thread(move || { // microphone_rx, processing_tx
+            microphone_thread(microphone_rx, processing_microphone_tx, microphone_gui_tx, capture_config);
+        });
+
+        spawn_big_thread(move || { // processing_rx, http_tx, microphone_tx
+            processing_thread(processing_rx, processing_http_tx, processing_gui_tx, microphone_processing_tx, processing_capture_config, preprocessing);
+        });
+
+        spawn_big_thread(move || { // http_rx
+            http_thread(http_rx, gui_tx, microphone_http_tx, backend);
+        });
+
+        Ok(Recognizer {
+            channels: Arc::new(RecognizerChannels {
+                microphone_tx,
+                processing_tx,
+                http_tx
+            }),
+            event_bus: self.event_bus
+        })
+
+    }
+
+}
+
+/// A handle to the threads of a recognizer, which can be cloned and passed
+/// to other threads. The threads are stopped when the last handle is
+/// dropped.
+
+#[derive(Clone)]
+pub struct Recognizer {
+    channels: Arc<RecognizerChannels>,
+    event_bus: EventBus
+}
+
+/// The channels to the threads of a recognizer, shared by its handles.
+
+struct RecognizerChannels {
+    microphone_tx: mpsc::Sender<MicrophoneMessage>,
+    processing_tx: mpsc::Sender<ProcessingMessage>,
+    http_tx: mpsc::Sender<HTTPMessage>
+}
+
+impl Drop for RecognizerChannels {
+    fn drop(self: &mut Self) {
+
+        // The threads hold the channels to each other, so the microphone
+        // thread is told to stop: the processing thread then stops as no
+        // more audio can be sent to it, and the HTTP thread after it
+
+        self.microphone_tx.send(MicrophoneMessage::Shutdown).ok();
+
+    }
+}
+
+impl Recognizer {
+
+    pub fn builder() -> RecognizerBuilder {
+        RecognizerBuilder::new()
+    }
+
+    /// Listen to the given audio device (along with the other capture
+    /// sources of the capture parameters, if any), recognizing songs at the
+    /// configured interval until stopped.
+
+    pub fn start_listening(self: &Self, device_name: &str) {
+        self.channels.microphone_tx.send(MicrophoneMessage::MicrophoneRecordStart(device_name.to_string())).unwrap();
+    }
+
+    pub fn stop_listening(self: &Self) {
+        self.channels.microphone_tx.send(MicrophoneMessage::MicrophoneRecordStop).unwrap();
+    }
+
+    /// Listen to another audio device.
+
+    pub fn restart_listening(self: &Self, device_name: &str) {
+        self.stop_listening();
+        self.start_listening(device_name);
+    }
+
+    /// Save the audio currently heard by each capture source, see
+    /// "audio_recorder.rs".
+
+    pub fn save_recording(self: &Self) {
+        self.channels.microphone_tx.send(MicrophoneMessage::SaveRecording).unwrap();
+    }
+
+    pub fn recognize_file(self: &Self, file_path: &str) {
+        self.channels.processing_tx.send(ProcessingMessage::ProcessAudioFile(file_path.to_string())).unwrap();
+    }
+
+    pub fn recognize_signature(self: &Self, signature: DecodedSignature) {
+        self.channels.http_tx.send(HTTPMessage::RecognizeSignature(Box::new(signature))).unwrap();
+    }
+
+    /// Receive the events of the recognizer from now on.
+
+    pub fn subscribe(self: &Self) -> RecognizerEvents {
+        subscribe(&self.event_bus)
+    }
+
+}
+
+/// The events received by a subscriber. Iterating blocks until the next
+/// event.
+
+pub struct RecognizerEvents {
+    events_rx: mpsc::Receiver<RecognizerEvent>
+}
+
+impl RecognizerEvents {
+
+    pub fn recv_timeout(self: &Self, timeout: Duration) -> Option<RecognizerEvent> {
+        self.events_rx.recv_timeout(timeout).ok()
+    }
+
+    pub fn try_recv(self: &Self) -> Option<RecognizerEvent> {
+        self.events_rx.try_recv().ok()
+    }
+
+}
+
+impl Iterator for RecognizerEvents {
+    type Item = RecognizerEvent;
+
+    fn next(self: &mut Self) -> Option<RecognizerEvent> {
+        self.events_rx.recv().ok()
+    }
+}
+
+fn subscribe(event_bus: &EventBus) -> RecognizerEvents {
+
+    let (events_tx, events_rx) = mpsc::channel();
+
+    event_bus.add_sink(events_tx);
+
+    RecognizerEvents { events_rx }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::thread_messages::{RecognitionError, SongRecognizedMessage};
+    use crate::fingerprinting::algorithm::SignatureGenerator;
+    use crate::fingerprinting::test_fixtures::AUDIO_FILE_PATH;
+
+    struct NoMatchBackend;
+
+    impl RecognitionBackend for NoMatchBackend {
+        fn recognize(self: &mut Self, _signature: DecodedSignature) -> Result<SongRecognizedMessage, RecognitionError> {
+            Err(RecognitionError::NoMatch)
+        }
+    }
+
+    #[test]
+    fn threads_stop_with_the_last_handle() {
+        let builder = Recognizer::builder().backend(NoMatchBackend);
+        let events = builder.subscribe();
+        let recognizer = builder.build().unwrap();
+        let other_recognizer = recognizer.clone();
+
+        recognizer.recognize_signature(SignatureGenerator::make_signature_from_file(AUDIO_FILE_PATH).unwrap());
+
+        drop(recognizer);
+
+        // The events end once the threads are gone, which they aren't as
+        // long as a handle remains
+
+        let (ended_tx, ended_rx) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            let event_count = events.count();
+            ended_tx.send(event_count).unwrap();
+        });
+
+        assert!(ended_rx.recv_timeout(Duration::from_secs(1)).is_err());
+
+        drop(other_recognizer);
+
+        let event_count = ended_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+
+        // At least the devices list, and the outcome of the recognition
+
+        assert!(event_count >= 2);
+    }
+
+}
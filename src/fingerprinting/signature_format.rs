@@ -6,8 +6,25 @@ use std::cmp::Ordering;
 use gettextrs::gettext;
 use crc32fast::Hasher;
 
+use crate::fingerprinting::lure::{Lure, LureOptions};
+
 const DATA_URI_PREFIX: &str = "data:audio/vnd.shazam.sig;base64,";
 
+/// The sample rates that can be stored in the header of a signature (see
+/// "shifted_sample_rate_id" below).
+
+pub const SUPPORTED_SAMPLE_RATES_HZ: [u32; 6] = [8000, 11025, 16000, 32000, 44100, 48000];
+
+const SAMPLES_PER_FFT_PASS: u64 = 128;
+
+// Peaks of the same band closer than this are considered duplicates when
+// merging signatures (bins are stored multiplied by 64, so this is one FFT
+// bin and one FFT pass)
+
+const DUPLICATE_PEAK_MAXIMUM_PASS_DISTANCE: u32 = 1;
+const DUPLICATE_PEAK_MAXIMUM_BIN_DISTANCE: i32 = 64;
+
+#[derive(Clone)]
 pub struct FrequencyPeak {
     pub fft_pass_number: u32,
     pub peak_magnitude: u16,
@@ -17,9 +34,9 @@ pub struct FrequencyPeak {
 
 impl FrequencyPeak {
        
-    /// Convert back a FFT bin to a frequency, given a 16 KHz sample
-    /// rate, 1024 useful bins and the multiplication by 64 made before
-    /// storing the information
+    /// Convert back a FFT bin to a frequency, given the sample rate of
+    /// the signature, 1024 useful bins and the multiplication by 64 made
+    /// before storing the information
     
     pub fn get_frequency_hz(self: &Self) -> f32 {
         
@@ -35,8 +52,8 @@ impl FrequencyPeak {
         
     }
     
-    /// Assume that new FFT bins are emitted every 128 samples, on a
-    /// standard 16 KHz sample rate basis.
+    /// Assume that new FFT bins are emitted every 128 samples, at the
+    /// sample rate of the signature.
     
     pub fn get_seconds(self: &Self) -> f32 {
         
@@ -80,6 +97,7 @@ struct RawSignatureHeader {
     
 }
 
+#[derive(Clone)]
 pub struct DecodedSignature {
     
     pub sample_rate_hz: u32,
@@ -212,6 +230,8 @@ impl DecodedSignature {
     
     pub fn encode_to_binary(self: &Self) -> Result<Vec<u8>, Box<dyn Error>> {
         
+        self.check_invariants()?;
+        
         let mut cursor = Cursor::new(vec![]);
         
         // Please see the RawSignatureHeader structure definition above for
@@ -252,8 +272,6 @@ impl DecodedSignature {
             
             for frequency_peak in frequency_peaks {
                 
-                assert!(frequency_peak.fft_pass_number >= fft_pass_number);
-                
                 if frequency_peak.fft_pass_number - fft_pass_number >= 255 {
                     
                     peaks_cursor.write_u8(0xff)?;
@@ -304,66 +322,345 @@ impl DecodedSignature {
         
     }
     
-    pub fn to_lure(self: &Self) -> Result<Vec<i16>, Box<dyn Error>> {
+    /// Check the invariants that the binary format relies upon: a sample rate
+    /// supported by the format, peaks of each band sorted by FFT pass number,
+    /// and peaks at the sample rate of the signature.
+    
+    pub fn check_invariants(self: &Self) -> Result<(), Box<dyn Error>> {
         
-        let mut buffer: Vec<i16> = [0].repeat((self.number_samples as f32 / self.sample_rate_hz as f32 * 16000.0) as usize);
+        if !SUPPORTED_SAMPLE_RATES_HZ.contains(&self.sample_rate_hz) {
+            return Err(gettext("Invalid sample rate passed when encoding Shazam packet").into());
+        }
         
-        let samples_per_sine = (1.0 / (16000.0 / 2048.0) * 16000.0 * 0.5) as usize; // Shazam uses buffers of 2048 samples at 16,000 Hz to perform recognition, which are fully renewed every 16 iterations.
-
         for frequency_peaks in self.frequency_band_to_sound_peaks.values() {
             
-            for frequency_peak in frequency_peaks {
+            if frequency_peaks.windows(2).any(|pair| pair[1].fft_pass_number < pair[0].fft_pass_number) {
+                return Err(gettext("The frequency peaks of a Shazam signature should be sorted by FFT pass number").into());
+            }
+            
+            if frequency_peaks.iter().any(|frequency_peak| frequency_peak.sample_rate_hz != self.sample_rate_hz) {
+                return Err(gettext("The frequency peaks of a Shazam signature should have the sample rate of the signature").into());
+            }
+            
+        }
+        
+        Ok(())
+        
+    }
+    
+    /// Cut the signature to the given time range (in seconds from its start,
+    /// rounded to the nearest FFT pass), so that the returned signature
+    /// starts at the beginning of the range.
+    
+    pub fn slice(self: &Self, start_seconds: f32, end_seconds: f32) -> Result<Self, Box<dyn Error>> {
+        
+        if !(start_seconds >= 0.0 && start_seconds < end_seconds) {
+            return Err(gettext("Invalid time range for slicing a Shazam signature").into());
+        }
+        
+        let start_sample = self.seconds_to_fft_pass(start_seconds).saturating_mul(SAMPLES_PER_FFT_PASS);
+        let end_sample = self.seconds_to_fft_pass(end_seconds).saturating_mul(SAMPLES_PER_FFT_PASS).min(self.number_samples as u64);
+        
+        if start_sample >= end_sample {
+            return Err(gettext("The time range for slicing a Shazam signature is outside of the signature").into());
+        }
+        
+        let start_fft_pass = (start_sample / SAMPLES_PER_FFT_PASS) as u32;
+        
+        let mut frequency_band_to_sound_peaks: HashMap<FrequencyBand, Vec<FrequencyPeak>> = HashMap::new();
+        
+        for (frequency_band, frequency_peaks) in self.frequency_band_to_sound_peaks.iter() {
+            
+            let sliced_peaks: Vec<FrequencyPeak> = frequency_peaks.iter()
+                .filter(|frequency_peak| {
+                    let peak_sample = frequency_peak.fft_pass_number as u64 * SAMPLES_PER_FFT_PASS;
+                    
+                    peak_sample >= start_sample && peak_sample < end_sample
+                })
+                .map(|frequency_peak| FrequencyPeak {
+                    fft_pass_number: frequency_peak.fft_pass_number - start_fft_pass,
+                    ..frequency_peak.clone()
+                })
+                .collect();
+            
+            if !sliced_peaks.is_empty() {
+                frequency_band_to_sound_peaks.insert(*frequency_band, sliced_peaks);
+            }
+            
+        }
+        
+        Ok(DecodedSignature {
+            sample_rate_hz: self.sample_rate_hz,
+            number_samples: (end_sample - start_sample) as u32,
+            frequency_band_to_sound_peaks
+        })
+        
+    }
+    
+    /// Add the frequency peaks of another signature (at the same sample rate)
+    /// starting from the given time of the current signature (in seconds,
+    /// rounded to the nearest FFT pass), extending the current signature if
+    /// needed. Peaks present in both signatures are kept once.
+    
+    pub fn merge(self: &mut Self, other: &DecodedSignature, offset_seconds: f32) -> Result<(), Box<dyn Error>> {
+        
+        if offset_seconds.is_nan() || offset_seconds < 0.0 {
+            return Err(gettext("Invalid time offset for merging Shazam signatures").into());
+        }
+        
+        let offset_fft_pass = self.seconds_to_fft_pass(offset_seconds);
+        
+        self.merge_at_fft_pass(other, offset_fft_pass)
+        
+    }
+    
+    /// Add the frequency peaks of the next signature of a stream (at the same
+    /// sample rate) after the end of the current signature.
+    
+    pub fn append(self: &mut Self, other: &DecodedSignature) -> Result<(), Box<dyn Error>> {
+        
+        let number_samples = self.number_samples as u64 + other.number_samples as u64;
+        
+        let offset_fft_pass = (self.number_samples as u64 + SAMPLES_PER_FFT_PASS / 2) / SAMPLES_PER_FFT_PASS;
+        
+        self.merge_at_fft_pass(other, offset_fft_pass)?;
+        
+        // When the length of the current signature is not a multiple of the
+        // FFT pass length, the peaks of the next one are rounded to the
+        // nearest FFT pass, but the durations of both are kept
+        
+        self.number_samples = number_samples as u32;
+        
+        Ok(())
+        
+    }
+    
+    /// Join consecutive signatures of a stream into one.
+    
+    pub fn concatenate(signatures: &[DecodedSignature]) -> Result<Self, Box<dyn Error>> {
+        
+        let (first_signature, next_signatures) = signatures.split_first()
+            .ok_or_else(|| gettext("No Shazam signature to concatenate"))?;
+        
+        let mut signature = first_signature.clone();
+        
+        for next_signature in next_signatures {
+            signature.append(next_signature)?;
+        }
+        
+        Ok(signature)
+        
+    }
+    
+    /// Keep only one of the peaks of a same band which are at the same time
+    /// and frequency (within one FFT pass and one FFT bin), for example after
+    /// merging overlapping signatures. The peak with the highest magnitude
+    /// is kept.
+    
+    pub fn deduplicate_peaks(self: &mut Self) {
+        
+        for frequency_peaks in self.frequency_band_to_sound_peaks.values_mut() {
+            
+            let mut sorted_peaks = std::mem::take(frequency_peaks);
+            sorted_peaks.sort_by_key(|frequency_peak| (frequency_peak.fft_pass_number, frequency_peak.corrected_peak_frequency_bin));
+            
+            for frequency_peak in sorted_peaks {
                 
-                let start_offset_of_sine = (frequency_peak.get_seconds() * 16000.0) as usize;
-                let end_offset_of_sine = start_offset_of_sine + samples_per_sine;
-                let amplitude = frequency_peak.get_amplitude_pcm() as f32;
-                let base_frequency = frequency_peak.get_frequency_hz() as f32;
+                // Look back at the peaks kept for close FFT passes
                 
-                if end_offset_of_sine < self.number_samples as usize {
-                    
-                    // Create harmonics to make it sound less though
-                    
-                    let frequencies: Vec<f32> = vec![base_frequency];
-                    
-                    /*let mut next_frequency = base_frequency / 2.0;
-                    while next_frequency > 80.0 {
-                        frequencies.insert(0, next_frequency);
-                        
-                        next_frequency /= 2.0;
-                    }
-                    next_frequency = base_frequency * 2.0;
-                    while next_frequency < 5500.0 {
-                        frequencies.push(next_frequency);
-                        
-                        next_frequency *= 2.0;
-                    }*/
-                    
-                    for frequency in frequencies {
-                    
-                        for num_sample in start_offset_of_sine..end_offset_of_sine {
-                    
-                            let soften_factor = match frequency == base_frequency {
-                                true => 1.0,
-                                false => 1.0 / 3.0
-                            };
-
-                            // let middle_sample = start_offset_of_sine + (end_offset_of_sine - start_offset_of_sine) / 2;
-                            // soften_factor *= (samples_per_sine as i32 - (middle_sample as i32 - num_sample as i32).abs()) as f32 / samples_per_sine as f32;
-                    
-                            buffer[num_sample] += ((2.0 * 3.14159265 * frequency * num_sample as f32 / 16000.0).sin() * amplitude * soften_factor) as i16;
-                        
+                let duplicate_peak = frequency_peaks.iter_mut().rev()
+                    .take_while(|kept_peak| frequency_peak.fft_pass_number - kept_peak.fft_pass_number <= DUPLICATE_PEAK_MAXIMUM_PASS_DISTANCE)
+                    .find(|kept_peak| (kept_peak.corrected_peak_frequency_bin as i32 - frequency_peak.corrected_peak_frequency_bin as i32).abs() <= DUPLICATE_PEAK_MAXIMUM_BIN_DISTANCE);
+                
+                match duplicate_peak {
+                    Some(kept_peak) => {
+                        if frequency_peak.peak_magnitude > kept_peak.peak_magnitude {
+                            *kept_peak = frequency_peak;
                         }
-                        
+                    },
+                    None => {
+                        frequency_peaks.push(frequency_peak);
                     }
-                    
-                }
+                };
                 
             }
             
+            // A replaced peak may be one FFT pass later than the ones
+            // that follow it
+            
+            frequency_peaks.sort_by_key(|frequency_peak| frequency_peak.fft_pass_number);
+            
         }
-                
-        Ok(buffer)
         
     }
     
+    fn seconds_to_fft_pass(self: &Self, seconds: f32) -> u64 {
+        
+        (seconds as f64 * self.sample_rate_hz as f64 / SAMPLES_PER_FFT_PASS as f64).round() as u64
+        
+    }
+    
+    fn merge_at_fft_pass(self: &mut Self, other: &DecodedSignature, offset_fft_pass: u64) -> Result<(), Box<dyn Error>> {
+        
+        if other.sample_rate_hz != self.sample_rate_hz {
+            return Err(gettext("Only Shazam signatures with the same sample rate can be joined").into());
+        }
+        
+        let number_samples = offset_fft_pass.saturating_mul(SAMPLES_PER_FFT_PASS).saturating_add(other.number_samples as u64).max(self.number_samples as u64);
+        
+        if number_samples > u32::MAX as u64 {
+            return Err(gettext("The joined Shazam signature would be too long").into());
+        }
+        
+        for (frequency_band, frequency_peaks) in other.frequency_band_to_sound_peaks.iter() {
+            
+            self.frequency_band_to_sound_peaks.entry(*frequency_band).or_default()
+                .extend(frequency_peaks.iter().map(|frequency_peak| FrequencyPeak {
+                    fft_pass_number: frequency_peak.fft_pass_number + offset_fft_pass as u32,
+                    ..frequency_peak.clone()
+                }));
+            
+        }
+        
+        self.number_samples = number_samples as u32;
+        
+        self.deduplicate_peaks();
+        
+        Ok(())
+        
+    }
+    
+    /// Produce tones matching the frequency peaks of the signature, as mono
+    /// samples at the sample rate of the signature (see "lure.rs" for more
+    /// options).
+    
+    pub fn to_lure(self: &Self) -> Result<Vec<i16>, Box<dyn Error>> {
+        
+        Ok(Lure::new(self, &LureOptions::default())?.samples)
+        
+    }
+    
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprinting::algorithm::SignatureGenerator;
+    use crate::fingerprinting::test_fixtures::AUDIO_FILE_PATH;
+
+    /// The peaks of a signature, comparable between signatures.
+
+    fn get_peaks(signature: &DecodedSignature) -> Vec<(FrequencyBand, u32, u16, u16)> {
+        let mut peaks: Vec<(FrequencyBand, u32, u16, u16)> = signature.frequency_band_to_sound_peaks.iter()
+            .flat_map(|(frequency_band, frequency_peaks)| frequency_peaks.iter().map(move |frequency_peak|
+                (*frequency_band, frequency_peak.fft_pass_number, frequency_peak.corrected_peak_frequency_bin, frequency_peak.peak_magnitude)))
+            .collect();
+
+        peaks.sort_unstable();
+        peaks
+    }
+
+    fn make_deduplicated(signature: &DecodedSignature) -> DecodedSignature {
+        let mut signature = signature.clone();
+        signature.deduplicate_peaks();
+        signature
+    }
+
+    #[test]
+    fn slices_can_be_encoded() {
+        let signature = SignatureGenerator::make_signature_from_file(AUDIO_FILE_PATH).unwrap();
+
+        let slice = signature.slice(1.0, 4.0).unwrap();
+
+        assert_eq!(slice.number_samples, 3 * 16000);
+
+        let expected_peaks: Vec<(FrequencyBand, u32, u16, u16)> = get_peaks(&signature).into_iter()
+            .filter(|(_, fft_pass_number, _, _)| (125..500).contains(fft_pass_number))
+            .map(|(frequency_band, fft_pass_number, frequency_bin, magnitude)| (frequency_band, fft_pass_number - 125, frequency_bin, magnitude))
+            .collect();
+
+        assert!(!expected_peaks.is_empty());
+        assert_eq!(get_peaks(&slice), expected_peaks);
+
+        let decoded_slice = DecodedSignature::decode_from_binary(&slice.encode_to_binary().unwrap()).unwrap();
+
+        assert_eq!(decoded_slice.sample_rate_hz, 16000);
+        assert_eq!(decoded_slice.number_samples, slice.number_samples);
+        assert_eq!(get_peaks(&decoded_slice), expected_peaks);
+
+        assert!(signature.slice(3.0, 2.0).is_err());
+        assert!(signature.slice(10.0, 12.0).is_err());
+    }
+
+    #[test]
+    fn halves_concatenate_to_the_whole() {
+        let signature = SignatureGenerator::make_signature_from_file(AUDIO_FILE_PATH).unwrap();
+
+        let duration_seconds = signature.number_samples as f32 / signature.sample_rate_hz as f32;
+
+        let halves = [
+            signature.slice(0.0, duration_seconds / 2.0).unwrap(),
+            signature.slice(duration_seconds / 2.0, duration_seconds).unwrap()
+        ];
+
+        let concatenation = DecodedSignature::concatenate(&halves).unwrap();
+
+        assert_eq!(concatenation.number_samples, signature.number_samples);
+        assert_eq!(get_peaks(&concatenation), get_peaks(&make_deduplicated(&signature)));
+
+        assert!(DecodedSignature::concatenate(&[]).is_err());
+    }
+
+    #[test]
+    fn overlapping_merges_are_deduplicated() {
+        let signature = SignatureGenerator::make_signature_from_file(AUDIO_FILE_PATH).unwrap();
+
+        let expected_peaks = get_peaks(&make_deduplicated(&signature));
+
+        // Merging a signature with itself adds no peak
+
+        let mut merged = signature.clone();
+        merged.merge(&signature, 0.0).unwrap();
+
+        assert_eq!(merged.number_samples, signature.number_samples);
+        assert_eq!(get_peaks(&merged), expected_peaks);
+
+        // Nor merging overlapping parts of it at the right offset
+
+        let mut merged = signature.slice(0.0, 4.0).unwrap();
+        merged.merge(&signature.slice(2.0, 6.0).unwrap(), 2.0).unwrap();
+
+        assert_eq!(merged.number_samples, signature.number_samples);
+        assert_eq!(get_peaks(&merged), expected_peaks);
+        merged.check_invariants().unwrap();
+
+        // No close peaks remain in a band
+
+        for frequency_peaks in merged.frequency_band_to_sound_peaks.values() {
+            for (index, frequency_peak) in frequency_peaks.iter().enumerate() {
+                assert!(!frequency_peaks[index + 1..].iter()
+                    .take_while(|next_peak| next_peak.fft_pass_number - frequency_peak.fft_pass_number <= DUPLICATE_PEAK_MAXIMUM_PASS_DISTANCE)
+                    .any(|next_peak| (next_peak.corrected_peak_frequency_bin as i32 - frequency_peak.corrected_peak_frequency_bin as i32).abs() <= DUPLICATE_PEAK_MAXIMUM_BIN_DISTANCE));
+            }
+        }
+
+        assert!(merged.merge(&signature, -1.0).is_err());
+        assert!(merged.merge(&signature, f32::NAN).is_err());
+    }
+
+    #[test]
+    fn unsorted_peaks_are_rejected() {
+        let mut signature = SignatureGenerator::make_signature_from_file(AUDIO_FILE_PATH).unwrap();
+
+        signature.check_invariants().unwrap();
+
+        let frequency_peaks = signature.frequency_band_to_sound_peaks.values_mut()
+            .find(|frequency_peaks| frequency_peaks.windows(2).any(|pair| pair[0].fft_pass_number < pair[1].fft_pass_number))
+            .unwrap();
+        frequency_peaks.reverse();
+
+        assert!(signature.check_invariants().is_err());
+        assert!(signature.encode_to_binary().is_err());
+    }
+
 }
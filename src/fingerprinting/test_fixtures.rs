@@ -0,0 +1,39 @@
+use crate::fingerprinting::resampler::{Resampler, to_i16_samples};
+use crate::fingerprinting::signature_format::DecodedSignature;
+
+/// Fixtures shared by the tests of the fingerprinting code (and of the code
+/// handling signatures), built around the 16 KHz mono test file of the
+/// Python version.
+
+pub const AUDIO_FILE_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/python-version/tests/stupeflip.wav");
+
+pub fn read_test_file() -> Vec<i16> {
+    let mut reader = hound::WavReader::open(AUDIO_FILE_PATH).unwrap();
+
+    assert_eq!(reader.spec().sample_rate, 16000);
+    assert_eq!(reader.spec().channels, 1);
+
+    reader.samples::<i16>().map(Result::unwrap).collect()
+}
+
+pub fn to_f32_samples(samples: &[i16]) -> Vec<f32> {
+    samples.iter().map(|sample| *sample as f32 / 32768.0).collect()
+}
+
+/// Render samples at another sample rate, in a single chunk.
+
+pub fn resample(samples: &[i16], input_sample_rate: u32, output_sample_rate: u32) -> Vec<i16> {
+    if input_sample_rate == output_sample_rate {
+        return samples.to_vec();
+    }
+
+    let mut resampler = Resampler::new(input_sample_rate, output_sample_rate);
+    let mut output_samples = resampler.process(&to_f32_samples(samples));
+    output_samples.extend(resampler.flush());
+
+    to_i16_samples(&output_samples)
+}
+
+pub fn count_peaks(signature: &DecodedSignature) -> usize {
+    signature.frequency_band_to_sound_peaks.values().map(Vec::len).sum()
+}
@@ -5,24 +5,24 @@ use gtk::ResponseType;
 use gettextrs::gettext;
 use gdk_pixbuf::Pixbuf;
 use std::error::Error;
-use std::sync::mpsc;
-use std::cell::RefCell;
+use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 use chrono::Local;
 use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
-use mpris_player::PlaybackStatus;
 
-use crate::core::microphone_thread::microphone_thread;
-use crate::core::processing_thread::processing_thread;
-use crate::core::http_thread::http_thread;
+use crate::core::capture_config::CaptureConfig;
+use crate::core::recognizer::Recognizer;
 use crate::core::thread_messages::{*, GUIMessage::*};
 
-use crate::utils::thread::spawn_big_thread;
 use crate::utils::pulseaudio_loopback::PulseaudioLoopback;
-use crate::utils::mpris_player::{get_player, update_song};
+use crate::utils::mpris_player::{get_player, update_song, MprisControl};
+use crate::utils::hooks::HookRunner;
+use crate::utils::dbus_service::{export_dbus_service, DBusControl};
+use crate::utils::fingerprint_image::render_png;
 
 use crate::gui::song_history_interface::SongHistoryInterface;
-use crate::gui::preferences::{PreferencesInterface, Preferences};
+use crate::utils::preferences::{PreferencesInterface, Preferences};
 use crate::utils::csv_song_history::SongHistoryRecord;
 use crate::utils::filesystem_operations::obtain_song_history_csv_path;
 
@@ -32,7 +32,7 @@ use std::os::windows::process::CommandExt;
 
 use crate::fingerprinting::signature_format::DecodedSignature;
 
-pub fn gui_main(recording: bool, input_file: Option<&str>, enable_mpris: bool) -> Result<(), Box<dyn Error>> {
+pub fn gui_main(recording: bool, input_file: Option<&str>, enable_mpris: bool, enable_dbus_service: bool) -> Result<(), Box<dyn Error>> {
     
     let application = gtk::Application::new(Some("com.github.marinm.songrec"),
         gio::ApplicationFlags::HANDLES_OPEN)
@@ -56,33 +56,40 @@ pub fn gui_main(recording: bool, input_file: Option<&str>, enable_mpris: bool) -
         // Load preferences file.
 
         // We use the GLib communication channel in order for
-        // communication with the main GTK+ loop and the standard
-        // Rust channels for other threads. An alternative would be
-        // to use async-based stuff with external crates.
+        // communication with the main GTK+ loop: it receives the
+        // events of the recognizer.
         
         let (gui_tx, gui_rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
-        let (microphone_tx, microphone_rx) = mpsc::channel();
-        let (processing_tx, processing_rx) = mpsc::channel();
-        let (http_tx, http_rx) = mpsc::channel();
-        
-        let microphone_tx_2 = microphone_tx.clone();
-        let microphone_tx_3 = microphone_tx.clone();
-        let microphone_tx_4 = microphone_tx.clone();
-        let microphone_tx_5 = microphone_tx.clone();
-        let processing_tx_2 = processing_tx.clone();
-        let processing_tx_4 = processing_tx.clone();
-        
-        spawn_big_thread(clone!(@strong gui_tx => move || { // microphone_rx, processing_tx
-            microphone_thread(microphone_rx, processing_tx_2, gui_tx);
-        }));
         
-        spawn_big_thread(clone!(@strong gui_tx => move || { // processing_rx, http_tx
-            processing_thread(processing_rx, http_tx, gui_tx);
-        }));
+        // The capture parameters come from the "[capture]" section of the
+        // preferences file, if any
         
-        spawn_big_thread(clone!(@strong gui_tx => move || { // http_rx
-            http_thread(http_rx, gui_tx, microphone_tx_3);
-        }));
+        let mut capture_config = old_preferences.capture.clone().unwrap_or_default();
+        
+        if let Err(error) = capture_config.validate() {
+            eprintln!("{} {}", gettext("When parsing the capture preferences:"), error);
+            capture_config = CaptureConfig::default();
+        }
+        
+        let preprocessing = old_preferences.preprocessing.clone().unwrap_or_default();
+        
+        // Only the level of the selected device is displayed, and the source
+        // of recognized songs only matters when listening to several devices
+        
+        let selected_source_id = capture_config.source_id.clone();
+        let several_sources = !capture_config.sources.is_empty();
+        
+        let recognizer = Recognizer::builder()
+            .capture_config(capture_config)
+            .preprocessing(preprocessing)
+            .event_sink(gui_tx.clone())
+            .build()
+            .unwrap();
+        
+        let recognizer_2 = recognizer.clone();
+        let recognizer_3 = recognizer.clone();
+        let recognizer_4 = recognizer.clone();
+        let recognizer_5 = recognizer.clone();
 
         // We create a callback for handling files to recognize opened
         // from the command line or through "xdg-open".
@@ -92,7 +99,7 @@ pub fn gui_main(recording: bool, input_file: Option<&str>, enable_mpris: bool) -
                 if let Some(file_path) = files[0].get_path() {
                     let file_path_string = file_path.into_os_string().into_string().unwrap();
                     
-                    processing_tx_4.send(ProcessingMessage::ProcessAudioFile(file_path_string)).unwrap();
+                    recognizer_2.recognize_file(&file_path_string);
                 }
             }
         });
@@ -259,15 +266,38 @@ pub fn gui_main(recording: bool, input_file: Option<&str>, enable_mpris: bool) -
 
         let youtube_button: gtk::Button = builder.get_object("youtube_button").unwrap();
         let lure_button: gtk::Button = builder.get_object("lure_button").unwrap();
+        let fingerprint_button: gtk::Button = builder.get_object("fingerprint_button").unwrap();
         
         let wipe_history_button: gtk::Button = builder.get_object("wipe_history_button").unwrap();
         let export_csv_button: gtk::Button = builder.get_object("export_csv_button").unwrap();
 
-        let mpris_player = if enable_mpris { get_player() } else { None };
+        let mut hook_runner = HookRunner::new(old_preferences.hooks.clone().unwrap_or_default());
+
+        // Play/stop requests from MPRIS clients are received from the MPRIS
+        // thread, and applied below as if the corresponding button was clicked
+        
+        let (mpris_control_tx, mpris_control_rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+
+        let mpris_player = if enable_mpris {
+            get_player(move |control| { mpris_control_tx.send(control).unwrap(); })
+        } else { None };
         if enable_mpris && mpris_player.is_none() {
             println!("{}", gettext("Unable to enable MPRIS support"))
         }
         
+        // Requests from the D-Bus control service are applied the same way
+        
+        let (dbus_control_tx, dbus_control_rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+
+        let dbus_service = if enable_dbus_service {
+            export_dbus_service(application, move |control| { dbus_control_tx.send(control).unwrap(); })
+        } else { None };
+        let dbus_service_2 = dbus_service.clone();
+        
+        if let Some(dbus_service) = &dbus_service {
+            dbus_service.set_history(song_history_interface.get_chronological_records().clone());
+        }
+        
         // Thread-local variables to be passed across callbacks.
         
         let youtube_query: Rc<RefCell<Option<String>>> = Rc::new(RefCell::new(None));
@@ -275,6 +305,7 @@ pub fn gui_main(recording: bool, input_file: Option<&str>, enable_mpris: bool) -
         
         let current_signature: Rc<RefCell<Option<DecodedSignature>>> = Rc::new(RefCell::new(None));
         let current_signature_2 = current_signature.clone();
+        let current_signature_3 = current_signature.clone();
         
         // Remember about the saved last-used microphone device, if any
 
@@ -298,8 +329,27 @@ pub fn gui_main(recording: bool, input_file: Option<&str>, enable_mpris: bool) -
         
         let current_volume_hbox: gtk::Box = builder.get_object("current_volume_hbox").unwrap();
         let current_volume_bar: gtk::ProgressBar = builder.get_object("current_volume_bar").unwrap();
+        let current_level_label: gtk::Label = builder.get_object("current_level_label").unwrap();
+        
+        // Whether the combo box is being refilled after devices were plugged
+        // or unplugged, in which case the selection shouldn't be saved nor
+        // restart listening
+        
+        let refreshing_devices_list: Rc<Cell<bool>> = Rc::new(Cell::new(false));
+        let mut devices_listed_once = false;
+        
+        // The last song recognized from each capture source (None for files)
+        
+        let mut last_songs: HashMap<Option<String>, String> = HashMap::new();
         
-        combo_box.connect_changed(clone!(@strong microphone_stop_button, @strong combo_box => move |_| {
+        let history_source_column: gtk::TreeViewColumn = builder.get_object("history_source_column").unwrap();
+        history_source_column.set_visible(several_sources);
+        
+        combo_box.connect_changed(clone!(@strong microphone_stop_button, @strong combo_box, @strong refreshing_devices_list => move |_| {
+            
+            if refreshing_devices_list.get() {
+                return;
+            }
             
             if let Some(device_name_str) = combo_box.get_active_id() {
 
@@ -311,13 +361,16 @@ pub fn gui_main(recording: bool, input_file: Option<&str>, enable_mpris: bool) -
                 new_preferences.current_device_name = Some(device_name_str.to_string());
                 preferences_interface.update(new_preferences);
                 
+                if let Some(dbus_service) = &dbus_service_2 {
+                    dbus_service.set_current_device(&device_name_str);
+                }
+                
                 if microphone_stop_button.is_visible() {
                     
                     // Re-launch the microphone recording with the new selected
                     // device
                     
-                    microphone_tx_4.send(MicrophoneMessage::MicrophoneRecordStop).unwrap();
-                    microphone_tx_4.send(MicrophoneMessage::MicrophoneRecordStart(device_name_str.to_string())).unwrap();
+                    recognizer_3.restart_listening(&device_name_str);
                     
                 }
             }
@@ -326,9 +379,7 @@ pub fn gui_main(recording: bool, input_file: Option<&str>, enable_mpris: bool) -
         
         // Handle various controls
         
-        let processing_tx_3 = processing_tx.clone();
-
-        recognize_file_button.connect_clicked(clone!(@strong window, @strong spinner, @strong recognize_file_button => move |_| {
+        recognize_file_button.connect_clicked(clone!(@strong window, @strong spinner, @strong recognize_file_button, @strong recognizer => move |_| {
             
             let file_chooser = gtk::FileChooserNative::new(
                 Some(&gettext("Select a file to recognize")),
@@ -346,15 +397,15 @@ pub fn gui_main(recording: bool, input_file: Option<&str>, enable_mpris: bool) -
                 let input_file_path = file_chooser.get_filename().expect(&gettext("Couldn't get filename"));
                 let input_file_string = input_file_path.to_str().unwrap().to_string();
                 
-                processing_tx_3.send(ProcessingMessage::ProcessAudioFile(input_file_string)).unwrap();
+                recognizer.recognize_file(&input_file_string);
             };
         
         }));
         
-        microphone_button.connect_clicked(clone!(@strong microphone_button, @strong microphone_stop_button, @strong current_volume_hbox, @strong combo_box => move |_| {
+        microphone_button.connect_clicked(clone!(@strong microphone_button, @strong microphone_stop_button, @strong current_volume_hbox, @strong combo_box, @strong recognizer => move |_| {
             
             if let Some(device_name) = combo_box.get_active_id() {
-                microphone_tx.send(MicrophoneMessage::MicrophoneRecordStart(device_name.to_owned())).unwrap();
+                recognizer.start_listening(&device_name);
                 
                 microphone_stop_button.show();
                 current_volume_hbox.show();
@@ -363,9 +414,9 @@ pub fn gui_main(recording: bool, input_file: Option<&str>, enable_mpris: bool) -
 
         }));
         
-        microphone_stop_button.connect_clicked(clone!(@strong microphone_button, @strong microphone_stop_button, @strong current_volume_hbox => move |_| {
+        microphone_stop_button.connect_clicked(clone!(@strong microphone_button, @strong microphone_stop_button, @strong current_volume_hbox, @strong recognizer => move |_| {
             
-            microphone_tx_2.send(MicrophoneMessage::MicrophoneRecordStop).unwrap();
+            recognizer.stop_listening();
             
             microphone_stop_button.hide();
             current_volume_hbox.hide();
@@ -373,8 +424,59 @@ pub fn gui_main(recording: bool, input_file: Option<&str>, enable_mpris: bool) -
             
         }));
         
-        recognize_from_my_speakers_checkbox.connect_toggled(clone!(@strong recognize_from_my_speakers_checkbox => move |_| {
-            PulseaudioLoopback::set_whether_audio_source_is_monitor(recognize_from_my_speakers_checkbox.get_active());
+        mpris_control_rx.attach(None, clone!(@strong microphone_button, @strong microphone_stop_button => move |control| {
+            
+            match control {
+                MprisControl::StartListening => {
+                    if microphone_button.is_visible() {
+                        microphone_button.clicked();
+                    }
+                },
+                MprisControl::StopListening => {
+                    if microphone_stop_button.is_visible() {
+                        microphone_stop_button.clicked();
+                    }
+                }
+            };
+            
+            Continue(true)
+        }));
+        
+        dbus_control_rx.attach(None, clone!(@strong microphone_button, @strong microphone_stop_button, @strong combo_box, @strong recognize_file_button, @strong spinner => move |control| {
+            
+            match control {
+                DBusControl::StartListening(device_name) => {
+                    if microphone_button.is_visible() {
+                        combo_box.set_active_id(Some(&device_name));
+                        microphone_button.clicked();
+                    }
+                },
+                DBusControl::StopListening => {
+                    if microphone_stop_button.is_visible() {
+                        microphone_stop_button.clicked();
+                    }
+                },
+                DBusControl::SelectDevice(device_name, _) => {
+                    // Changing the active device restarts listening when
+                    // needed, see above
+                    
+                    combo_box.set_active_id(Some(&device_name));
+                },
+                DBusControl::RecognizeFile(file_path) => {
+                    recognize_file_button.hide();
+                    spinner.show();
+                    
+                    recognizer_4.recognize_file(&file_path);
+                }
+            };
+            
+            Continue(true)
+        }));
+        
+        recognize_from_my_speakers_checkbox.connect_toggled(clone!(@strong recognize_from_my_speakers_checkbox, @strong gui_tx => move |_| {
+            if let Err(error) = PulseaudioLoopback::set_whether_audio_source_is_monitor(recognize_from_my_speakers_checkbox.get_active()) {
+                gui_tx.send(GUIMessage::ErrorMessage(error.to_string())).unwrap();
+            }
         }));
         
         youtube_button.connect_clicked(move |_| {
@@ -395,7 +497,9 @@ pub fn gui_main(recording: bool, input_file: Option<&str>, enable_mpris: bool) -
             
             let current_signature_borrow = current_signature_2.borrow();
     
-            let mixed_source = rodio::buffer::SamplesBuffer::new::<Vec<i16>>(1, 16000, current_signature_borrow.as_ref().unwrap().to_lure().unwrap());
+            let current_signature = current_signature_borrow.as_ref().unwrap();
+    
+            let mixed_source = rodio::buffer::SamplesBuffer::new::<Vec<i16>>(1, current_signature.sample_rate_hz, current_signature.to_lure().unwrap());
     
             std::thread::Builder::new().spawn(move || {
                         
@@ -411,6 +515,48 @@ pub fn gui_main(recording: bool, input_file: Option<&str>, enable_mpris: bool) -
         
         });
         
+        fingerprint_button.connect_clicked(clone!(@strong window => move |_| {
+            
+            let current_signature_borrow = current_signature_3.borrow();
+    
+            let current_signature = current_signature_borrow.as_ref().unwrap();
+            
+            let stream = gio::MemoryInputStream::from_bytes(&glib::Bytes::from(&render_png(current_signature, None)));
+            
+            match Pixbuf::from_stream::<_, gio::Cancellable>(&stream, None) {
+                Ok(pixbuf) => {
+                    let peak_count: usize = current_signature.frequency_band_to_sound_peaks.values().map(Vec::len).sum();
+                    
+                    let description = gtk::Label::new(Some(&format!("{} {} {:.1} {}",
+                        peak_count,
+                        gettext("frequency peaks over"),
+                        current_signature.number_samples as f32 / current_signature.sample_rate_hz as f32,
+                        gettext("seconds (from 250 to 520 Hz in red, to 1450 Hz in green, to 3500 Hz in blue and to 5500 Hz in orange)"))));
+                    
+                    description.set_line_wrap(true);
+                    
+                    let scrolled_window = gtk::ScrolledWindow::new::<gtk::Adjustment, gtk::Adjustment>(None, None);
+                    scrolled_window.add(&gtk::Image::from_pixbuf(Some(&pixbuf)));
+                    
+                    let vertical_box = gtk::Box::new(gtk::Orientation::Vertical, 10);
+                    vertical_box.set_border_width(10);
+                    vertical_box.pack_start(&description, false, true, 0);
+                    vertical_box.pack_start(&scrolled_window, true, true, 0);
+                    
+                    let fingerprint_window = gtk::Window::new(gtk::WindowType::Toplevel);
+                    fingerprint_window.set_title(&gettext("Fingerprint"));
+                    fingerprint_window.set_transient_for(Some(&window));
+                    fingerprint_window.set_default_size(900, 600);
+                    fingerprint_window.add(&vertical_box);
+                    fingerprint_window.show_all();
+                },
+                Err(error) => {
+                    eprintln!("{} {}", gettext("Unable to display the fingerprint:"), error);
+                }
+            };
+            
+        }));
+        
         wipe_history_button.connect_clicked(clone!(@strong gui_tx => move |_| {
 
             gui_tx.send(GUIMessage::WipeSongHistory).unwrap();
@@ -442,7 +588,7 @@ pub fn gui_main(recording: bool, input_file: Option<&str>, enable_mpris: bool) -
         gui_rx.attach(None, clone!(@strong application, @strong window, @strong results_frame, @strong current_volume_hbox, @strong spinner, @strong recognize_file_button, @strong network_unreachable, @strong microphone_stop_button, @strong recognize_from_my_speakers_checkbox, @strong notification_enable_checkbox => move |gui_message| {
             
             match gui_message {
-                ErrorMessage(_) | NetworkStatus(_) | SongRecognized(_) => {
+                ErrorMessage(_) | NoMatch | NetworkStatus(_) | SongRecognized(_) => {
                     recognize_file_button.show();
                     spinner.hide();
                 },
@@ -451,32 +597,81 @@ pub fn gui_main(recording: bool, input_file: Option<&str>, enable_mpris: bool) -
 
             match gui_message {
                 ErrorMessage(string) => {
-                    if !(string == gettext("No match for this song") && microphone_stop_button.is_visible()) {
+                    if let Some(dbus_service) = &dbus_service {
+                        dbus_service.error(&string);
+                    }
+                    let dialog = gtk::MessageDialog::new(Some(&window),
+                        gtk::DialogFlags::MODAL, gtk::MessageType::Error, gtk::ButtonsType::Ok, &string);
+                    dialog.connect_response(|dialog, _| dialog.close());
+                    dialog.show_all();
+                },
+                NoMatch => {
+                    let string = gettext("No match for this song");
+                    if let Some(hook_runner) = &mut hook_runner {
+                        hook_runner.no_match();
+                    }
+                    if let Some(dbus_service) = &dbus_service {
+                        dbus_service.error(&string);
+                    }
+                    // Not finding a match while listening is expected
+                    if !microphone_stop_button.is_visible() {
                         let dialog = gtk::MessageDialog::new(Some(&window),
                             gtk::DialogFlags::MODAL, gtk::MessageType::Error, gtk::ButtonsType::Ok, &string);
                         dialog.connect_response(|dialog, _| dialog.close());
                         dialog.show_all();
                     }
                 },
+                MicrophoneError(error) => {
+                    if let Some(dbus_service) = &dbus_service {
+                        dbus_service.error(&error.to_string());
+                    }
+                    
+                    let dialog = gtk::MessageDialog::new(Some(&window),
+                        gtk::DialogFlags::MODAL, gtk::MessageType::Error, gtk::ButtonsType::Ok, &error.to_string());
+                    dialog.connect_response(|dialog, _| dialog.close());
+                    dialog.show_all();
+                },
                 NetworkStatus(network_is_reachable) => {
+                    if let Some(hook_runner) = &mut hook_runner {
+                        hook_runner.network_status(network_is_reachable);
+                    }
+
                     if network_is_reachable {
                         network_unreachable.hide();
                     }
                     else {
                         network_unreachable.show_all();
                     }
-                    let mpris_status = if network_is_reachable { PlaybackStatus::Playing } else { PlaybackStatus::Paused };
-
-                    mpris_player.as_ref().map(|p| p.set_playback_status(mpris_status));
+                    if let Some(mpris_player) = &mpris_player {
+                        mpris_player.set_network_reachable(network_is_reachable);
+                    }
                 }
                 DevicesList(device_names) => {
+                    if let Some(dbus_service) = &dbus_service {
+                        dbus_service.set_devices(&device_names);
+                    }
+                    
+                    // When the list is refreshed, select back the device chosen
+                    // by the user if it is (still) there, without touching to
+                    // listening: the microphone thread takes care of falling
+                    // back to the default device and of switching back
+                    
+                    let preferred_device_name = match devices_listed_once {
+                        false => old_device_name.clone(),
+                        true => PreferencesInterface::new().preferences.current_device_name
+                    };
+                    
+                    refreshing_devices_list.set(devices_listed_once);
+                    
+                    combo_box_model.clear();
+                    
                     let mut old_device_index = 0;
                     let mut current_index = 0;
                     
                     for device_name in device_names.iter() {
                         combo_box_model.set(&combo_box_model.append(), &[0], &[device_name]);
                         
-                        if old_device_name == Some(device_name.to_string()) {
+                        if preferred_device_name == Some(device_name.to_string()) {
                             old_device_index = current_index;
                         }
                         current_index += 1;
@@ -484,13 +679,20 @@ pub fn gui_main(recording: bool, input_file: Option<&str>, enable_mpris: bool) -
                     
                     combo_box.set_active(Some(old_device_index));
                     
+                    refreshing_devices_list.set(false);
+                    
+                    if devices_listed_once {
+                        return Continue(true);
+                    }
+                    devices_listed_once = true;
+                    
                     // Should we start recording yet? (will depend of the possible
                     // command line flags of the application)
 
                     if recording {
                     
                         if let Some(device_name) = combo_box.get_active_id() {
-                            microphone_tx_5.send(MicrophoneMessage::MicrophoneRecordStart(device_name.to_owned())).unwrap();
+                            recognizer_5.start_listening(&device_name);
                             
                             microphone_stop_button.show();
                             current_volume_hbox.show();
@@ -500,12 +702,46 @@ pub fn gui_main(recording: bool, input_file: Option<&str>, enable_mpris: bool) -
                 },
                 WipeSongHistory => {
                     song_history_interface.wipe_and_save();
+                    if let Some(dbus_service) = &dbus_service {
+                        dbus_service.set_history(vec![]);
+                    }
                 },
-                MicrophoneVolumePercent(percent) => {
-                    current_volume_bar.set_fraction((percent / 100.0) as f64);
+                MicrophoneInputLevel(source_id, level) => {
+                    if source_id != selected_source_id {
+                        return Continue(true);
+                    }
+                    
+                    current_volume_bar.set_fraction(level.bar_fraction());
+                    
+                    // Clipping is shown for as long as the peak is held
+                    
+                    let level_text = if level.peak_hold_dbfs >= -0.1 {
+                        gettext("Clipping!")
+                    }
+                    else if level.too_quiet {
+                        gettext("Too quiet to recognize")
+                    }
+                    else {
+                        format!("{} {:.0} dBFS", gettext("Peak:"), level.peak_hold_dbfs)
+                    };
+                    
+                    if current_level_label.get_text().as_str() != level_text {
+                        current_level_label.set_text(&level_text);
+                    }
                 },
                 MicrophoneRecording => {
                     
+                    microphone_stop_button.show();
+                    current_volume_hbox.show();
+                    microphone_button.hide();
+                    
+                    if let Some(mpris_player) = &mpris_player {
+                        mpris_player.set_listening(true);
+                    }
+                    if let Some(dbus_service) = &dbus_service {
+                        dbus_service.set_listening(true);
+                    }
+                    
                     // Initally show the "Recognize from my speakers instead
                     // of microphone" checkbox if PulseAudio seems to be
                     // available, and we can see (supposedly) ourselves through
@@ -525,14 +761,40 @@ pub fn gui_main(recording: bool, input_file: Option<&str>, enable_mpris: bool) -
                         }
                     }
                 },
+                MicrophoneStopped => {
+                    
+                    // Listening may also have stopped because the device
+                    // couldn't be opened
+                    
+                    microphone_stop_button.hide();
+                    current_volume_hbox.hide();
+                    microphone_button.show();
+                    
+                    if let Some(mpris_player) = &mpris_player {
+                        mpris_player.set_listening(false);
+                    }
+                    if let Some(dbus_service) = &dbus_service {
+                        dbus_service.set_listening(false);
+                    }
+                },
                 SongRecognized(message) => {
                     let mut youtube_query_borrow = youtube_query.borrow_mut();
 
                     let song_name = Some(format!("{} - {}", message.artist_name, message.song_name));
         
-                    if *youtube_query_borrow != song_name { // If this is already the last recognized song, don't update the display (if for example we recognized a lure we played, it would update the proposed lure to a lesser quality)
+                    if last_songs.get(&message.source_id) != song_name.as_ref() { // If this is already the last song recognized from this source, don't update the display (if for example we recognized a lure we played, it would update the proposed lure to a lesser quality)
+                        
+                        last_songs.insert(message.source_id.clone(), song_name.clone().unwrap());
 
-                        mpris_player.as_ref().map(|p| update_song(p, &message));
+                        if let Some(mpris_player) = &mpris_player {
+                            update_song(mpris_player, &message);
+                        }
+                        if let Some(hook_runner) = &mut hook_runner {
+                            hook_runner.song_recognized(&message);
+                        }
+                        if let Some(dbus_service) = &dbus_service {
+                            dbus_service.song_recognized(&message);
+                        }
 
                         let notification = gio::Notification::new(&gettext("Song recognized"));
                         notification.set_body(Some(song_name.as_ref().unwrap()));
@@ -544,6 +806,7 @@ pub fn gui_main(recording: bool, input_file: Option<&str>, enable_mpris: bool) -
                             track_key: message.track_key,
                             release_year: message.release_year.as_ref().unwrap_or(&"".to_string()).to_string(),
                             genre: message.genre.as_ref().unwrap_or(&"".to_string()).to_string(),
+                            source: message.source_id.clone().unwrap_or_default()
                         });
 
                         recognized_song_name.set_markup(&format!("<b>{}</b>", glib::markup_escape_text(song_name.as_ref().unwrap())));
@@ -45,7 +45,7 @@ impl SongHistoryInterface {
                     
                     let record: SongHistoryRecord = result?;
 
-                    self.gtk_list_store.set(&self.gtk_list_store.insert(0), &[0, 1, 2], &[&record.song_name, &record.album, &record.recognition_date]);
+                    self.gtk_list_store.set(&self.gtk_list_store.insert(0), &[0, 1, 2, 3], &[&record.song_name, &record.album, &record.recognition_date, &record.source]);
 
                     self.chronological_records.push(record);
                                         
@@ -56,6 +56,10 @@ impl SongHistoryInterface {
         Ok(())
     }
     
+    pub fn get_chronological_records(self: &Self) -> &Vec<SongHistoryRecord> {
+        &self.chronological_records
+    }
+    
     pub fn wipe_and_save(self: &mut Self) {
         self.chronological_records.clear();
         
@@ -67,7 +71,7 @@ impl SongHistoryInterface {
     }
     
     pub fn add_column_and_save(self: &mut Self, record: SongHistoryRecord) {
-        self.gtk_list_store.set(&self.gtk_list_store.insert(0), &[0, 1, 2], &[&record.song_name, &record.album, &record.recognition_date]);
+        self.gtk_list_store.set(&self.gtk_list_store.insert(0), &[0, 1, 2, 3], &[&record.song_name, &record.album, &record.recognition_date, &record.source]);
 
         self.chronological_records.push(record);
         
@@ -1,37 +1,56 @@
 pub mod fingerprinting {
     pub mod algorithm;
     pub mod communication;
+    pub mod comparison;
     mod hanning;
+    pub mod lure;
+    pub mod preprocessing;
+    pub mod quality;
+    pub mod resampler;
     pub mod signature_format;
+    #[cfg(test)]
+    pub mod test_fixtures;
     mod user_agent;
 }
 
 pub mod core {
+    pub mod capture_config;
+    pub mod event_bus;
     pub mod http_thread;
+    pub mod level_meter;
     pub mod microphone_thread;
     pub mod processing_thread;
+    pub mod recognizer;
     pub mod thread_messages;
 }
 
 #[cfg(feature = "gui")]
 pub mod gui {
     pub mod main_window;
-    mod preferences;
     mod song_history_interface;
 }
 
 pub mod cli {
     pub mod cli_main;
+    pub mod http_api;
 }
 
 pub mod utils {
+    pub mod application_capture;
+    pub mod audio_decoding;
+    pub mod audio_recorder;
     pub mod csv_song_history;
+    pub mod dbus_service;
     pub mod ffmpeg_wrapper;
-    #[cfg(feature = "gui")]
+    pub mod fingerprint_image;
     pub mod filesystem_operations;
+    pub mod hooks;
     pub mod internationalization;
     pub mod mpris_player;
-    #[cfg(feature = "gui")]
+    #[cfg(feature = "opus")]
+    pub mod opus_decoder;
+    pub mod preferences;
     pub mod pulseaudio_loopback;
+    pub mod remote_audio;
     pub mod thread;
 }
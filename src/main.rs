@@ -4,22 +4,136 @@
 use songrec::fingerprinting::algorithm::SignatureGenerator;
 use songrec::fingerprinting::signature_format::DecodedSignature;
 use songrec::fingerprinting::communication::recognize_song_from_signature;
+use songrec::fingerprinting::comparison::SignatureComparison;
+use songrec::fingerprinting::lure::{Lure, LureOptions};
+use songrec::fingerprinting::quality::SignatureQuality;
 
 use songrec::utils::internationalization::setup_internationalization;
 #[cfg(feature = "gui")]
 use songrec::gui::main_window::gui_main;
 use songrec::cli::cli_main::{cli_main, CLIParameters, CLIOutputType};
+use songrec::utils::hooks::HooksConfig;
+use songrec::core::capture_config::{CaptureConfig, CaptureSourceConfig, CaptureSampleFormat, DownmixStrategy};
+use songrec::utils::audio_recorder::RecordingFormat;
+use songrec::fingerprinting::preprocessing::{PreprocessingConfig, SourcePreprocessing};
+use songrec::utils::application_capture::APPLICATION_DEVICE_PREFIX;
+use songrec::utils::fingerprint_image::save_fingerprint_image;
+use songrec::utils::audio_decoding::print_format_support;
+use songrec::utils::ffmpeg_wrapper::set_ffmpeg_path;
+use songrec::utils::remote_audio::{DownloadLimits, download_audio, is_url};
+use songrec::utils::preferences::PreferencesInterface;
 
 use std::error::Error;
 use gettextrs::gettext;
 use clap::{App, Arg};
 
+// Options for the capture parameters of the microphone thread and for the
+// preprocessing of audio, shared by the subcommands listening the microphone
+
+macro_rules! capture_arguments {
+    ($app:expr) => {
+    $app
+        .arg(
+            Arg::with_name("sample-rate")
+                .long("sample-rate")
+                .takes_value(true)
+                .value_name("HZ")
+                .help(gettext("Preferred sample rate to open the audio device with").as_str())
+        )
+        .arg(
+            Arg::with_name("channels")
+                .long("channels")
+                .takes_value(true)
+                .value_name("COUNT")
+                .help(gettext("Preferred channel count to open the audio device with").as_str())
+        )
+        .arg(
+            Arg::with_name("sample-format")
+                .long("sample-format")
+                .takes_value(true)
+                .possible_values(&["f32", "i16", "u16"])
+                .help(gettext("Preferred sample format to open the audio device with").as_str())
+        )
+        .arg(
+            Arg::with_name("downmix")
+                .long("downmix")
+                .takes_value(true)
+                .possible_values(&["average", "left", "right"])
+                .help(gettext("How to convert multi-channel audio to mono (default: average)").as_str())
+        )
+        .arg(
+            Arg::with_name("buffer-seconds")
+                .long("buffer-seconds")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help(gettext("Length of the recording sent for recognition (default: 12)").as_str())
+        )
+        .arg(
+            Arg::with_name("recognition-interval")
+                .long("recognition-interval")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help(gettext("Delay between two recognition attempts (default: 4)").as_str())
+        )
+        .arg(
+            Arg::with_name("record-dir")
+                .long("record-dir")
+                .takes_value(true)
+                .value_name("DIRECTORY")
+                .help(gettext("Save the audio sent for each recognition attempt, along with the result, to the given directory").as_str())
+        )
+        .arg(
+            Arg::with_name("record-format")
+                .long("record-format")
+                .takes_value(true)
+                .possible_values(&["wav", "flac"])
+                .help(gettext("Format of the saved audio (default: wav, FLAC requires FFMpeg)").as_str())
+        )
+        .arg(
+            Arg::with_name("preprocess")
+                .long("preprocess")
+                .help(gettext("Reduce noise and normalize the audio before recognizing it (may help with noisy microphones)").as_str())
+        )
+    };
+}
+
+// Limits for the subcommands recognizing a file, which may also be given as
+// an HTTP(S) URL
+
+macro_rules! download_arguments {
+    ($app:expr) => {
+    $app
+        .arg(
+            Arg::with_name("max-bytes")
+                .long("max-bytes")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help(gettext("When recognizing an URL, maximum number of bytes to download (default: 16 MiB)").as_str())
+        )
+        .arg(
+            Arg::with_name("max-seconds")
+                .long("max-seconds")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help(gettext("When recognizing an URL, maximum duration of audio to download from HLS playlists, or of downloading otherwise (default: 20)").as_str())
+        )
+    };
+}
+
 macro_rules! base_app {
     () => {
     App::new("SongRec")
         .version("0.3.2")
         .about(gettext("An open-source Shazam client for Linux, written in Rust.").as_str())
-        .subcommand(
+        .arg(
+            Arg::with_name("ffmpeg-path")
+                .long("ffmpeg-path")
+                .takes_value(true)
+                .value_name("PATH")
+                .global(true)
+                .help(gettext("The FFMpeg binary to use for decoding and encoding the formats which are not supported natively (looked up in the PATH by default)").as_str())
+        )
+        .subcommand(capture_arguments!(
             App::new("listen")
                 .about(gettext("Run as a command-line program listening the microphone and printing recognized songs to stdout, exposing current song info via MPRIS").as_str())
                 .arg(
@@ -29,6 +143,14 @@ macro_rules! base_app {
                         .takes_value(true)
                         .help(gettext("Specify the audio device to use").as_str())
                 )
+                .arg(
+                    Arg::with_name("capture-app")
+                        .long("capture-app")
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .conflicts_with("audio-device")
+                        .help(gettext("Recognize the audio played by the given application only (through PulseAudio or PipeWire)").as_str())
+                )
                 .arg(
                     Arg::with_name("json")
                         .short("j")
@@ -47,8 +169,29 @@ macro_rules! base_app {
                         .long("disable-mpris")
                         .help(gettext("Disable MPRIS support").as_str())
                 )
-        )
-        .subcommand(
+                .arg(
+                    Arg::with_name("disable-dbus-service")
+                        .long("disable-dbus-service")
+                        .help(gettext("Disable the D-Bus control service").as_str())
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .long("source")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("ID=DEVICE")
+                        .help(gettext("Also listen to the given audio device, tagging the songs recognized from it with the given identifier (may be repeated, the first one replaces the default device when no device is specified)").as_str())
+                )
+                .arg(
+                    Arg::with_name("http")
+                        .long("http")
+                        .takes_value(true)
+                        .value_name("ADDRESS")
+                        .help(gettext("Expose the current song, history and controls through a local HTTP API (for example: 127.0.0.1:8080)").as_str())
+                )
+        ))
+        .subcommand(download_arguments!(capture_arguments!(
             App::new("recognize")
                 .about(gettext("Recognize one song from a sound file or microphone and print its info.").as_str())
                 .arg(
@@ -58,6 +201,14 @@ macro_rules! base_app {
                         .takes_value(true)
                         .help(gettext("Specify the audio device to use").as_str())
                 )
+                .arg(
+                    Arg::with_name("capture-app")
+                        .long("capture-app")
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .conflicts_with("audio-device")
+                        .help(gettext("Recognize the audio played by the given application only (through PulseAudio or PipeWire)").as_str())
+                )
                 .arg(
                     Arg::with_name("json")
                         .short("j")
@@ -74,18 +225,18 @@ macro_rules! base_app {
                 .arg(
                     Arg::with_name("input_file")
                         .required(false)
-                        .help(gettext("Recognize a file instead of using mic input").as_str())
+                        .help(gettext("Recognize an audio or video file, or an HTTP(S) URL (of a file, stream, or M3U or HLS playlist), instead of using mic input").as_str())
                 )
-        )
-        .subcommand(
+        )))
+        .subcommand(download_arguments!(
             App::new("audio-file-to-recognized-song")
                 .about(gettext("Generate a Shazam fingerprint from a sound file, perform song recognition towards Shazam's servers and print obtained information to the standard output.").as_str())
                 .arg(
                     Arg::with_name("input_file")
                         .required(true)
-                        .help(gettext("The audio file to recognize.").as_str())
+                        .help(gettext("The audio or video file, or HTTP(S) URL, to recognize.").as_str())
                 )
-        )
+        ))
         .subcommand(
             App::new("microphone-to-recognized-song")
                 .about(gettext("Recognize a currently playing song using the microphone and print obtained information to the standard output").as_str())
@@ -105,6 +256,13 @@ macro_rules! base_app {
                         .required(true)
                         .help(gettext("The .WAV or .MP3 file to generate an audio fingerprint for.").as_str())
                 )
+                .arg(
+                    Arg::with_name("sample-rate")
+                        .long("sample-rate")
+                        .takes_value(true)
+                        .possible_values(&["8000", "11025", "16000", "32000", "44100", "48000"])
+                        .help(gettext("Sample rate of the generated fingerprint, in Hz (16000 by default)").as_str())
+                )
         )
         .subcommand(
             App::new("fingerprint-to-recognized-song")
@@ -126,9 +284,76 @@ macro_rules! base_app {
                 .arg(
                     Arg::with_name("output_file")
                         .required(false)
-                        .help(gettext("File path of the .WAV file to write tones to, or nothing to play back the sound instantly.").as_str())
+                        .help(gettext("File path of the .WAV, .FLAC or .OGG file to write tones to (the two latter require FFMpeg), or nothing to play back the sound instantly.").as_str())
+                )
+                .arg(
+                    Arg::with_name("sample-rate")
+                        .long("sample-rate")
+                        .takes_value(true)
+                        .value_name("HZ")
+                        .help(gettext("Sample rate of the produced sound, in Hz (the sample rate of the fingerprint by default)").as_str())
+                )
+                .arg(
+                    Arg::with_name("harmonics")
+                        .long("harmonics")
+                        .help(gettext("Add octaves above and below each tone, which sounds less harsh but is recognized less reliably").as_str())
+                )
+        )
+        .subcommand(
+            App::new("audio-file-to-spectrogram")
+                .about(gettext("Render the spectrogram of a sound file, as computed when generating its fingerprint, along with the frequency peaks of the fingerprint, to a .PNG or .SVG image.").as_str())
+                .arg(
+                    Arg::with_name("input_file")
+                        .required(true)
+                        .help(gettext("The .WAV or .MP3 file to render the spectrogram of.").as_str())
+                )
+                .arg(
+                    Arg::with_name("output_file")
+                        .required(true)
+                        .help(gettext("File path of the .PNG or .SVG image to write.").as_str())
                 )
         )
+        .subcommand(
+            App::new("fingerprint-to-image")
+                .about(gettext("Render the frequency peaks of a data-URI Shazam fingerprint to a .PNG or .SVG image.").as_str())
+                .arg(
+                    Arg::with_name("fingerprint")
+                        .required(true)
+                        .help(gettext("The data-URI Shazam fingerprint to render.").as_str())
+                )
+                .arg(
+                    Arg::with_name("output_file")
+                        .required(true)
+                        .help(gettext("File path of the .PNG or .SVG image to write.").as_str())
+                )
+        )
+        .subcommand(
+            App::new("fingerprint-info")
+                .about(gettext("Print statistics about a fingerprint (peaks per frequency band and per second, coverage of its duration and frequency range, distribution of the magnitude of its peaks), and whether it is likely to be recognized by Shazam.").as_str())
+                .arg(
+                    Arg::with_name("fingerprint")
+                        .required(true)
+                        .help(gettext("The data-URI Shazam fingerprint, or .WAV or .MP3 file to fingerprint.").as_str())
+                )
+        )
+        .subcommand(
+            App::new("compare-fingerprints")
+                .about(gettext("Tell whether two fingerprints were generated from the same audio, printing their similarity score (between 0 and 1), the number of matching frequency peaks, and the time offset and skew between them.").as_str())
+                .arg(
+                    Arg::with_name("first")
+                        .required(true)
+                        .help(gettext("The first data-URI Shazam fingerprint, or .WAV or .MP3 file to fingerprint.").as_str())
+                )
+                .arg(
+                    Arg::with_name("second")
+                        .required(true)
+                        .help(gettext("The second data-URI Shazam fingerprint, or .WAV or .MP3 file to fingerprint.").as_str())
+                )
+        )
+        .subcommand(
+            App::new("formats")
+                .about(gettext("List the audio formats that can be recognized from files, and whether they are decoded natively or through FFMpeg.").as_str())
+        )
     };
 }
 
@@ -149,6 +374,11 @@ macro_rules! gui_app {
                         .long("disable-mpris")
                         .help(gettext("Disable MPRIS support").as_str())
                 )
+                .arg(
+                    Arg::with_name("disable-dbus-service")
+                        .long("disable-dbus-service")
+                        .help(gettext("Disable the D-Bus control service").as_str())
+                )
         )
         .subcommand(
             App::new("gui-norecording")
@@ -163,6 +393,11 @@ macro_rules! gui_app {
                         .long("disable-mpris")
                         .help(gettext("Disable MPRIS support").as_str())
                 )
+                .arg(
+                    Arg::with_name("disable-dbus-service")
+                        .long("disable-dbus-service")
+                        .help(gettext("Disable the D-Bus control service").as_str())
+                )
         )
     };
 }
@@ -177,6 +412,130 @@ macro_rules! app {
     () => { base_app!() };
 }
 
+// Event hooks are configured in the preferences file
+
+fn load_hooks_config() -> HooksConfig {
+    PreferencesInterface::new().preferences.hooks.unwrap_or_default()
+}
+
+/// Download the input of a subcommand first when it is an URL. The returned
+/// temporary file, if any, is removed when dropped.
+
+fn get_input_file(subcommand_args: &clap::ArgMatches, input_file: &str) -> Result<(String, Option<tempfile::TempPath>), Box<dyn Error>> {
+    if !is_url(input_file) {
+        return Ok((input_file.to_string(), None));
+    }
+
+    let mut limits = DownloadLimits::default();
+
+    if let Some(max_bytes) = subcommand_args.value_of("max-bytes") {
+        limits.max_bytes = max_bytes.parse().map_err(|_| gettext("Invalid maximum number of bytes"))?;
+    }
+    if let Some(max_seconds) = subcommand_args.value_of("max-seconds") {
+        limits.max_seconds = max_seconds.parse().map_err(|_| gettext("Invalid maximum duration"))?;
+    }
+
+    let downloaded_file = download_audio(input_file, &limits)?;
+
+    Ok((downloaded_file.to_string_lossy().to_string(), Some(downloaded_file)))
+}
+
+// The capture parameters from the preferences file may be overriden from the
+// command line
+
+fn get_capture_config(subcommand_args: &clap::ArgMatches) -> Result<CaptureConfig, Box<dyn Error>> {
+    let mut capture_config = PreferencesInterface::new().preferences.capture.unwrap_or_default();
+
+    if let Some(sample_rate) = subcommand_args.value_of("sample-rate") {
+        capture_config.sample_rate = Some(sample_rate.parse().map_err(|_| gettext("Invalid sample rate"))?);
+    }
+    if let Some(channels) = subcommand_args.value_of("channels") {
+        capture_config.channels = Some(channels.parse().map_err(|_| gettext("Invalid channel count"))?);
+    }
+    if let Some(sample_format) = subcommand_args.value_of("sample-format") {
+        capture_config.sample_format = CaptureSampleFormat::from_name(sample_format);
+    }
+    if let Some(downmix) = subcommand_args.value_of("downmix").and_then(DownmixStrategy::from_name) {
+        capture_config.downmix = downmix;
+    }
+    if let Some(buffer_seconds) = subcommand_args.value_of("buffer-seconds") {
+        capture_config.buffer_seconds = buffer_seconds.parse().map_err(|_| gettext("Invalid capture buffer length"))?;
+    }
+    if let Some(recognition_interval) = subcommand_args.value_of("recognition-interval") {
+        capture_config.recognition_interval_seconds = recognition_interval.parse().map_err(|_| gettext("Invalid recognition interval"))?;
+    }
+
+    if let Some(record_directory) = subcommand_args.value_of("record-dir") {
+        capture_config.recorder.enabled = true;
+        capture_config.recorder.directory = Some(record_directory.to_string());
+    }
+    if let Some(record_format) = subcommand_args.value_of("record-format").and_then(RecordingFormat::from_name) {
+        capture_config.recorder.format = record_format;
+    }
+
+    capture_config.validate()?;
+
+    Ok(capture_config)
+}
+
+// The preprocessing chains from the preferences file may be replaced by the
+// recommended one from the command line
+
+fn get_preprocessing(subcommand_args: &clap::ArgMatches) -> SourcePreprocessing {
+    if subcommand_args.is_present("preprocess") {
+        return SourcePreprocessing {
+            microphone: PreprocessingConfig::recommended(),
+            file: PreprocessingConfig::recommended()
+        };
+    }
+
+    PreferencesInterface::new().preferences.preprocessing.unwrap_or_default()
+}
+
+// Additional capture sources given on the command line replace those from
+// the preferences file. Without an explicit device, the first one takes the
+// place of the selected device
+
+fn apply_source_arguments(subcommand_args: &clap::ArgMatches, audio_device: &mut Option<String>, capture_config: &mut CaptureConfig) -> Result<(), Box<dyn Error>> {
+    let mut sources: Vec<CaptureSourceConfig> = match subcommand_args.values_of("source") {
+        Some(arguments) => arguments.map(|argument| {
+            CaptureSourceConfig::from_argument(argument).ok_or(format!("{} {}", gettext("Invalid audio source (expected ID=DEVICE):"), argument))
+        }).collect::<Result<_, _>>()?,
+        None => return Ok(())
+    };
+
+    if audio_device.is_none() {
+        let first_source = sources.remove(0);
+
+        capture_config.source_id = first_source.id;
+        *audio_device = Some(first_source.device);
+    }
+
+    capture_config.sources = sources;
+    capture_config.validate()?;
+
+    Ok(())
+}
+
+// Capturing a given application is requested to the microphone thread as
+// a special audio device name
+
+fn get_audio_device(subcommand_args: &clap::ArgMatches) -> Option<String> {
+    match subcommand_args.value_of("capture-app") {
+        Some(application_name) => Some(format!("{}{}", APPLICATION_DEVICE_PREFIX, application_name)),
+        None => subcommand_args.value_of("audio-device").map(str::to_string)
+    }
+}
+
+// Fingerprints to inspect may be given either as data-URIs or as audio files
+
+fn get_signature(fingerprint_or_file: &str) -> Result<DecodedSignature, Box<dyn Error>> {
+    match fingerprint_or_file.starts_with("data:") {
+        true => DecodedSignature::decode_from_uri(fingerprint_or_file),
+        false => SignatureGenerator::make_signature_from_file(fingerprint_or_file)
+    }
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
 
     // Set up the translation/internationalization part
@@ -186,20 +545,31 @@ fn main() -> Result<(), Box<dyn Error>> {
     // Collect the program arguments
     let args = app!().get_matches();
     
+    // The global argument may be given before or after the subcommand
+    
+    if let Some(ffmpeg_path) = args.value_of("ffmpeg-path").or_else(|| args.subcommand().1.and_then(|subcommand_args| subcommand_args.value_of("ffmpeg-path"))) {
+        set_ffmpeg_path(ffmpeg_path);
+    }
+    
     match args.subcommand_name() {
         Some("audio-file-to-recognized-song") => {            
             let subcommand_args = args.subcommand_matches("audio-file-to-recognized-song").unwrap();
             
-            let input_file_string = subcommand_args.value_of("input_file").unwrap();
+            let (input_file_string, _downloaded_file) = get_input_file(subcommand_args, subcommand_args.value_of("input_file").unwrap())?;
             
-            println!("{}", serde_json::to_string_pretty(&recognize_song_from_signature(&SignatureGenerator::make_signature_from_file(input_file_string)?)?)?);
+            println!("{}", serde_json::to_string_pretty(&recognize_song_from_signature(&SignatureGenerator::make_signature_from_file(&input_file_string)?)?)?);
         },
         Some("audio-file-to-fingerprint") => {
             let subcommand_args = args.subcommand_matches("audio-file-to-fingerprint").unwrap();
             
             let input_file_string = subcommand_args.value_of("input_file").unwrap();
             
-            println!("{}", SignatureGenerator::make_signature_from_file(input_file_string)?.encode_to_uri()?);
+            let signature = match subcommand_args.value_of("sample-rate") {
+                Some(sample_rate) => SignatureGenerator::make_signature_from_file_at_sample_rate(input_file_string, sample_rate.parse().map_err(|_| gettext("Invalid sample rate"))?)?,
+                None => SignatureGenerator::make_signature_from_file(input_file_string)?
+            };
+            
+            println!("{}", signature.encode_to_uri()?);
         },
         Some("fingerprint-to-recognized-song") => {
             let subcommand_args = args.subcommand_matches("fingerprint-to-recognized-song").unwrap();
@@ -213,27 +583,22 @@ fn main() -> Result<(), Box<dyn Error>> {
             
             let fingerprint_string = subcommand_args.value_of("fingerprint").unwrap();
             
-            let samples: Vec<i16> = DecodedSignature::decode_from_uri(fingerprint_string)?.to_lure()?;
+            let signature = DecodedSignature::decode_from_uri(fingerprint_string)?;
+            
+            let lure = Lure::new(&signature, &LureOptions {
+                sample_rate_hz: match subcommand_args.value_of("sample-rate") {
+                    Some(sample_rate) => Some(sample_rate.parse().map_err(|_| gettext("Invalid sample rate"))?),
+                    None => None
+                },
+                harmonics: subcommand_args.is_present("harmonics")
+            })?;
             
             match subcommand_args.value_of("output_file") {
                 Some(output_file_string) => {
-                    let spec = hound::WavSpec {
-                        channels: 1,
-                        sample_rate: 16000,
-                        bits_per_sample: 16,
-                        sample_format: hound::SampleFormat::Int,
-                    };
-                    
-                    let mut writer = hound::WavWriter::create(output_file_string, spec)?;
-                    
-                    for sample in samples {
-                        writer.write_sample(sample)?;
-                    }
-                    
-                    writer.finalize()?;
+                    lure.save(output_file_string)?;
                 },
                 None => {
-                    let mixed_source = rodio::buffer::SamplesBuffer::new::<Vec<i16>>(1, 16000, samples);
+                    let mixed_source = rodio::buffer::SamplesBuffer::new::<Vec<i16>>(1, lure.sample_rate_hz, lure.samples);
                             
                     let (_stream, handle) = rodio::OutputStream::try_default()?;
                     let sink = rodio::Sink::try_new(&handle).unwrap();
@@ -246,15 +611,60 @@ fn main() -> Result<(), Box<dyn Error>> {
             };
             
         },
+        Some("audio-file-to-spectrogram") => {
+            let subcommand_args = args.subcommand_matches("audio-file-to-spectrogram").unwrap();
+            
+            let input_file_string = subcommand_args.value_of("input_file").unwrap();
+            let output_file_string = subcommand_args.value_of("output_file").unwrap();
+            
+            let (signature, spectrogram) = SignatureGenerator::make_signature_and_spectrogram_from_file(input_file_string)?;
+            
+            save_fingerprint_image(output_file_string, &signature, Some(&spectrogram))?;
+        },
+        Some("fingerprint-to-image") => {
+            let subcommand_args = args.subcommand_matches("fingerprint-to-image").unwrap();
+            
+            let fingerprint_string = subcommand_args.value_of("fingerprint").unwrap();
+            let output_file_string = subcommand_args.value_of("output_file").unwrap();
+            
+            save_fingerprint_image(output_file_string, &DecodedSignature::decode_from_uri(fingerprint_string)?, None)?;
+        },
+        Some("fingerprint-info") => {
+            let subcommand_args = args.subcommand_matches("fingerprint-info").unwrap();
+            
+            println!("{}", SignatureQuality::new(&get_signature(subcommand_args.value_of("fingerprint").unwrap())?));
+        },
+        Some("compare-fingerprints") => {
+            let subcommand_args = args.subcommand_matches("compare-fingerprints").unwrap();
+            
+            let first_signature = get_signature(subcommand_args.value_of("first").unwrap())?;
+            let second_signature = get_signature(subcommand_args.value_of("second").unwrap())?;
+            
+            let comparison = SignatureComparison::new(&first_signature, &second_signature);
+            
+            println!("{} {:.3}", gettext("Similarity:"), comparison.similarity);
+            println!("{} {}", gettext("Matched frequency peaks:"), comparison.matched_peak_count);
+            println!("{} {:.3} {}", gettext("Time offset:"), comparison.time_offset_seconds, gettext("seconds"));
+            println!("{} {:.5}", gettext("Time skew:"), comparison.time_skew);
+            println!("{} {:.3} {}", gettext("Overlap:"), comparison.overlap_seconds, gettext("seconds"));
+        },
+        Some("formats") => {
+            print_format_support();
+        },
         Some("listen") => {
             let subcommand_args = args.subcommand_matches("listen").unwrap();
-            let audio_device = subcommand_args.value_of("audio-device").map(str::to_string);
+            let mut audio_device = get_audio_device(subcommand_args);
+            let mut capture_config = get_capture_config(subcommand_args)?;
+            apply_source_arguments(subcommand_args, &mut audio_device, &mut capture_config)?;
             let enable_mpris = !subcommand_args.is_present("disable-mpris");
+            let enable_dbus_service = !subcommand_args.is_present("disable-dbus-service");
             let enable_json = subcommand_args.is_present("json");
             let enable_csv = subcommand_args.is_present("csv");
+            let http_address = subcommand_args.value_of("http").map(str::to_string);
 
             cli_main(CLIParameters {
                 enable_mpris,
+                enable_dbus_service,
                 recognize_once: false,
                 audio_device,
                 input_file: None,
@@ -266,18 +676,29 @@ fn main() -> Result<(), Box<dyn Error>> {
                 }
                 else {
                     CLIOutputType::SongName
-                }
+                },
+                hooks: load_hooks_config(),
+                capture_config,
+                preprocessing: get_preprocessing(subcommand_args),
+                http_address
             })?;
         },
         Some("recognize") => {
             let subcommand_args = args.subcommand_matches("recognize").unwrap();
-            let audio_device = subcommand_args.value_of("audio-device").map(str::to_string);
-            let input_file = subcommand_args.value_of("input_file").map(str::to_string);
+            let audio_device = get_audio_device(subcommand_args);
+            let (input_file, _downloaded_file) = match subcommand_args.value_of("input_file") {
+                Some(input_file) => {
+                    let (input_file, downloaded_file) = get_input_file(subcommand_args, input_file)?;
+                    (Some(input_file), downloaded_file)
+                },
+                None => (None, None)
+            };
             let enable_json = subcommand_args.is_present("json");
             let enable_csv = subcommand_args.is_present("csv");
 
             cli_main(CLIParameters {
                 enable_mpris: false,
+                enable_dbus_service: false,
                 recognize_once: true,
                 audio_device,
                 input_file,
@@ -290,7 +711,11 @@ fn main() -> Result<(), Box<dyn Error>> {
                 }
                 else {
                     CLIOutputType::SongName
-                }
+                },
+                hooks: load_hooks_config(),
+                capture_config: get_capture_config(subcommand_args)?,
+                preprocessing: get_preprocessing(subcommand_args),
+                http_address: None
             })?;
         },
         Some("microphone-to-recognized-song") => {
@@ -299,10 +724,15 @@ fn main() -> Result<(), Box<dyn Error>> {
 
             cli_main(CLIParameters {
                 enable_mpris: false,
+                enable_dbus_service: false,
                 recognize_once: true,
                 audio_device,
                 input_file: None,
-                output_type: CLIOutputType::JSON
+                output_type: CLIOutputType::JSON,
+                hooks: load_hooks_config(),
+                capture_config: get_capture_config(subcommand_args)?,
+                preprocessing: get_preprocessing(subcommand_args),
+                http_address: None
             })?;
         },
         #[cfg(feature="gui")]
@@ -312,6 +742,7 @@ fn main() -> Result<(), Box<dyn Error>> {
             gui_main(false,
                  subcommand_args.value_of("input_file"),
                  !subcommand_args.is_present("disable-mpris"),
+                 !subcommand_args.is_present("disable-dbus-service"),
             )?;
         },
         #[cfg(feature="gui")]
@@ -320,20 +751,26 @@ fn main() -> Result<(), Box<dyn Error>> {
                 gui_main(true,
                      subcommand_args.value_of("input_file"),
                      !subcommand_args.is_present("disable-mpris"),
+                     !subcommand_args.is_present("disable-dbus-service"),
                 )?;
             }
             else {
-                gui_main(true, None, true)?;
+                gui_main(true, None, true, true)?;
             }
         },
         #[cfg(not(feature="gui"))]
         None => {
             cli_main(CLIParameters {
                 enable_mpris: true,
+                enable_dbus_service: true,
                 recognize_once: false,
                 audio_device: None,
                 input_file: None,
-                output_type: CLIOutputType::SongName
+                output_type: CLIOutputType::SongName,
+                hooks: load_hooks_config(),
+                capture_config: get_capture_config(&args)?,
+                preprocessing: get_preprocessing(&args),
+                http_address: None
             })?;
         },
         _ => unreachable!()
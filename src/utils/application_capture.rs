@@ -0,0 +1,146 @@
+use std::error::Error;
+use std::process::Command;
+use gettextrs::gettext;
+
+use crate::utils::pulseaudio_loopback::{PulseaudioLoopback, PulseaudioSinkInput};
+
+/// This file contains code for recognizing audio from a single application
+/// (for example a web browser), rather than from everything played through
+/// the speakers.
+///
+/// When the capture starts, a null sink is created, the streams of the
+/// application are moved to it, and a loopback is created from its monitor
+/// to the sink where they were initially played, so that the user can still
+/// hear them. SongRec then records from the monitor of the null sink. This is
+/// equivalent to:
+///
+///  pactl load-module module-null-sink sink_name=songrec_capture_1234
+///  pactl load-module module-loopback source=songrec_capture_1234.monitor sink=0
+///  pactl move-sink-input 26 songrec_capture_1234
+///
+/// Everything is undone when the capture is dropped. This works under both
+/// PulseAudio and PipeWire (through "pipewire-pulse"), as long as "pactl" is
+/// available.
+///
+/// Note that streams opened by the application after the capture started
+/// (for example when a new tab starts playing) are not captured, until
+/// listening is restarted.
+
+/// The prefix used for distinguishing applications from other audio devices,
+/// in the device list of the GUI.
+
+pub const APPLICATION_DEVICE_PREFIX: &str = "Application: ";
+
+pub struct ApplicationCapture {
+    null_sink_name: String,
+    module_indexes: Vec<String>,
+    moved_sink_inputs: Vec<PulseaudioSinkInput>
+}
+
+impl ApplicationCapture {
+
+    /// The names of the applications currently playing audio, to be listed
+    /// as audio devices.
+
+    pub fn list_application_names() -> Vec<String> {
+        let mut application_names: Vec<String> = PulseaudioLoopback::list_sink_inputs().unwrap_or_default()
+            .into_iter().map(|sink_input| sink_input.application_name).collect();
+
+        application_names.sort();
+        application_names.dedup();
+
+        application_names
+    }
+
+    /// Start capturing the streams of the application whose name contains
+    /// the given string (case-insensitively).
+
+    pub fn start(application_name: &str) -> Result<Self, Box<dyn Error>> {
+        let lowercase_application_name = application_name.to_lowercase();
+
+        let sink_inputs: Vec<PulseaudioSinkInput> = PulseaudioLoopback::list_sink_inputs()
+            .ok_or_else(|| gettext("Unable to list the applications playing audio (is \"pactl\" available?)"))?
+            .into_iter()
+            .filter(|sink_input| sink_input.application_name.to_lowercase().contains(&lowercase_application_name))
+            .collect();
+
+        if sink_inputs.is_empty() {
+            return Err(format!("{} \"{}\"", gettext("No audio is currently played by the application"), application_name).into());
+        }
+
+        let mut capture = ApplicationCapture {
+            null_sink_name: format!("songrec_capture_{}", std::process::id()),
+            module_indexes: vec![],
+            moved_sink_inputs: vec![]
+        };
+
+        // If anything fails below, dropping the capture undoes what was
+        // already done
+
+        let null_sink_module = run_pactl(&[
+            "load-module",
+            "module-null-sink",
+            &format!("sink_name={}", capture.null_sink_name),
+            "sink_properties=device.description=SongRec"
+        ])?;
+        capture.module_indexes.push(null_sink_module);
+
+        let mut looped_back_sinks: Vec<u64> = vec![];
+
+        for sink_input in sink_inputs.iter() {
+            if !looped_back_sinks.contains(&sink_input.sink_index) {
+                let loopback_module = run_pactl(&[
+                    "load-module",
+                    "module-loopback",
+                    &format!("source={}.monitor", capture.null_sink_name),
+                    &format!("sink={}", sink_input.sink_index),
+                    "latency_msec=1"
+                ])?;
+                capture.module_indexes.push(loopback_module);
+                looped_back_sinks.push(sink_input.sink_index);
+            }
+
+            run_pactl(&["move-sink-input", &sink_input.index.to_string(), &capture.null_sink_name])?;
+            capture.moved_sink_inputs.push(sink_input.clone());
+        }
+
+        Ok(capture)
+    }
+
+    /// The PulseAudio source to record from.
+
+    pub fn source_name(self: &Self) -> String {
+        format!("{}.monitor", self.null_sink_name)
+    }
+
+}
+
+impl Drop for ApplicationCapture {
+    fn drop(self: &mut Self) {
+
+        // Move back the streams before unloading the modules, so that they
+        // don't end up on the default sink instead of the one they were
+        // played on (the streams may have been closed since, in which case
+        // this fails silently)
+
+        for sink_input in self.moved_sink_inputs.iter() {
+            run_pactl(&["move-sink-input", &sink_input.index.to_string(), &sink_input.sink_index.to_string()]).ok();
+        }
+
+        for module_index in self.module_indexes.iter().rev() {
+            if let Err(error) = run_pactl(&["unload-module", module_index]) {
+                eprintln!("{} {}: {}", gettext("Unable to unload the PulseAudio module"), module_index, error);
+            }
+        }
+    }
+}
+
+fn run_pactl(arguments: &[&str]) -> Result<String, Box<dyn Error>> {
+    let output = Command::new("pactl").args(arguments).output()?;
+
+    if !output.status.success() {
+        return Err(format!("pactl {}: {}", arguments.join(" "), String::from_utf8_lossy(&output.stderr).trim()).into());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
@@ -0,0 +1,352 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::time::Duration;
+use gettextrs::gettext;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{CodecRegistry, CodecType, Decoder, DecoderOptions, CODEC_TYPE_NULL, CODEC_TYPE_AAC, CODEC_TYPE_ALAC, CODEC_TYPE_OPUS, CODEC_TYPE_WMA, CODEC_TYPE_FLAC, CODEC_TYPE_MP3, CODEC_TYPE_VORBIS};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatReader;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::probe::Hint;
+
+use crate::utils::ffmpeg_wrapper::{FfmpegDecoder, FfmpegError, is_ffmpeg_available};
+#[cfg(feature = "opus")]
+use crate::utils::opus_decoder::OpusDecoder;
+
+/// This module decodes the audio files to recognize, natively through Rodio
+/// (and the Symphonia decoders it uses) when possible, and through FFMpeg
+/// otherwise.
+///
+/// Video files (such as MP4, MKV or WebM files) are decoded natively too, from
+/// their first audio track which has a decoder.
+///
+/// Some codecs can be left out at build time through cargo features ("aac"
+/// and "alac", enabled by default). Opus is decoded natively through libopus
+/// with the "opus" cargo feature, disabled by default. There is no native
+/// decoder for WMA, which is only supported when FFMpeg is installed.
+///
+/// When a file can't be decoded, the error names the missing codec (after
+/// probing the container of the file with Symphonia) rather than being a
+/// generic decoding failure.
+
+#[derive(Debug)]
+pub enum DecodingError {
+    UnrecognizedFormat,
+    MissingCodec(String, Option<&'static str>), // The codec name, and the cargo feature providing it, if any
+    InvalidFile(String)
+}
+
+impl Error for DecodingError {}
+
+impl fmt::Display for DecodingError {
+    fn fmt(self: &Self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            DecodingError::UnrecognizedFormat => gettext("The format of this file was not recognized."),
+            DecodingError::MissingCodec(codec_name, Some(feature)) => format!("{} {} ({} \"{}\").", gettext("No decoder is available for the codec of this file:"), codec_name,
+                gettext("SongRec was built without the cargo feature"), feature),
+            DecodingError::MissingCodec(codec_name, None) => format!("{} {} ({}).", gettext("No decoder is available for the codec of this file:"), codec_name,
+                gettext("only supported through FFMpeg")),
+            DecodingError::InvalidFile(error) => format!("{} {}.", gettext("Unable to decode this file:"), error)
+        };
+
+        // Only a missing codec is known to be decodable by FFMpeg
+
+        match self {
+            DecodingError::MissingCodec(_, _) => write!(formatter, "{} {}", message, gettext("FFMpeg, which could decode it, was not found (is it installed?)")),
+            _ => write!(formatter, "{}", message)
+        }
+    }
+}
+
+/// Whether a format is supported, as listed by the "formats" subcommand.
+
+pub struct FormatSupport {
+    pub name: &'static str,
+    pub extensions: &'static str,
+    pub native: bool,
+    pub feature: Option<&'static str> // The cargo feature providing native support, if any
+}
+
+pub fn get_format_support() -> Vec<FormatSupport> {
+    vec![
+        FormatSupport { name: "WAV", extensions: ".wav", native: true, feature: None },
+        FormatSupport { name: "FLAC", extensions: ".flac", native: true, feature: None },
+        FormatSupport { name: "MP3", extensions: ".mp3", native: true, feature: None },
+        FormatSupport { name: "Ogg Vorbis", extensions: ".ogg, .oga", native: true, feature: None },
+        FormatSupport { name: "Matroska/WebM (Vorbis, FLAC, MP3, AAC)", extensions: ".mka, .mkv, .webm", native: true, feature: None },
+        FormatSupport { name: "AAC (MP4 or raw)", extensions: ".m4a, .mp4, .m4v, .mov, .aac", native: cfg!(feature = "aac"), feature: Some("aac") },
+        FormatSupport { name: "ALAC (MP4)", extensions: ".m4a", native: cfg!(feature = "alac"), feature: Some("alac") },
+        FormatSupport { name: "Opus", extensions: ".opus, .ogg, .webm", native: cfg!(feature = "opus"), feature: Some("opus") },
+        FormatSupport { name: "WMA", extensions: ".wma", native: false, feature: None }
+    ]
+}
+
+/// Print the supported formats, for the "formats" subcommand.
+
+pub fn print_format_support() {
+    let ffmpeg_available = is_ffmpeg_available();
+
+    for format in get_format_support() {
+        let status = match (format.native, ffmpeg_available, format.feature) {
+            (true, _, _) => gettext("native"),
+            (false, true, _) => gettext("through FFMpeg"),
+            (false, false, Some(feature)) => format!("{} \"{}\" {}", gettext("unavailable (build with the"), feature, gettext("cargo feature, or install FFMpeg)")),
+            (false, false, None) => gettext("unavailable (install FFMpeg)")
+        };
+
+        println!("{:<40} {:<20} {}", format.name, format.extensions, status);
+    }
+
+    println!();
+    println!("{} {}", gettext("FFMpeg:"), match ffmpeg_available {
+        true => gettext("found, other formats and video files can be decoded too"),
+        false => gettext("not found")
+    });
+}
+
+/// Decode an audio or video file natively if possible, and through FFMpeg
+/// otherwise.
+
+pub fn decode_file(file_path: &str) -> Result<Box<dyn rodio::Source<Item = i16> + Send>, Box<dyn Error>> {
+    if let Some(decoder) = AudioTrackDecoder::new(file_path)? {
+        return Ok(Box::new(decoder));
+    }
+
+    // Try to decode with FFMpeg, if available, in case of failure with
+    // Rodio (most likely due to the use of a format unsupported natively,
+    // such as .WMA or .OPUS). When FFMpeg is there but fails too, its own
+    // error output tells more than probing the file would
+
+    match FfmpegDecoder::new().decode(file_path) {
+        Ok(decoder) => Ok(Box::new(decoder)),
+        Err(FfmpegError::NotFound) => Err(Box::new(diagnose_file(file_path))),
+        Err(error) => Err(Box::new(error))
+    }
+}
+
+/// The decoders of Symphonia enabled at build time, along with the Opus
+/// decoder when built with the "opus" cargo feature.
+
+fn get_codecs() -> CodecRegistry {
+    let mut codecs = CodecRegistry::new();
+
+    symphonia::default::register_enabled_codecs(&mut codecs);
+
+    #[cfg(feature = "opus")]
+    codecs.register_all::<OpusDecoder>();
+
+    codecs
+}
+
+/// A Rodio source decoding the first audio track of a file which has a
+/// decoder, skipping the packets of other tracks. Rodio's own Symphonia
+/// decoder only reads the default track of a file, and feeds it with the
+/// packets of all tracks, which doesn't work for video files.
+
+struct AudioTrackDecoder {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    samples: Vec<i16>, // Interleaved samples of the last decoded packet
+    channels: u16,
+    sample_rate: u32,
+    offset: usize
+}
+
+// Decoding errors are skipped, unless they happen for this many packets in
+// a row
+
+const MAXIMUM_CONSECUTIVE_DECODING_ERRORS: usize = 3;
+
+impl AudioTrackDecoder {
+
+    /// Return None when the format or the codecs of the file are not
+    /// supported natively, and an error when the file can't be read.
+
+    fn new(file_path: &str) -> Result<Option<Self>, Box<dyn Error>> {
+
+        let file = File::open(file_path)?;
+
+        let mut hint = Hint::new();
+
+        if let Some(extension) = std::path::Path::new(file_path).extension().and_then(|extension| extension.to_str()) {
+            hint.with_extension(extension);
+        }
+
+        let format = match symphonia::default::get_probe().format(&hint, MediaSourceStream::new(Box::new(file), Default::default()), &Default::default(), &Default::default()) {
+            Ok(probed) => probed.format,
+            Err(_) => { return Ok(None); }
+        };
+
+        let codecs = get_codecs();
+
+        let track = format.tracks().iter()
+            .filter(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+            .find_map(|track| codecs.make(&track.codec_params, &DecoderOptions::default()).ok()
+                .map(|decoder| (track.id, decoder)));
+
+        let (track_id, decoder) = match track {
+            Some(track) => track,
+            None => { return Ok(None); }
+        };
+
+        let mut decoder = AudioTrackDecoder {
+            format,
+            decoder,
+            track_id,
+            samples: vec![],
+            channels: 1,
+            sample_rate: 16000,
+            offset: 0
+        };
+
+        // Decode the first packet, so that the sample rate and channel count
+        // are known before the first sample is requested
+
+        match decoder.decode_next_packet() {
+            true => Ok(Some(decoder)),
+            false => Ok(None)
+        }
+
+    }
+
+    fn decode_next_packet(self: &mut Self) -> bool {
+
+        let mut consecutive_errors = 0;
+
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(_) => { return false; }
+            };
+
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            match self.decoder.decode(&packet) {
+                Ok(decoded) => {
+                    let spec = *decoded.spec();
+
+                    if decoded.frames() == 0 {
+                        continue;
+                    }
+
+                    let mut buffer = SampleBuffer::<i16>::new(decoded.capacity() as u64, spec);
+                    buffer.copy_interleaved_ref(decoded);
+
+                    self.samples = buffer.samples().to_vec();
+                    self.channels = spec.channels.count() as u16;
+                    self.sample_rate = spec.rate;
+                    self.offset = 0;
+
+                    return true;
+                },
+                Err(SymphoniaError::DecodeError(_)) if consecutive_errors < MAXIMUM_CONSECUTIVE_DECODING_ERRORS => {
+                    consecutive_errors += 1;
+                },
+                Err(_) => { return false; }
+            }
+        }
+
+    }
+
+}
+
+impl Iterator for AudioTrackDecoder {
+    type Item = i16;
+
+    fn next(self: &mut Self) -> Option<i16> {
+        let sample = *self.samples.get(self.offset)?;
+
+        self.offset += 1;
+
+        // Decode the next packet as soon as this one is exhausted, so that
+        // the length of the current frame is only zero at the end
+
+        if self.offset >= self.samples.len() {
+            self.decode_next_packet();
+        }
+
+        Some(sample)
+    }
+}
+
+impl rodio::Source for AudioTrackDecoder {
+    fn current_frame_len(self: &Self) -> Option<usize> {
+        Some(self.samples.len() - self.offset)
+    }
+
+    fn channels(self: &Self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(self: &Self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(self: &Self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Tell why a file couldn't be decoded natively, by probing its container
+/// and looking for a decoder for the codec of its first audio track.
+
+fn diagnose_file(file_path: &str) -> DecodingError {
+    let file = match File::open(file_path) {
+        Ok(file) => file,
+        Err(error) => { return DecodingError::InvalidFile(error.to_string()); }
+    };
+
+    let mut hint = Hint::new();
+
+    if let Some(extension) = std::path::Path::new(file_path).extension().and_then(|extension| extension.to_str()) {
+        hint.with_extension(extension);
+    }
+
+    let probed = symphonia::default::get_probe().format(&hint, MediaSourceStream::new(Box::new(file), Default::default()), &Default::default(), &Default::default());
+
+    let format = match probed {
+        Ok(probed) => probed.format,
+        Err(_) => {
+            // Symphonia has no demuxer for ASF, the container of WMA files
+
+            return match std::path::Path::new(file_path).extension().and_then(|extension| extension.to_str()).map(|extension| extension.to_lowercase()).as_deref() {
+                Some("m4a") | Some("mp4") | Some("aac") if !cfg!(feature = "aac") && !cfg!(feature = "alac") => DecodingError::MissingCodec("AAC".to_string(), Some("aac")),
+                Some("wma") | Some("asf") => DecodingError::MissingCodec("WMA".to_string(), None),
+                _ => DecodingError::UnrecognizedFormat
+            };
+        }
+    };
+
+    let codecs = get_codecs();
+
+    let codec_type = format.tracks().iter()
+        .map(|track| track.codec_params.codec)
+        .find(|codec_type| *codec_type != CODEC_TYPE_NULL && codecs.get_codec(*codec_type).is_none())
+        .unwrap_or(CODEC_TYPE_NULL);
+
+    match codec_type {
+        CODEC_TYPE_NULL => DecodingError::InvalidFile(gettext("the file seems to be corrupted, or to contain no audio track")),
+        _ => {
+            let (codec_name, feature) = get_codec_name(codec_type);
+
+            DecodingError::MissingCodec(codec_name, feature)
+        }
+    }
+}
+
+fn get_codec_name(codec_type: CodecType) -> (String, Option<&'static str>) {
+    match codec_type {
+        CODEC_TYPE_AAC => ("AAC".to_string(), Some("aac")),
+        CODEC_TYPE_ALAC => ("ALAC".to_string(), Some("alac")),
+        CODEC_TYPE_OPUS => ("Opus".to_string(), Some("opus")),
+        CODEC_TYPE_WMA => ("WMA".to_string(), None),
+        CODEC_TYPE_FLAC => ("FLAC".to_string(), None),
+        CODEC_TYPE_MP3 => ("MP3".to_string(), None),
+        CODEC_TYPE_VORBIS => ("Vorbis".to_string(), None),
+        _ => (format!("{}", codec_type), None)
+    }
+}
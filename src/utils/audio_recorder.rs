@@ -0,0 +1,450 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use chrono::{DateTime, Local};
+use gettextrs::gettext;
+use regex::Regex;
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+
+use crate::utils::filesystem_operations::obtain_recordings_directory_path;
+use crate::core::event_bus::EventBus;
+use crate::core::thread_messages::{GUIMessage, RecognitionError, RecognitionOutcome};
+use crate::utils::ffmpeg_wrapper::FfmpegDecoder;
+
+/// This module contains the recorder of the microphone thread, which saves
+/// the exact audio windows sent for recognition (so that songs which were
+/// not recognized can be retried or debugged later), each one along with a
+/// JSON file containing the fingerprint that was sent and the result.
+///
+/// The recorder is configured in the "[capture.recorder]" section of the
+/// preferences file, for example:
+///
+/// ```toml
+/// [capture.recorder]
+/// enabled = true
+/// directory = "/home/user/songrec-recordings"
+/// format = "flac"
+/// max_recordings = 50
+/// ```
+///
+/// When it is not enabled, audio windows are only saved on demand (through
+/// the "MicrophoneMessage::SaveRecording" message). The oldest recordings are
+/// removed when there are more than "max_recordings" of them, or when they
+/// take more than "max_total_megabytes".
+
+const FILE_NAME_PREFIX: &str = "songrec-";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordingFormat {
+    Wav,
+    Flac // Encoded through FFMpeg, if available (WAV is used otherwise)
+}
+
+impl RecordingFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "wav" => Some(RecordingFormat::Wav),
+            "flac" => Some(RecordingFormat::Flac),
+            _ => None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RecorderConfig {
+    pub enabled: bool, // Whether each recognition attempt is recorded
+    pub directory: Option<String>, // Defaults to a "recordings" directory next to the song history
+    pub format: RecordingFormat,
+    pub max_recordings: Option<usize>,
+    pub max_total_megabytes: Option<u64>
+}
+
+impl Default for RecorderConfig {
+    fn default() -> Self {
+        RecorderConfig {
+            enabled: false,
+            directory: None,
+            format: RecordingFormat::Wav,
+            max_recordings: Some(100),
+            max_total_megabytes: Some(200)
+        }
+    }
+}
+
+/// The recorder writes the files from a worker thread, so that encoding them
+/// never delays the microphone thread. The results of the recognitions are
+/// added by the same thread, after the files they complete were written.
+
+enum RecorderJob {
+    Save(SavedWindow),
+    Complete(PathBuf, Box<RecognitionOutcome>)
+}
+
+struct SavedWindow {
+    samples: Vec<i16>,
+    directory: PathBuf,
+    base_name: String,
+    source_id: String,
+    device_name: Option<String>,
+    recognition_attempt: bool,
+    recording_date: DateTime<Local>
+}
+
+pub struct AudioRecorder {
+    config: RecorderConfig,
+    jobs_tx: Option<mpsc::Sender<RecorderJob>>,
+    worker: Option<thread::JoinHandle<()>>
+}
+
+impl AudioRecorder {
+
+    /// Errors occurring on the worker thread are sent to the given bus.
+
+    pub fn new(config: RecorderConfig, gui_tx: EventBus) -> Self {
+        let (jobs_tx, jobs_rx) = mpsc::channel();
+
+        let worker_config = config.clone();
+
+        let worker = thread::spawn(move || {
+            recorder_thread(jobs_rx, worker_config, gui_tx);
+        });
+
+        AudioRecorder {
+            config,
+            jobs_tx: Some(jobs_tx),
+            worker: Some(worker)
+        }
+    }
+
+    /// Save 16 KHz mono samples from the given capture source, and return
+    /// the path of the JSON file that will describe them (so that the result
+    /// of their recognition can be added to it later).
+
+    pub fn save(self: &Self, samples: Vec<i16>, source_id: &str, device_name: Option<&str>, recognition_attempt: bool) -> Result<PathBuf, Box<dyn Error>> {
+        let directory = self.obtain_directory()?;
+
+        let recording_date = Local::now();
+
+        // Windows of several capture sources may be saved at the same time
+
+        let base_name = format!("{}{}-{}", FILE_NAME_PREFIX, recording_date.format("%Y%m%d-%H%M%S-%3f"), sanitize_file_name(source_id));
+
+        let metadata_path = directory.join(format!("{}.json", base_name));
+
+        self.send(RecorderJob::Save(SavedWindow {
+            samples,
+            directory,
+            base_name,
+            source_id: source_id.to_string(),
+            device_name: device_name.map(str::to_string),
+            recognition_attempt,
+            recording_date
+        }))?;
+
+        Ok(metadata_path)
+    }
+
+    /// Add the fingerprint and the result of a recognition to the JSON file
+    /// of a recording.
+
+    pub fn complete(self: &Self, metadata_path: PathBuf, outcome: Box<RecognitionOutcome>) -> Result<(), Box<dyn Error>> {
+        self.send(RecorderJob::Complete(metadata_path, outcome))
+    }
+
+    fn send(self: &Self, job: RecorderJob) -> Result<(), Box<dyn Error>> {
+        let sent = match &self.jobs_tx {
+            Some(jobs_tx) => jobs_tx.send(job).is_ok(),
+            None => false
+        };
+
+        match sent {
+            true => Ok(()),
+            false => Err(gettext("The recorder has stopped working").into())
+        }
+    }
+
+    fn obtain_directory(self: &Self) -> Result<PathBuf, Box<dyn Error>> {
+        let directory = match self.config.directory {
+            Some(ref directory) => PathBuf::from(directory),
+            None => default_recordings_directory()?
+        };
+
+        fs::create_dir_all(&directory)?;
+
+        Ok(directory)
+    }
+
+}
+
+impl Drop for AudioRecorder {
+    fn drop(self: &mut Self) {
+
+        // Let the worker thread write the pending recordings
+
+        self.jobs_tx = None;
+
+        if let Some(worker) = self.worker.take() {
+            worker.join().ok();
+        }
+
+    }
+}
+
+fn recorder_thread(jobs_rx: mpsc::Receiver<RecorderJob>, config: RecorderConfig, gui_tx: EventBus) {
+
+    for job in jobs_rx.iter() {
+        match job {
+            RecorderJob::Save(window) => {
+                if let Err(error) = write_recording(&window, &config) {
+                    gui_tx.send(GUIMessage::ErrorMessage(format!("{} {}", gettext("Unable to save the recording:"), error)));
+                }
+            },
+            RecorderJob::Complete(metadata_path, outcome) => {
+                if let Err(error) = write_outcome(&metadata_path, &outcome) {
+                    eprintln!("{} {}", gettext("Unable to save the recording:"), error);
+                }
+            }
+        };
+    }
+
+}
+
+fn write_recording(window: &SavedWindow, config: &RecorderConfig) -> Result<(), Box<dyn Error>> {
+    let wav_path = window.directory.join(format!("{}.wav", window.base_name));
+
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: 16000,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+
+    let mut writer = hound::WavWriter::create(&wav_path, spec)?;
+    for sample in window.samples.iter() {
+        writer.write_sample(*sample)?;
+    }
+    writer.finalize()?;
+
+    let mut audio_path = wav_path.clone();
+
+    if config.format == RecordingFormat::Flac {
+        let flac_path = window.directory.join(format!("{}.flac", window.base_name));
+
+        match FfmpegDecoder::new().convert(&wav_path.to_string_lossy(), &flac_path.to_string_lossy()) {
+            Ok(()) => {
+                fs::remove_file(&wav_path)?;
+                audio_path = flac_path;
+            },
+            Err(error) => {
+                eprintln!("{} {}", gettext("Unable to encode the recording to FLAC, keeping it as WAV:"), error);
+            }
+        }
+    }
+
+    let metadata_path = window.directory.join(format!("{}.json", window.base_name));
+
+    let metadata = json!({
+        "audio_file": audio_path.file_name().map(|name| name.to_string_lossy().to_string()),
+        "recording_date": window.recording_date.to_rfc3339(),
+        "source": window.source_id,
+        "device": window.device_name,
+        "sample_rate": 16000,
+        "duration_seconds": window.samples.len() as f32 / 16000.0,
+        "trigger": match window.recognition_attempt {
+            true => "recognition",
+            false => "on_demand"
+        },
+        "status": match window.recognition_attempt {
+            true => "pending",
+            false => "not_recognized"
+        },
+        "signature_uri": Value::Null,
+        "error": Value::Null,
+        "result": Value::Null
+    });
+
+    fs::write(&metadata_path, serde_json::to_string_pretty(&metadata)?)?;
+
+    enforce_retention(&window.directory, config)
+}
+
+fn write_outcome(metadata_path: &Path, outcome: &RecognitionOutcome) -> Result<(), Box<dyn Error>> {
+
+    // The recording may have been removed in the meantime because of
+    // the retention limits
+
+    if !metadata_path.exists() {
+        return Ok(());
+    }
+
+    let mut metadata: Value = serde_json::from_str(&fs::read_to_string(metadata_path)?)?;
+
+    metadata["signature_uri"] = json!(outcome.signature_uri);
+    metadata["error"] = json!(outcome.error.as_ref().map(|error| error.to_string()));
+    metadata["result"] = outcome.shazam_json.as_ref()
+        .and_then(|shazam_json| serde_json::from_str(shazam_json).ok())
+        .unwrap_or(Value::Null);
+    metadata["status"] = json!(match (&outcome.shazam_json, &outcome.error) {
+        (Some(_), _) => "recognized",
+        (None, Some(RecognitionError::NoMatch)) => "no_match",
+        (None, _) => "error"
+    });
+
+    fs::write(metadata_path, serde_json::to_string_pretty(&metadata)?)?;
+
+    Ok(())
+}
+
+/// Remove the oldest recordings (the file names begin with the date)
+/// until the retention limits are satisfied.
+
+fn enforce_retention(directory: &Path, config: &RecorderConfig) -> Result<(), Box<dyn Error>> {
+    let mut recordings: Vec<(String, Vec<PathBuf>, u64)> = vec![]; // Base name, files, total size
+
+    // Only consider the files written by the recorder: other files, such as
+    // downloads, may share the prefix when the recordings are kept in the
+    // temporary directory
+
+    let recording_name = Regex::new(&format!(r"^{}\d{{8}}-\d{{6}}-\d{{3}}-[\p{{Alphabetic}}\p{{N}}_-]+\.(wav|flac|json)$", regex::escape(FILE_NAME_PREFIX))).unwrap();
+
+    let mut file_paths: Vec<PathBuf> = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.file_name().map(|name| recording_name.is_match(&name.to_string_lossy())).unwrap_or(false))
+        .collect();
+
+    file_paths.sort();
+
+    for file_path in file_paths {
+        let base_name = match file_path.file_stem() {
+            Some(base_name) => base_name.to_string_lossy().to_string(),
+            None => continue
+        };
+        let size = fs::metadata(&file_path).map(|metadata| metadata.len()).unwrap_or(0);
+
+        match recordings.last_mut() {
+            Some(recording) if recording.0 == base_name => {
+                recording.1.push(file_path);
+                recording.2 += size;
+            },
+            _ => {
+                recordings.push((base_name, vec![file_path], size));
+            }
+        };
+    }
+
+    let mut total_size: u64 = recordings.iter().map(|recording| recording.2).sum();
+    let mut count = recordings.len();
+
+    for (_, file_paths, size) in recordings.iter() {
+        let too_many = config.max_recordings.map(|max_recordings| count > max_recordings).unwrap_or(false);
+        let too_large = config.max_total_megabytes.map(|max_megabytes| total_size > max_megabytes * 1024 * 1024).unwrap_or(false);
+
+        if !too_many && !too_large {
+            break;
+        }
+
+        for file_path in file_paths {
+            fs::remove_file(file_path)?;
+        }
+
+        total_size -= size;
+        count -= 1;
+    }
+
+    Ok(())
+}
+
+fn sanitize_file_name(name: &str) -> String {
+    name.chars().map(|character| match character.is_alphanumeric() || character == '-' || character == '_' {
+        true => character,
+        false => '_'
+    }).collect()
+}
+
+fn default_recordings_directory() -> Result<PathBuf, Box<dyn Error>> {
+    Ok(PathBuf::from(obtain_recordings_directory_path()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_config(directory: &Path) -> RecorderConfig {
+        RecorderConfig {
+            enabled: true,
+            directory: Some(directory.to_string_lossy().to_string()),
+            max_recordings: Some(2),
+            ..RecorderConfig::default()
+        }
+    }
+
+    #[test]
+    fn retention_only_removes_recordings() {
+        let directory = tempfile::tempdir().unwrap();
+
+        let recordings = [
+            "songrec-20260101-120000-000-default.wav", "songrec-20260101-120000-000-default.json",
+            "songrec-20260101-120010-500-mic_2.flac", "songrec-20260101-120010-500-mic_2.json",
+            "songrec-20260101-120020-250-monitor.wav", "songrec-20260101-120020-250-monitor.json"
+        ];
+
+        let other_files = [
+            "songrec-a1b2c3.mp3", // A download
+            "songrec-20260101-115000-000-default.txt",
+            "songrec-20260101-115000-default.wav",
+            "recording-20260101-115000-000-default.wav"
+        ];
+
+        for file_name in recordings.iter().chain(other_files.iter()) {
+            fs::write(directory.path().join(file_name), b"data").unwrap();
+        }
+
+        enforce_retention(directory.path(), &make_config(directory.path())).unwrap();
+
+        let mut remaining: Vec<String> = fs::read_dir(directory.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        remaining.sort();
+
+        let mut expected: Vec<&str> = recordings[2..].iter().chain(other_files.iter()).cloned().collect();
+        expected.sort();
+
+        assert_eq!(remaining, expected);
+    }
+
+    #[test]
+    fn recordings_are_completed() {
+        let directory = tempfile::tempdir().unwrap();
+
+        let recorder = AudioRecorder::new(make_config(directory.path()), EventBus::new());
+
+        let metadata_path = recorder.save(vec![0; 16000], "mic #2", Some("Microphone"), true).unwrap();
+
+        recorder.complete(metadata_path.clone(), Box::new(RecognitionOutcome {
+            signature_uri: Some("data:audio/vnd.shazam.sig;base64,".to_string()),
+            shazam_json: None,
+            error: Some(RecognitionError::NoMatch)
+        })).unwrap();
+
+        drop(recorder);
+
+        let metadata: Value = serde_json::from_str(&fs::read_to_string(&metadata_path).unwrap()).unwrap();
+
+        assert_eq!(metadata["source"], "mic #2");
+        assert_eq!(metadata["device"], "Microphone");
+        assert_eq!(metadata["duration_seconds"], 1.0);
+        assert_eq!(metadata["status"], "no_match");
+        assert_eq!(metadata["signature_uri"], "data:audio/vnd.shazam.sig;base64,");
+
+        let audio_path = directory.path().join(metadata["audio_file"].as_str().unwrap());
+        assert!(audio_path.to_string_lossy().ends_with("-mic__2.wav"));
+        assert_eq!(hound::WavReader::open(&audio_path).unwrap().len(), 16000);
+    }
+
+}
@@ -8,7 +8,7 @@
 
 use serde::{Serialize, Deserialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SongHistoryRecord {
     pub song_name: String,
     pub album: String,
@@ -20,6 +20,11 @@ pub struct SongHistoryRecord {
     #[serde(default)]
     pub release_year: String,
     #[serde(default)]
-    pub genre: String
+    pub genre: String,
+
+    // The capture source the song was heard from, when listening to several
+    // sources (see "capture_config.rs")
+    #[serde(default)]
+    pub source: String
 }
 
@@ -0,0 +1,745 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, mpsc};
+use std::thread;
+
+use chrono::Local;
+use dbus::{BusType, Connection, Interface, NameFlag, Path, RequestNameReply};
+use dbus::tree::{Factory, MethodErr};
+use gettextrs::gettext;
+
+#[cfg(feature = "gui")]
+use std::ffi::CString;
+#[cfg(feature = "gui")]
+use std::os::raw::c_char;
+#[cfg(feature = "gui")]
+use std::sync::Mutex;
+#[cfg(feature = "gui")]
+use gio::prelude::*;
+#[cfg(feature = "gui")]
+use glib::translate::{from_glib_full, from_glib_none, ToGlibPtr};
+#[cfg(feature = "gui")]
+use glib::ToVariant;
+
+use crate::core::thread_messages::SongRecognizedMessage;
+use crate::utils::csv_song_history::SongHistoryRecord;
+
+/// This module exposes a D-Bus service allowing to drive SongRec from scripts
+/// and desktop shortcuts, for example:
+///
+/// ```sh
+/// dbus-send --session --print-reply --dest=com.github.marinm.songrec \
+///     /com/github/marinm/songrec/Control com.github.marinm.songrec.Control.StartListening
+/// ```
+///
+/// In the CLI mode, as for MPRIS, the "com.github.marinm.songrec" name is
+/// owned by a dedicated thread, which receives state updates from the main
+/// loop through a standard channel, and which forwards requests from D-Bus
+/// clients to a callback.
+///
+/// In the GUI mode, the name is already owned by the GTK application (which
+/// uses it to ensure that a single instance is running), so the interface is
+/// exported on the D-Bus connection of the application instead, and served
+/// from the GTK main loop.
+
+const SERVICE_NAME: &str = "com.github.marinm.songrec";
+const SERVICE_OBJECT_PATH: &str = "/com/github/marinm/songrec/Control";
+const SERVICE_INTERFACE: &str = "com.github.marinm.songrec.Control";
+
+// The interface as exported through GIO in the GUI mode, which is the same
+// as the one described with the "dbus" crate below
+
+#[cfg(feature = "gui")]
+const INTROSPECTION_XML: &str = r#"
+<node>
+  <interface name="com.github.marinm.songrec.Control">
+    <method name="StartListening"/>
+    <method name="StopListening"/>
+    <method name="ListDevices">
+      <arg name="devices" type="as" direction="out"/>
+    </method>
+    <method name="SelectDevice">
+      <arg name="device_name" type="s" direction="in"/>
+    </method>
+    <method name="RecognizeFile">
+      <arg name="file_path" type="s" direction="in"/>
+    </method>
+    <method name="GetLastResult">
+      <arg name="shazam_json" type="s" direction="out"/>
+    </method>
+    <method name="GetHistory">
+      <arg name="history_json" type="s" direction="out"/>
+    </method>
+    <property name="Listening" type="b" access="read"/>
+    <property name="CurrentDevice" type="s" access="read"/>
+    <signal name="SongRecognized">
+      <arg name="artist_name" type="s"/>
+      <arg name="song_name" type="s"/>
+      <arg name="album_name" type="s"/>
+      <arg name="track_key" type="s"/>
+      <arg name="shazam_json" type="s"/>
+    </signal>
+    <signal name="Error">
+      <arg name="message" type="s"/>
+    </signal>
+  </interface>
+</node>
+"#;
+
+/// Requests received from D-Bus clients, to be applied by the GUI or CLI
+/// main loop through the usual inter-thread messages.
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DBusControl {
+    StartListening(String), // The argument is the audio device name
+    StopListening,
+    SelectDevice(String, bool), // The device name, and whether listening should be restarted with it
+    RecognizeFile(String)
+}
+
+enum ServiceCommand {
+    SetDevices(Vec<String>),
+    SetCurrentDevice(String),
+    SetListening(bool),
+    SetHistory(Vec<SongHistoryRecord>),
+    SongRecognized(Box<RecognizedSong>),
+    Error(String)
+}
+
+struct RecognizedSong {
+    artist_name: String,
+    song_name: String,
+    album_name: String,
+    track_key: String,
+    shazam_json: String,
+    history_record: SongHistoryRecord
+}
+
+#[derive(Default)]
+struct ServiceState {
+    devices: Vec<String>,
+    current_device: Option<String>,
+    listening: bool,
+    last_result: Option<String>,
+    history: Vec<SongHistoryRecord>
+}
+
+impl ServiceState {
+
+    // Method calls are validated against the current state, returning the
+    // request to pass to the control callback
+
+    fn start_listening(self: &Self) -> Result<DBusControl, MethodErr> {
+        match self.current_device.clone().or_else(|| self.devices.first().cloned()) {
+            Some(device_name) => Ok(DBusControl::StartListening(device_name)),
+            None => Err(MethodErr::failed(&gettext("No audio device available")))
+        }
+    }
+
+    fn select_device(self: &mut Self, device_name: &str) -> Result<DBusControl, MethodErr> {
+        if !self.devices.iter().any(|device| device == device_name) {
+            return Err(MethodErr::invalid_arg(&device_name));
+        }
+
+        self.current_device = Some(device_name.to_string());
+
+        Ok(DBusControl::SelectDevice(device_name.to_string(), self.listening))
+    }
+
+    fn recognize_file(file_path: &str) -> Result<DBusControl, MethodErr> {
+        if !std::path::Path::new(file_path).is_file() {
+            return Err(MethodErr::failed(&format!("{} {}", gettext("No such file:"), file_path)));
+        }
+
+        Ok(DBusControl::RecognizeFile(file_path.to_string()))
+    }
+
+    /// The raw Shazam JSON, or an empty string if nothing was recognized yet.
+
+    fn get_last_result(self: &Self) -> String {
+        self.last_result.clone().unwrap_or_default()
+    }
+
+    /// A JSON array of the history records, in chronological order.
+
+    fn get_history_json(self: &Self) -> Result<String, MethodErr> {
+        serde_json::to_string(&self.history).map_err(|error| MethodErr::failed(&error))
+    }
+
+    /// Apply an update from the GUI or CLI main loop (the signals are
+    /// emitted by the caller).
+
+    fn update(self: &mut Self, command: &ServiceCommand) {
+        match command {
+            ServiceCommand::SetDevices(devices) => {
+                self.devices = devices.clone();
+            },
+            ServiceCommand::SetCurrentDevice(device_name) => {
+                self.current_device = Some(device_name.clone());
+            },
+            ServiceCommand::SetListening(listening) => {
+                self.listening = *listening;
+            },
+            ServiceCommand::SetHistory(records) => {
+                self.history = records.clone();
+            },
+            ServiceCommand::SongRecognized(song) => {
+                self.last_result = Some(song.shazam_json.clone());
+                self.history.push(song.history_record.clone());
+            },
+            ServiceCommand::Error(_) => { }
+        }
+    }
+
+}
+
+/// Handle used to update the state exposed over D-Bus. Dropping every copy
+/// of it unregisters the service.
+
+#[derive(Clone)]
+pub struct DBusService {
+    service_tx: mpsc::Sender<ServiceCommand>
+}
+
+impl DBusService {
+
+    pub fn set_devices(self: &Self, devices: &[String]) {
+        self.service_tx.send(ServiceCommand::SetDevices(devices.to_vec())).ok();
+    }
+
+    /// The device that "StartListening" will use.
+
+    pub fn set_current_device(self: &Self, device_name: &str) {
+        self.service_tx.send(ServiceCommand::SetCurrentDevice(device_name.to_string())).ok();
+    }
+
+    pub fn set_listening(self: &Self, listening: bool) {
+        self.service_tx.send(ServiceCommand::SetListening(listening)).ok();
+    }
+
+    /// Replace the history returned by "GetHistory" (used by the GUI, which
+    /// loads the previous history from the disk).
+
+    pub fn set_history(self: &Self, records: Vec<SongHistoryRecord>) {
+        self.service_tx.send(ServiceCommand::SetHistory(records)).ok();
+    }
+
+    /// Store the result and emit the "SongRecognized" signal.
+
+    pub fn song_recognized(self: &Self, message: &SongRecognizedMessage) {
+        self.service_tx.send(ServiceCommand::SongRecognized(Box::new(RecognizedSong {
+            artist_name: message.artist_name.clone(),
+            song_name: message.song_name.clone(),
+            album_name: message.album_name.clone().unwrap_or_default(),
+            track_key: message.track_key.clone(),
+            shazam_json: message.shazam_json.clone(),
+            history_record: SongHistoryRecord {
+                song_name: format!("{} - {}", message.artist_name, message.song_name),
+                album: message.album_name.clone().unwrap_or_default(),
+                recognition_date: Local::now().format("%c").to_string(),
+                track_key: message.track_key.clone(),
+                release_year: message.release_year.clone().unwrap_or_default(),
+                genre: message.genre.clone().unwrap_or_default(),
+                source: message.source_id.clone().unwrap_or_default()
+            }
+        }))).ok();
+    }
+
+    /// Emit the "Error" signal.
+
+    pub fn error(self: &Self, error_message: &str) {
+        self.service_tx.send(ServiceCommand::Error(error_message.to_string())).ok();
+    }
+
+}
+
+/// Register the service on the session bus, or on the bus at the given
+/// address when one is provided (which allows to run the service on a
+/// private bus, for example one launched with "dbus-daemon --session
+/// --print-address"). Returns None if D-Bus is not available or if the
+/// name is already taken. The control callback is called from the D-Bus
+/// thread.
+
+pub fn start_dbus_service<F>(bus_address: Option<String>, control_callback: F) -> Option<DBusService>
+    where F: Fn(DBusControl) + Send + 'static {
+
+    let (service_tx, service_rx) = mpsc::channel();
+    let (ready_tx, ready_rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let connection = match register_connection(bus_address.as_deref()) {
+            Ok(connection) => connection,
+            Err(error) => {
+                eprintln!("{} {}", gettext("Unable to register the D-Bus service:"), error);
+                ready_tx.send(false).unwrap();
+                return;
+            }
+        };
+        ready_tx.send(true).unwrap();
+
+        service_thread(connection, service_rx, control_callback);
+    });
+
+    match ready_rx.recv() {
+        Ok(true) => Some(DBusService { service_tx }),
+        _ => None
+    }
+}
+
+/// Export the interface on the D-Bus connection of the GTK application,
+/// which owns the name of the service, serving it from the GTK main loop.
+/// Returns None if the application isn't registered on the session bus.
+
+#[cfg(feature = "gui")]
+pub fn export_dbus_service<P, F>(application: &P, control_callback: F) -> Option<DBusService>
+    where P: IsA<gio::Application>, F: Fn(DBusControl) + Send + 'static {
+
+    let connection = application.get_dbus_connection()?;
+
+    let node_info = gio::DBusNodeInfo::new_for_xml(INTROSPECTION_XML).ok()?;
+    let interface_info = node_info.lookup_interface(SERVICE_INTERFACE)?;
+
+    // GIO requires the callbacks to be thread-safe, though they are called
+    // from the GTK main loop
+
+    let state = Arc::new(Mutex::new(ServiceState::default()));
+    let (state_1, state_2) = (state.clone(), state.clone());
+
+    let control_callback = Mutex::new(control_callback);
+
+    let registration_id = connection.register_object(SERVICE_OBJECT_PATH, &interface_info,
+        move |_connection, _sender, _object_path, _interface_name, method_name, parameters, invocation| {
+            let mut state = state_1.lock().unwrap();
+
+            let reply: Result<Option<glib::Variant>, MethodErr> = match method_name {
+                "StartListening" => state.start_listening().map(|control| {
+                    (control_callback.lock().unwrap())(control);
+                    None
+                }),
+                "StopListening" => {
+                    (control_callback.lock().unwrap())(DBusControl::StopListening);
+                    Ok(None)
+                },
+                "ListDevices" => Ok(Some(make_tuple_variant(&[make_string_array_variant(&state.devices)]))),
+                "SelectDevice" => state.select_device(&get_string_argument(&parameters)).map(|control| {
+                    (control_callback.lock().unwrap())(control);
+                    None
+                }),
+                "RecognizeFile" => ServiceState::recognize_file(&get_string_argument(&parameters)).map(|control| {
+                    (control_callback.lock().unwrap())(control);
+                    None
+                }),
+                "GetLastResult" => Ok(Some(make_tuple_variant(&[state.get_last_result().to_variant()]))),
+                "GetHistory" => state.get_history_json().map(|history| Some(make_tuple_variant(&[history.to_variant()]))),
+                _ => Err(MethodErr::no_method(&method_name))
+            };
+
+            match reply {
+                Ok(reply) => invocation.return_value(reply.as_ref()),
+                Err(error) => invocation.return_dbus_error(error.errorname(), error.description())
+            };
+        },
+        move |_connection, _sender, _object_path, _interface_name, property_name| {
+            let state = state_2.lock().unwrap();
+
+            match property_name {
+                "Listening" => state.listening.to_variant(),
+                _ => state.current_device.clone().unwrap_or_default().to_variant()
+            }
+        },
+        |_connection, _sender, _object_path, _interface_name, _property_name, _value| false
+    ).ok()?;
+
+    // State updates are applied from the GTK main loop, as often as the
+    // D-Bus thread of the CLI mode does
+
+    let (service_tx, service_rx) = mpsc::channel::<ServiceCommand>();
+
+    let mut registration_id = Some(registration_id);
+
+    glib::timeout_add_local(100, move || {
+        loop {
+            match service_rx.try_recv() {
+                Ok(command) => {
+                    state.lock().unwrap().update(&command);
+
+                    let signal = match command {
+                        ServiceCommand::SongRecognized(song) => Some(("SongRecognized", make_tuple_variant(&[
+                            song.artist_name.to_variant(),
+                            song.song_name.to_variant(),
+                            song.album_name.to_variant(),
+                            song.track_key.to_variant(),
+                            song.shazam_json.to_variant()
+                        ]))),
+                        ServiceCommand::Error(error_message) => Some(("Error", make_tuple_variant(&[error_message.to_variant()]))),
+                        _ => None
+                    };
+
+                    if let Some((signal_name, parameters)) = signal {
+                        connection.emit_signal(None, SERVICE_OBJECT_PATH, SERVICE_INTERFACE, signal_name, Some(&parameters)).ok();
+                    }
+                },
+                Err(mpsc::TryRecvError::Empty) => return glib::Continue(true),
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    if let Some(registration_id) = registration_id.take() {
+                        connection.unregister_object(registration_id).ok();
+                    }
+                    return glib::Continue(false);
+                }
+            };
+        }
+    });
+
+    Some(DBusService { service_tx })
+}
+
+// The method arguments, replies and signals of GIO are GVariant tuples,
+// which the version of the GLib bindings in use can't make
+
+#[cfg(feature = "gui")]
+fn make_tuple_variant(children: &[glib::Variant]) -> glib::Variant {
+    let children: Vec<*mut glib_sys::GVariant> = children.iter().map(|child| child.to_glib_none().0).collect();
+
+    unsafe { from_glib_none(glib_sys::g_variant_new_tuple(children.as_ptr(), children.len())) }
+}
+
+#[cfg(feature = "gui")]
+fn make_string_array_variant(strings: &[String]) -> glib::Variant {
+    let strings: Vec<CString> = strings.iter().map(|string| CString::new(string.as_str()).unwrap_or_default()).collect();
+    let pointers: Vec<*const c_char> = strings.iter().map(|string| string.as_ptr()).collect();
+
+    unsafe { from_glib_none(glib_sys::g_variant_new_strv(pointers.as_ptr(), pointers.len() as _)) }
+}
+
+/// Read the single string argument of a method.
+
+#[cfg(feature = "gui")]
+fn get_string_argument(parameters: &glib::Variant) -> String {
+    let argument: glib::Variant = unsafe { from_glib_full(glib_sys::g_variant_get_child_value(parameters.to_glib_none().0, 0)) };
+
+    argument.get_str().unwrap_or_default().to_string()
+}
+
+fn register_connection(bus_address: Option<&str>) -> Result<Connection, String> {
+    let connection = match bus_address {
+        Some(address) => {
+            let connection = Connection::open_private(address).map_err(|error| error.to_string())?;
+            connection.register().map_err(|error| error.to_string())?;
+            connection
+        },
+        None => Connection::get_private(BusType::Session).map_err(|error| error.to_string())?
+    };
+
+    match connection.register_name(SERVICE_NAME, NameFlag::DoNotQueue as u32).map_err(|error| error.to_string())? {
+        RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner => Ok(connection),
+        _ => Err(gettext("The D-Bus name is already taken by another process"))
+    }
+}
+
+fn service_thread<F>(connection: Connection, service_rx: mpsc::Receiver<ServiceCommand>, control_callback: F)
+    where F: Fn(DBusControl) + 'static {
+
+    let state = Rc::new(RefCell::new(ServiceState::default()));
+
+    // The loop below doesn't get back control while method calls keep
+    // coming, so that their resulting requests are passed to the callback
+    // right away
+
+    let control_callback = Rc::new(control_callback);
+
+    let factory = Factory::new_fn::<()>();
+
+    let song_recognized_signal = Arc::new(factory.signal("SongRecognized", ())
+        .sarg::<&str, _>("artist_name")
+        .sarg::<&str, _>("song_name")
+        .sarg::<&str, _>("album_name")
+        .sarg::<&str, _>("track_key")
+        .sarg::<&str, _>("shazam_json"));
+
+    let error_signal = Arc::new(factory.signal("Error", ())
+        .sarg::<&str, _>("message"));
+
+    let (state_1, state_2, state_3, state_4, state_5, state_6, state_7) =
+        (state.clone(), state.clone(), state.clone(), state.clone(), state.clone(), state.clone(), state.clone());
+    let (control_callback_1, control_callback_2, control_callback_3, control_callback_4) =
+        (control_callback.clone(), control_callback.clone(), control_callback.clone(), control_callback.clone());
+
+    let interface = factory.interface(SERVICE_INTERFACE, ())
+        .add_m(factory.method("StartListening", (), move |method_info| {
+            let control = state_1.borrow().start_listening()?;
+            control_callback_1(control);
+            Ok(vec![method_info.msg.method_return()])
+        }))
+        .add_m(factory.method("StopListening", (), move |method_info| {
+            control_callback_2(DBusControl::StopListening);
+            Ok(vec![method_info.msg.method_return()])
+        }))
+        .add_m(factory.method("ListDevices", (), move |method_info| {
+            Ok(vec![method_info.msg.method_return().append1(state_2.borrow().devices.clone())])
+        }).outarg::<Vec<&str>, _>("devices"))
+        .add_m(factory.method("SelectDevice", (), move |method_info| {
+            let device_name: &str = method_info.msg.read1()?;
+
+            let control = state_3.borrow_mut().select_device(device_name)?;
+            control_callback_3(control);
+            Ok(vec![method_info.msg.method_return()])
+        }).inarg::<&str, _>("device_name"))
+        .add_m(factory.method("RecognizeFile", (), move |method_info| {
+            let file_path: &str = method_info.msg.read1()?;
+
+            control_callback_4(ServiceState::recognize_file(file_path)?);
+            Ok(vec![method_info.msg.method_return()])
+        }).inarg::<&str, _>("file_path"))
+        .add_m(factory.method("GetLastResult", (), move |method_info| {
+            Ok(vec![method_info.msg.method_return().append1(state_4.borrow().get_last_result())])
+        }).outarg::<&str, _>("shazam_json"))
+        .add_m(factory.method("GetHistory", (), move |method_info| {
+            Ok(vec![method_info.msg.method_return().append1(state_5.borrow().get_history_json()?)])
+        }).outarg::<&str, _>("history_json"))
+        .add_p(factory.property::<bool, _>("Listening", ()).on_get(move |i, _| {
+            i.append(state_6.borrow().listening);
+            Ok(())
+        }))
+        .add_p(factory.property::<&str, _>("CurrentDevice", ()).on_get(move |i, _| {
+            i.append(state_7.borrow().current_device.clone().unwrap_or_default());
+            Ok(())
+        }))
+        .add_s(song_recognized_signal.clone())
+        .add_s(error_signal.clone());
+
+    let tree = factory.tree(()).add(
+        factory.object_path(SERVICE_OBJECT_PATH, ())
+            .introspectable()
+            .add(interface)
+    );
+
+    if tree.set_registered(&connection, true).is_err() {
+        return;
+    }
+    connection.add_handler(tree);
+
+    let object_path = Path::new(SERVICE_OBJECT_PATH).unwrap();
+    let interface_name = Interface::new(SERVICE_INTERFACE).unwrap();
+
+    loop {
+        connection.incoming(100).next();
+
+        loop {
+            match service_rx.try_recv() {
+                Ok(command) => {
+                    state.borrow_mut().update(&command);
+
+                    match command {
+                        ServiceCommand::SongRecognized(song) => {
+                            connection.send(song_recognized_signal.msg(&object_path, &interface_name)
+                                .append3(&song.artist_name, &song.song_name, &song.album_name)
+                                .append2(&song.track_key, &song.shazam_json)).ok();
+                        },
+                        ServiceCommand::Error(error_message) => {
+                            connection.send(error_signal.msg(&object_path, &interface_name).append1(&error_message)).ok();
+                        },
+                        _ => { }
+                    };
+                },
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => return
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::time::{Duration, Instant};
+    use std::collections::HashMap;
+    use dbus::{arg, Message, MessageType};
+    use crate::fingerprinting::signature_format::DecodedSignature;
+
+    const TIMEOUT_MILLISECONDS: i32 = 2000;
+
+    /// A bus of our own, stopped when this is dropped.
+
+    struct PrivateBus {
+        dbus_daemon: Child,
+        address: String
+    }
+
+    impl PrivateBus {
+        fn start() -> Option<Self> {
+            let mut dbus_daemon = match Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn() {
+                Ok(dbus_daemon) => dbus_daemon,
+                Err(ref error) if error.kind() == std::io::ErrorKind::NotFound => return None,
+                Err(error) => panic!("Unable to start dbus-daemon: {}", error)
+            };
+
+            let mut address = String::new();
+            BufReader::new(dbus_daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+
+            Some(PrivateBus { dbus_daemon, address: address.trim().to_string() })
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(self: &mut Self) {
+            self.dbus_daemon.kill().ok();
+            self.dbus_daemon.wait().ok();
+        }
+    }
+
+    fn call(connection: &Connection, interface: &str, method: &str, arguments: &[&str]) -> Result<Message, dbus::Error> {
+        let mut message = Message::new_method_call(SERVICE_NAME, SERVICE_OBJECT_PATH, interface, method).unwrap();
+        for argument in arguments {
+            message = message.append1(*argument);
+        }
+        connection.send_with_reply_and_block(message, TIMEOUT_MILLISECONDS)
+    }
+
+    fn get_listening(connection: &Connection) -> bool {
+        let reply = call(connection, "org.freedesktop.DBus.Properties", "Get", &[SERVICE_INTERFACE, "Listening"]).unwrap();
+        reply.get1::<arg::Variant<bool>>().unwrap().0
+    }
+
+    fn get_current_device(connection: &Connection) -> String {
+        let reply = call(connection, "org.freedesktop.DBus.Properties", "Get", &[SERVICE_INTERFACE, "CurrentDevice"]).unwrap();
+        reply.get1::<arg::Variant<String>>().unwrap().0
+    }
+
+    fn get_string(connection: &Connection, method: &str) -> String {
+        call(connection, SERVICE_INTERFACE, method, &[]).unwrap().get1::<String>().unwrap()
+    }
+
+    fn make_song_recognized_message() -> SongRecognizedMessage {
+        SongRecognizedMessage {
+            artist_name: "Stupeflip".to_string(),
+            album_name: None,
+            song_name: "Stupeflip Vite!!!".to_string(),
+            cover_image: None,
+            signature: Box::new(DecodedSignature {
+                sample_rate_hz: 16000,
+                number_samples: 0,
+                frequency_band_to_sound_peaks: HashMap::new()
+            }),
+            track_key: "48374157".to_string(),
+            release_year: None,
+            genre: None,
+            track_url: None,
+            shazam_json: r#"{"track":{"key":"48374157"}}"#.to_string(),
+            source_id: None
+        }
+    }
+
+    fn wait_for_signal(connection: &Connection, member: &str) -> Message {
+        let start = Instant::now();
+
+        while start.elapsed() < Duration::from_secs(5) {
+            for message in connection.incoming(200) {
+                if message.msg_type() == MessageType::Signal && message.member().as_deref() == Some(member) {
+                    return message;
+                }
+            }
+        }
+
+        panic!("the {} signal wasn't received", member);
+    }
+
+    /// Wait for the service thread to apply an update (which it does once no
+    /// method call has come for 100 ms).
+
+    fn wait_until<F: Fn() -> bool>(condition: F) {
+        let start = Instant::now();
+
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(5), "the service wasn't updated");
+            thread::sleep(Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn service_on_private_bus() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => {
+                eprintln!("dbus-daemon is not installed, skipping the test");
+                return;
+            }
+        };
+
+        let (control_tx, control_rx) = mpsc::channel();
+
+        let service = start_dbus_service(Some(bus.address.clone()), move |control| { control_tx.send(control).unwrap(); }).unwrap();
+
+        let client = Connection::open_private(&bus.address).unwrap();
+        client.register().unwrap();
+
+        // Listening can't start until a device is known
+
+        assert!(call(&client, SERVICE_INTERFACE, "StartListening", &[]).is_err());
+
+        service.set_devices(&["Test device".to_string()]);
+        wait_until(|| call(&client, SERVICE_INTERFACE, "StartListening", &[]).is_ok());
+
+        assert_eq!(control_rx.recv_timeout(Duration::from_secs(5)).unwrap(), DBusControl::StartListening("Test device".to_string()));
+
+        assert!(!get_listening(&client));
+        service.set_listening(true);
+        wait_until(|| get_listening(&client));
+
+        call(&client, SERVICE_INTERFACE, "StopListening", &[]).unwrap();
+
+        assert_eq!(control_rx.recv_timeout(Duration::from_secs(5)).unwrap(), DBusControl::StopListening);
+
+        service.set_listening(false);
+        wait_until(|| !get_listening(&client));
+
+        // Only known devices can be selected, listening is restarted with
+        // them if it was ongoing
+
+        service.set_devices(&["Test device".to_string(), "Other device".to_string()]);
+        wait_until(|| call(&client, SERVICE_INTERFACE, "SelectDevice", &["Other device"]).is_ok());
+
+        assert_eq!(control_rx.recv_timeout(Duration::from_secs(5)).unwrap(), DBusControl::SelectDevice("Other device".to_string(), false));
+        assert_eq!(get_current_device(&client), "Other device");
+
+        assert!(call(&client, SERVICE_INTERFACE, "SelectDevice", &["Unknown device"]).is_err());
+
+        service.set_listening(true);
+        wait_until(|| get_listening(&client));
+
+        call(&client, SERVICE_INTERFACE, "SelectDevice", &["Test device"]).unwrap();
+        assert_eq!(control_rx.recv_timeout(Duration::from_secs(5)).unwrap(), DBusControl::SelectDevice("Test device".to_string(), true));
+
+        // Recognitions are stored and signaled
+
+        assert_eq!(get_string(&client, "GetLastResult"), "");
+        assert_eq!(get_string(&client, "GetHistory"), "[]");
+
+        client.add_match(&format!("type='signal',interface='{}'", SERVICE_INTERFACE)).unwrap();
+
+        service.song_recognized(&make_song_recognized_message());
+
+        let signal = wait_for_signal(&client, "SongRecognized");
+        let (artist_name, song_name, album_name, track_key, shazam_json): (&str, &str, &str, &str, &str) = signal.read5().unwrap();
+        assert_eq!((artist_name, song_name, album_name, track_key), ("Stupeflip", "Stupeflip Vite!!!", "", "48374157"));
+        assert_eq!(shazam_json, r#"{"track":{"key":"48374157"}}"#);
+
+        assert_eq!(get_string(&client, "GetLastResult"), r#"{"track":{"key":"48374157"}}"#);
+
+        let history: serde_json::Value = serde_json::from_str(&get_string(&client, "GetHistory")).unwrap();
+        assert_eq!(history.as_array().unwrap().len(), 1);
+        assert_eq!(history[0]["song_name"], "Stupeflip - Stupeflip Vite!!!");
+        assert_eq!(history[0]["track_key"], "48374157");
+
+        service.error("No match for this song");
+
+        let signal = wait_for_signal(&client, "Error");
+        assert_eq!(signal.read1::<&str>().unwrap(), "No match for this song");
+    }
+
+}
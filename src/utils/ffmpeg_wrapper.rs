@@ -1,92 +1,273 @@
-
-use std::io::BufReader;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use gettextrs::gettext;
 
 #[cfg(windows)]
 use std::os::windows::process::CommandExt;
 
-use std::process::Command;
+/// This module runs FFMpeg, if it is installed on the system, to decode the
+/// formats that can't be decoded natively (such as .WMA, .OPUS without the
+/// "opus" cargo feature, or video files), and to encode recordings and lures to .FLAC or .OGG.
+///
+/// The FFMpeg binary is looked up once per process (unless an explicit path
+/// was set with the "--ffmpeg-path" option), every run is bounded by a
+/// timeout after which FFMpeg is killed, and failures carry the end of what
+/// FFMpeg wrote to its error output.
+
+const DEFAULT_TIMEOUT_SECONDS: u64 = 120;
+
+const POLLING_INTERVAL: Duration = Duration::from_millis(20);
+
+const STDERR_TAIL_MAXIMUM_LINES: usize = 10;
+
+// How long to wait for the rest of the error output once FFMpeg exited (it
+// may stay open if FFMpeg is a wrapper script which left a child behind)
+
+const STDERR_GRACE_PERIOD: Duration = Duration::from_millis(500);
+
+static FFMPEG_PATH: OnceLock<Option<String>> = OnceLock::new();
+
+#[derive(Debug)]
+pub enum FfmpegError {
+    NotFound,
+    SpawnFailed(String, String), // The path of the binary, and the I/O error
+    TimedOut(Duration, String), // The timeout, and the tail of the error output
+    Failed(Option<i32>, String), // The exit code (none if killed by a signal), and the tail of the error output
+    Io(String) // Failure to create the temporary file, or to read back the output
+}
+
+impl Error for FfmpegError {}
+
+impl fmt::Display for FfmpegError {
+    fn fmt(self: &Self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let (message, stderr_tail) = match self {
+            FfmpegError::NotFound => (gettext("FFMpeg was not found (is it installed?)"), None),
+            FfmpegError::SpawnFailed(path, error) => (format!("{} {}: {}", gettext("Unable to run FFMpeg at"), path, error), None),
+            FfmpegError::TimedOut(timeout, stderr_tail) => (format!("{} {} {}", gettext("FFMpeg was killed after running for"), timeout.as_secs(), gettext("seconds")), Some(stderr_tail)),
+            FfmpegError::Failed(Some(code), stderr_tail) => (format!("{} {}", gettext("FFMpeg failed with exit code"), code), Some(stderr_tail)),
+            FfmpegError::Failed(None, stderr_tail) => (gettext("FFMpeg was terminated by a signal"), Some(stderr_tail)),
+            FfmpegError::Io(error) => (format!("{} {}", gettext("Unable to exchange audio with FFMpeg:"), error), None)
+        };
+
+        match stderr_tail {
+            Some(stderr_tail) if !stderr_tail.is_empty() => write!(formatter, "{}:\n{}", message, stderr_tail),
+            _ => write!(formatter, "{}", message)
+        }
+    }
+}
 
-/// This function used to decode a file with FFMpeg, if it is installed on
-/// the system, in the case where Rodio can't decode the concerned format
-/// (for example with .WMA, .M4A, etc.).
+/// Set the FFMpeg binary to use, instead of looking it up. This should be
+/// called before anything runs FFMpeg, and returns false otherwise.
+
+pub fn set_ffmpeg_path(ffmpeg_path: &str) -> bool {
+
+    FFMPEG_PATH.set(Some(ffmpeg_path.to_string())).is_ok()
+
+}
+
+pub fn is_ffmpeg_available() -> bool {
+
+    get_ffmpeg_path().is_some()
+
+}
+
+#[derive(Clone, Debug)]
+pub struct FfmpegDecoder {
+    ffmpeg_path: Option<String>, // Looked up (once per process) when not given
+    timeout: Duration
+}
+
+impl Default for FfmpegDecoder {
+    fn default() -> Self {
+        FfmpegDecoder {
+            ffmpeg_path: None,
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECONDS)
+        }
+    }
+}
+
+impl FfmpegDecoder {
+
+    pub fn new() -> Self {
+        FfmpegDecoder::default()
+    }
+
+    pub fn with_ffmpeg_path(self: Self, ffmpeg_path: &str) -> Self {
+        FfmpegDecoder { ffmpeg_path: Some(ffmpeg_path.to_string()), ..self }
+    }
+
+    pub fn with_timeout(self: Self, timeout: Duration) -> Self {
+        FfmpegDecoder { timeout, ..self }
+    }
+
+    /// Decode any audio or video file supported by FFMpeg, through a
+    /// temporary .WAV s16le PCM file that is then passed to Rodio.
+
+    pub fn decode(self: &Self, file_path: &str) -> Result<rodio::Decoder<BufReader<File>>, FfmpegError> {
+
+        let sink_file_path = tempfile::Builder::new().suffix(".wav").tempfile()
+            .map_err(|error| FfmpegError::Io(error.to_string()))?
+            .into_temp_path();
+
+        self.run(&["-y", "-i", file_path, "-vn", &sink_file_path.to_string_lossy()])?;
+
+        // The temporary file is removed when returning, but stays readable
+        // through the file handle given to Rodio
+
+        let sink_file = File::open(&sink_file_path).map_err(|error| FfmpegError::Io(error.to_string()))?;
+
+        rodio::Decoder::new(BufReader::new(sink_file)).map_err(|error| FfmpegError::Io(error.to_string()))
+
+    }
+
+    /// Convert an audio file to another format, deduced by FFMpeg from the
+    /// extension of the output file.
+
+    pub fn convert(self: &Self, input_file_path: &str, output_file_path: &str) -> Result<(), FfmpegError> {
+
+        self.run(&["-y", "-i", input_file_path, output_file_path])
+
+    }
+
+    fn run(self: &Self, arguments: &[&str]) -> Result<(), FfmpegError> {
+
+        let ffmpeg_path = match &self.ffmpeg_path {
+            Some(ffmpeg_path) => ffmpeg_path.clone(),
+            None => get_ffmpeg_path().ok_or(FfmpegError::NotFound)?
+        };
+
+        let mut command = Command::new(&ffmpeg_path);
+
+        let command = command.args(["-hide_banner", "-nostdin", "-loglevel", "error"])
+            .args(arguments)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+
+        #[cfg(windows)]
+        let command = command.creation_flags(0x00000008); // Set "CREATE_NO_WINDOW" on Windows
 
-pub fn decode_with_ffmpeg(file_path: &str) -> Option<rodio::Decoder<BufReader<std::fs::File>>> {
+        let mut child = ChildGuard(command.spawn().map_err(|error| FfmpegError::SpawnFailed(ffmpeg_path.clone(), error.to_string()))?);
+
+        // Read the error output from another thread, so that FFMpeg never
+        // blocks on a full pipe while it is being waited for
+
+        let mut stderr = child.0.stderr.take().unwrap();
+
+        let stderr_output: Arc<Mutex<Vec<u8>>> = Arc::new(Mutex::new(vec![]));
+        let stderr_output_2 = stderr_output.clone();
+
+        let stderr_thread = thread::spawn(move || {
+            let mut buffer = [0u8; 4096];
+
+            while let Ok(length @ 1..) = stderr.read(&mut buffer) {
+                stderr_output_2.lock().unwrap().extend_from_slice(&buffer[..length]);
+            }
+        });
+
+        let start = Instant::now();
+
+        let status = loop {
+            match child.0.try_wait() {
+                Ok(Some(status)) => break Some(status),
+                Ok(None) if start.elapsed() >= self.timeout => break None,
+                Ok(None) => thread::sleep(POLLING_INTERVAL),
+                Err(error) => { return Err(FfmpegError::Io(error.to_string())); }
+            }
+        };
+
+        // On timeout, dropping the guard kills FFMpeg, which closes its error
+        // output and lets the reading thread finish
+
+        drop(child);
+
+        let exit_time = Instant::now();
+
+        while !stderr_thread.is_finished() && exit_time.elapsed() < STDERR_GRACE_PERIOD {
+            thread::sleep(POLLING_INTERVAL);
+        }
+
+        let stderr_tail = get_stderr_tail(&stderr_output.lock().unwrap());
+
+        match status {
+            Some(status) if status.success() => Ok(()),
+            Some(status) => Err(FfmpegError::Failed(status.code(), stderr_tail)),
+            None => Err(FfmpegError::TimedOut(self.timeout, stderr_tail))
+        }
+
+    }
+
+}
+
+/// Kill the FFMpeg process if it is still running when dropped (after a
+/// timeout, or if the calling thread panics), and reap it.
+
+struct ChildGuard(Child);
+
+impl Drop for ChildGuard {
+    fn drop(self: &mut Self) {
+        if let Ok(None) = self.0.try_wait() {
+            let _ = self.0.kill();
+        }
+
+        let _ = self.0.wait();
+    }
+}
+
+fn get_stderr_tail(stderr: &[u8]) -> String {
+
+    let stderr = String::from_utf8_lossy(stderr);
+
+    let lines: Vec<&str> = stderr.lines().filter(|line| !line.trim().is_empty()).collect();
+
+    lines[lines.len().saturating_sub(STDERR_TAIL_MAXIMUM_LINES)..].join("\n")
+
+}
+
+fn get_ffmpeg_path() -> Option<String> {
+
+    FFMPEG_PATH.get_or_init(find_ffmpeg_path).clone()
+
+}
+
+fn find_ffmpeg_path() -> Option<String> {
 
     // Find the path for FFMpeg, in the case where it is installed
-    
-    let mut possible_ffmpeg_paths: Vec<&str> = vec!["ffmpeg", "ffmpeg.exe"];
-    
-    let mut current_dir_ffmpeg_path = std::env::current_exe().unwrap();
-    current_dir_ffmpeg_path.pop();
-    current_dir_ffmpeg_path.push("ffmpeg.exe");
-    
-    possible_ffmpeg_paths.push(current_dir_ffmpeg_path.to_str().unwrap());
-    
-    let mut actual_ffmpeg_path: Option<&str> = None;
-    
+
+    let mut possible_ffmpeg_paths: Vec<String> = vec!["ffmpeg".to_string(), "ffmpeg.exe".to_string()];
+
+    if let Ok(mut current_dir_ffmpeg_path) = std::env::current_exe() {
+        current_dir_ffmpeg_path.pop();
+        current_dir_ffmpeg_path.push("ffmpeg.exe");
+
+        possible_ffmpeg_paths.push(current_dir_ffmpeg_path.to_string_lossy().to_string());
+    }
+
     for possible_path in possible_ffmpeg_paths {
-        
+
         // Use .output() to execute the subprocess testing for FFMpeg
         // presence and correct execution, so that it does not pollute
         // the standard or error output in any way
-        
-        let mut command = Command::new(possible_path);
+
+        let mut command = Command::new(&possible_path);
         let command = command.arg("-version");
-        
+
         #[cfg(windows)]
         let command = command.creation_flags(0x00000008); // Set "CREATE_NO_WINDOW" on Windows
-        
-        if let Ok(process) = command.output() {
-            if process.status.success() {
-                actual_ffmpeg_path = Some(possible_path);
-                break;
-            }
-        }
-        
-    }
-    
-    // If FFMpeg is available, use it to convert the input file
-    // from whichever format to a .WAV (because Rodio has its
-    // decoding support limited to .WAV, .FLAC, .OGG, .MP3, which
-    // makes that .MP4/.AAC, .OPUS or .WMA are not supported, and
-    // Rodio's minimp3 .MP3 decoder seems to crash on Windows anyway)
-    
-    if let Some(ffmpeg_path) = actual_ffmpeg_path {
-        
-        // Create a sink file for FFMpeg
-        
-        let sink_file = tempfile::Builder::new().suffix(".wav").tempfile().unwrap();
-        
-        let sink_file_path = sink_file.into_temp_path();
-        
-        // Try to convert the input video or audio file to a standard
-        // .WAV s16le PCM file using FFMpeg, and pass it to Rodio
-        // later in the case where it succeeded
-        
-        let mut command = Command::new(ffmpeg_path);
-        
-        let command = command.args(&["-y", "-i", file_path,
-            sink_file_path.to_str().unwrap()]);
-        
-        // Set "CREATE_NO_WINDOW" on Windows, see
-        // https://stackoverflow.com/a/60958956/662399
-        #[cfg(windows)]
-        let command = command.creation_flags(0x00000008);
-        
+
         if let Ok(process) = command.output() {
-            
             if process.status.success() {
-                return Some(rodio::Decoder::new(
-                    BufReader::new(
-                        std::fs::File::open(
-                            sink_file_path.to_str().unwrap()
-                        ).unwrap()
-                    )
-                ).unwrap());
+                return Some(possible_path);
             }
         }
-        
+
     }
-    
+
     None
 }
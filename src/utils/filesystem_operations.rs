@@ -16,6 +16,13 @@ pub fn obtain_song_history_csv_path() -> Result<String, Box<dyn Error>> {
     Ok(csv_path.to_str().unwrap().to_string())
 }
 
+pub fn obtain_recordings_directory_path() -> Result<String, Box<dyn Error>> {
+    let project_dir = ProjectDirs::from(QUALIFIER, ORGANIZATION, APPLICATION).ok_or("No valid path")?;
+    let mut recordings_path: PathBuf = obtain_data_directory(project_dir)?;
+    recordings_path.push("recordings");
+    Ok(recordings_path.to_str().unwrap().to_string())
+}
+
 pub fn obtain_preferences_file_path() -> Result<String, Box<dyn Error>> {
     let project_dir = ProjectDirs::from(QUALIFIER, ORGANIZATION, APPLICATION).ok_or("No valid path")?;
     let mut preferences_file_path: PathBuf = obtain_preferences_directory(project_dir)?;
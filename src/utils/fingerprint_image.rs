@@ -0,0 +1,364 @@
+use std::error::Error;
+use std::fmt::Write;
+use byteorder::{BigEndian, WriteBytesExt};
+use crc32fast::Hasher;
+use gettextrs::gettext;
+
+use crate::fingerprinting::algorithm::Spectrogram;
+use crate::fingerprinting::signature_format::{DecodedSignature, FrequencyBand, FrequencyPeak};
+
+/// This module renders a signature as an image, for understanding why a
+/// recognition failed: the frequency peaks are drawn as dots colored by
+/// frequency band over the spectrogram of the audio when it is available
+/// (black to white for weak to strong FFT magnitudes), with time from left to
+/// right and frequencies from bottom to top.
+///
+/// One column of pixels is drawn per FFT pass (8 ms) and one row per 7.8 Hz
+/// (an FFT bin at 16 KHz), up to 6 KHz. PNG images have no text, SVG images
+/// have axis labels and a tooltip for each peak.
+
+const SECONDS_PER_COLUMN: f32 = 128.0 / 16000.0;
+const HZ_PER_ROW: f32 = 16000.0 / 2048.0;
+const HEIGHT: usize = 768; // Up to 6 KHz
+
+const BAND_BOUNDARIES_HZ: [u32; 5] = [250, 520, 1450, 3500, 5500];
+
+// Palette of the rendered images: shades of gray for the spectrogram,
+// followed by the color of the band boundaries and the colors of bands
+
+const SPECTROGRAM_LEVELS: usize = 240;
+const BOUNDARY_COLOR: u8 = 240;
+const BAND_COLORS: [[u8; 3]; 4] = [
+    [230, 25, 75], // 250-520 Hz: red
+    [60, 180, 75], // 520-1450 Hz: green
+    [0, 130, 200], // 1450-3500 Hz: blue
+    [245, 130, 48] // 3500-5500 Hz: orange
+];
+
+// The dynamic range shown on the spectrogram, in nepers of power below the
+// strongest magnitude (12 nepers is about 52 dB)
+
+const SPECTROGRAM_RANGE: f32 = 12.0;
+
+// Room left around the SVG drawing for the labels
+
+const SVG_LEFT_MARGIN: usize = 70;
+const SVG_BOTTOM_MARGIN: usize = 30;
+const SVG_TOP_MARGIN: usize = 30;
+
+pub enum ImageFormat {
+    Png,
+    Svg
+}
+
+impl ImageFormat {
+
+    pub fn from_file_path(file_path: &str) -> Result<Self, Box<dyn Error>> {
+        match std::path::Path::new(file_path).extension().and_then(|extension| extension.to_str()).map(|extension| extension.to_lowercase()).as_deref() {
+            Some("png") => Ok(ImageFormat::Png),
+            Some("svg") => Ok(ImageFormat::Svg),
+            _ => Err(gettext("The image file name should end with .png or .svg").into())
+        }
+    }
+
+}
+
+/// Write the image of a signature to a PNG or SVG file, depending on its
+/// extension.
+
+pub fn save_fingerprint_image(file_path: &str, signature: &DecodedSignature, spectrogram: Option<&Spectrogram>) -> Result<(), Box<dyn Error>> {
+    match ImageFormat::from_file_path(file_path)? {
+        ImageFormat::Png => std::fs::write(file_path, render_png(signature, spectrogram))?,
+        ImageFormat::Svg => std::fs::write(file_path, render_svg(signature, spectrogram))?
+    };
+
+    Ok(())
+}
+
+pub fn render_png(signature: &DecodedSignature, spectrogram: Option<&Spectrogram>) -> Vec<u8> {
+    let mut canvas = Canvas::new(get_width(signature, spectrogram));
+
+    if let Some(spectrogram) = spectrogram {
+        canvas.draw_spectrogram(spectrogram);
+    }
+
+    canvas.draw_band_boundaries();
+
+    for (frequency_band, frequency_peaks) in signature.frequency_band_to_sound_peaks.iter() {
+        for frequency_peak in frequency_peaks {
+            canvas.draw_peak(frequency_peak, SPECTROGRAM_LEVELS as u8 + 1 + *frequency_band as u8);
+        }
+    }
+
+    canvas.encode_png()
+}
+
+pub fn render_svg(signature: &DecodedSignature, spectrogram: Option<&Spectrogram>) -> String {
+    let width = get_width(signature, spectrogram);
+
+    let total_width = SVG_LEFT_MARGIN + width + 10;
+    let total_height = SVG_TOP_MARGIN + HEIGHT + SVG_BOTTOM_MARGIN;
+
+    let mut svg = String::new();
+
+    // Writing to a String can't fail
+
+    writeln!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="{}" height="{}" viewBox="0 0 {} {}" font-family="sans-serif" font-size="11">"#, total_width, total_height, total_width, total_height).unwrap();
+    writeln!(svg, r#"<rect width="100%" height="100%" fill="white"/>"#).unwrap();
+    writeln!(svg, r#"<g transform="translate({} {})">"#, SVG_LEFT_MARGIN, SVG_TOP_MARGIN).unwrap();
+    writeln!(svg, r#"<rect width="{}" height="{}" fill="black"/>"#, width, HEIGHT).unwrap();
+
+    // The spectrogram is embedded as a PNG image, as it would take a
+    // rectangle per pixel otherwise
+
+    if let Some(spectrogram) = spectrogram {
+        let mut canvas = Canvas::new(width);
+        canvas.draw_spectrogram(spectrogram);
+
+        writeln!(svg, r#"<image width="{}" height="{}" style="image-rendering: pixelated" xlink:href="data:image/png;base64,{}"/>"#, width, HEIGHT, base64::encode(canvas.encode_png())).unwrap();
+    }
+
+    // Frequency axis, with the boundaries of bands
+
+    for frequency_hz in BAND_BOUNDARIES_HZ.iter() {
+        let y = get_y(*frequency_hz as f32);
+
+        writeln!(svg, r#"<line x1="0" y1="{:.1}" x2="{}" y2="{:.1}" stroke="rgb(90,90,140)" stroke-dasharray="2 6"/>"#, y, width, y).unwrap();
+        writeln!(svg, r#"<text x="-6" y="{:.1}" text-anchor="end" dominant-baseline="middle">{} Hz</text>"#, y, frequency_hz).unwrap();
+    }
+
+    // Time axis, with a tick every second
+
+    let duration_seconds = width as f32 * SECONDS_PER_COLUMN;
+
+    for second in 0..=duration_seconds as usize {
+        let x = second as f32 / SECONDS_PER_COLUMN;
+
+        writeln!(svg, r#"<line x1="{:.1}" y1="{}" x2="{:.1}" y2="{}" stroke="black"/>"#, x, HEIGHT, x, HEIGHT + 5).unwrap();
+        writeln!(svg, r#"<text x="{:.1}" y="{}" text-anchor="middle">{} s</text>"#, x, HEIGHT + 18, second).unwrap();
+    }
+
+    // Legend
+
+    let mut legend_x = 0;
+
+    for (frequency_band, name) in [
+        (FrequencyBand::_250_520, "250-520 Hz"),
+        (FrequencyBand::_520_1450, "520-1450 Hz"),
+        (FrequencyBand::_1450_3500, "1450-3500 Hz"),
+        (FrequencyBand::_3500_5500, "3500-5500 Hz")
+    ].iter() {
+        let peak_count = signature.frequency_band_to_sound_peaks.get(frequency_band).map(Vec::len).unwrap_or(0);
+
+        writeln!(svg, r#"<circle cx="{}" cy="-14" r="4" fill="{}"/>"#, legend_x + 4, get_svg_color(*frequency_band)).unwrap();
+        writeln!(svg, r#"<text x="{}" y="-14" dominant-baseline="middle">{} ({})</text>"#, legend_x + 12, name, peak_count).unwrap();
+
+        legend_x += 150;
+    }
+
+    // Peaks, with their details as tooltips
+
+    for (frequency_band, frequency_peaks) in signature.frequency_band_to_sound_peaks.iter() {
+        for frequency_peak in frequency_peaks {
+            writeln!(svg, r#"<circle cx="{:.1}" cy="{:.1}" r="2.5" fill="{}"><title>{:.0} Hz, {:.3} s, {} {}</title></circle>"#,
+                get_x(frequency_peak),
+                get_y(frequency_peak.get_frequency_hz()),
+                get_svg_color(*frequency_band),
+                frequency_peak.get_frequency_hz(),
+                frequency_peak.get_seconds(),
+                gettext("magnitude"),
+                frequency_peak.peak_magnitude).unwrap();
+        }
+    }
+
+    writeln!(svg, "</g>").unwrap();
+    writeln!(svg, "</svg>").unwrap();
+
+    svg
+}
+
+/// The number of columns needed for showing the whole signature.
+
+fn get_width(signature: &DecodedSignature, spectrogram: Option<&Spectrogram>) -> usize {
+    let signature_seconds = signature.number_samples as f32 / signature.sample_rate_hz.max(1) as f32;
+
+    let last_peak_seconds = signature.frequency_band_to_sound_peaks.values()
+        .flat_map(|frequency_peaks| frequency_peaks.iter())
+        .map(|frequency_peak| frequency_peak.get_seconds())
+        .fold(0.0, f32::max);
+
+    let spectrogram_columns = spectrogram.map(|spectrogram| spectrogram.magnitudes.len()).unwrap_or(0);
+
+    ((signature_seconds.max(last_peak_seconds) / SECONDS_PER_COLUMN).ceil() as usize + 1).max(spectrogram_columns).max(1)
+}
+
+fn get_x(frequency_peak: &FrequencyPeak) -> f32 {
+    frequency_peak.get_seconds() / SECONDS_PER_COLUMN
+}
+
+fn get_y(frequency_hz: f32) -> f32 {
+    HEIGHT as f32 - 1.0 - frequency_hz / HZ_PER_ROW
+}
+
+fn get_svg_color(frequency_band: FrequencyBand) -> String {
+    let [red, green, blue] = BAND_COLORS[frequency_band as usize];
+
+    format!("rgb({},{},{})", red, green, blue)
+}
+
+/// An image with indexed colors (see the palette above).
+
+struct Canvas {
+    width: usize,
+    pixels: Vec<u8>
+}
+
+impl Canvas {
+
+    fn new(width: usize) -> Self {
+        Canvas {
+            width,
+            pixels: vec![0; width * HEIGHT]
+        }
+    }
+
+    fn set_pixel(self: &mut Self, x: i64, y: i64, color: u8) {
+        if x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < HEIGHT {
+            self.pixels[y as usize * self.width + x as usize] = color;
+        }
+    }
+
+    fn draw_spectrogram(self: &mut Self, spectrogram: &Spectrogram) {
+        let maximum_level = spectrogram.magnitudes.iter()
+            .flat_map(|magnitudes| magnitudes.iter())
+            .fold(f32::MIN, |maximum, magnitude| maximum.max(magnitude.ln()));
+
+        let minimum_level = maximum_level - SPECTROGRAM_RANGE;
+
+        for (x, magnitudes) in spectrogram.magnitudes.iter().enumerate().take(self.width) {
+            for y in 0..HEIGHT {
+                let frequency_hz = (HEIGHT - 1 - y) as f32 * HZ_PER_ROW;
+                let bin_position = (frequency_hz * spectrogram.window_size as f32 / spectrogram.sample_rate_hz as f32).round() as usize;
+
+                if let Some(magnitude) = magnitudes.get(bin_position) {
+                    let level = ((magnitude.ln() - minimum_level) / SPECTROGRAM_RANGE).clamp(0.0, 1.0);
+
+                    self.pixels[y * self.width + x] = (level * (SPECTROGRAM_LEVELS - 1) as f32).round() as u8;
+                }
+            }
+        }
+    }
+
+    fn draw_band_boundaries(self: &mut Self) {
+        for frequency_hz in BAND_BOUNDARIES_HZ.iter() {
+            let y = get_y(*frequency_hz as f32).round() as i64;
+
+            for x in (0..self.width as i64).step_by(8) {
+                self.set_pixel(x, y, BOUNDARY_COLOR);
+                self.set_pixel(x + 1, y, BOUNDARY_COLOR);
+            }
+        }
+    }
+
+    /// Draw a dot of 5x5 pixels, without its corners.
+
+    fn draw_peak(self: &mut Self, frequency_peak: &FrequencyPeak, color: u8) {
+        let center_x = get_x(frequency_peak).round() as i64;
+        let center_y = get_y(frequency_peak.get_frequency_hz()).round() as i64;
+
+        for offset_y in -2..=2i64 {
+            for offset_x in -2..=2i64 {
+                if offset_x.abs() + offset_y.abs() < 4 {
+                    self.set_pixel(center_x + offset_x, center_y + offset_y, color);
+                }
+            }
+        }
+    }
+
+    fn get_palette() -> Vec<u8> {
+        let mut palette: Vec<u8> = vec![];
+
+        for level in 0..SPECTROGRAM_LEVELS {
+            let gray = (level * 255 / (SPECTROGRAM_LEVELS - 1)) as u8;
+
+            palette.extend_from_slice(&[gray, gray, gray]);
+        }
+
+        palette.extend_from_slice(&[90, 90, 140]); // BOUNDARY_COLOR
+
+        for band_color in BAND_COLORS.iter() {
+            palette.extend_from_slice(band_color);
+        }
+
+        palette
+    }
+
+    /// Encode the canvas as an 8-bit indexed PNG file. No compression library
+    /// is available, so the image data is stored as uncompressed deflate
+    /// blocks (which takes about one byte per pixel).
+
+    fn encode_png(self: &Self) -> Vec<u8> {
+        let mut raw_data: Vec<u8> = Vec::with_capacity((self.width + 1) * HEIGHT);
+
+        for row in self.pixels.chunks_exact(self.width) {
+            raw_data.push(0); // No filter
+            raw_data.extend_from_slice(row);
+        }
+
+        // Wrap the data into a zlib stream, made of stored deflate blocks of
+        // up to 65535 bytes
+
+        let mut zlib_data: Vec<u8> = vec![0x78, 0x01];
+
+        let blocks: Vec<&[u8]> = raw_data.chunks(65535).collect();
+
+        for (index, block) in blocks.iter().enumerate() {
+            zlib_data.push((index == blocks.len() - 1) as u8); // Final block flag, and "stored" block type
+            zlib_data.write_u16::<byteorder::LittleEndian>(block.len() as u16).unwrap();
+            zlib_data.write_u16::<byteorder::LittleEndian>(!(block.len() as u16)).unwrap();
+            zlib_data.extend_from_slice(block);
+        }
+
+        zlib_data.write_u32::<BigEndian>(get_adler32(&raw_data)).unwrap();
+
+        let mut header: Vec<u8> = vec![];
+
+        header.write_u32::<BigEndian>(self.width as u32).unwrap();
+        header.write_u32::<BigEndian>(HEIGHT as u32).unwrap();
+        header.extend_from_slice(&[8, 3, 0, 0, 0]); // 8-bit depth, indexed color, default compression, filtering and no interlacing
+
+        let mut png: Vec<u8> = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+        write_png_chunk(&mut png, b"IHDR", &header);
+        write_png_chunk(&mut png, b"PLTE", &Canvas::get_palette());
+        write_png_chunk(&mut png, b"IDAT", &zlib_data);
+        write_png_chunk(&mut png, b"IEND", &[]);
+
+        png
+    }
+
+}
+
+fn write_png_chunk(png: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    png.write_u32::<BigEndian>(data.len() as u32).unwrap();
+    png.extend_from_slice(chunk_type);
+    png.extend_from_slice(data);
+
+    let mut hasher = Hasher::new();
+    hasher.update(chunk_type);
+    hasher.update(data);
+
+    png.write_u32::<BigEndian>(hasher.finalize()).unwrap();
+}
+
+fn get_adler32(data: &[u8]) -> u32 {
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    (b << 16) | a
+}
@@ -0,0 +1,521 @@
+use std::error::Error;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(windows)]
+use std::os::windows::process::CommandExt;
+
+use chrono::Local;
+use gettextrs::gettext;
+use hmac::{Hmac, Mac};
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use crate::core::thread_messages::SongRecognizedMessage;
+
+/// Event hooks allow to react to what SongRec hears without having to parse
+/// the standard output of the "listen" subcommand: when a song is recognized,
+/// when no match was found, or when the network status changes, a shell
+/// command can be run (with the track fields passed as environment
+/// variables) and/or a JSON payload can be POSTed to an URL.
+///
+/// Hooks are configured in the "preferences.toml" file, for example:
+///
+/// ```toml
+/// [[hooks.commands]]
+/// command = "notify-send \"$SONGREC_ARTIST\" \"$SONGREC_SONG\""
+/// events = ["song_recognized"]
+/// timeout_seconds = 10
+///
+/// [[hooks.webhooks]]
+/// url = "https://example.com/songrec"
+/// secret = "shared secret used for signing the payload"
+/// retries = 3
+/// ```
+///
+/// An empty or absent "events" list means that the hook is run for all
+/// events. When a "secret" is set for a webhook, the request body is signed
+/// using HMAC-SHA256 and the hexadecimal digest is passed in the
+/// "X-SongRec-Signature" header, prefixed with "sha256=".
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEventKind {
+    SongRecognized,
+    NoMatch,
+    NetworkStatus
+}
+
+impl HookEventKind {
+    fn name(self: &Self) -> &'static str {
+        match self {
+            HookEventKind::SongRecognized => "song_recognized",
+            HookEventKind::NoMatch => "no_match",
+            HookEventKind::NetworkStatus => "network_status"
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CommandHook {
+    pub command: String,
+    pub events: Vec<HookEventKind>,
+    pub timeout_seconds: u64
+}
+
+impl Default for CommandHook {
+    fn default() -> Self {
+        CommandHook {
+            command: String::new(),
+            events: vec![],
+            timeout_seconds: 30
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct WebhookHook {
+    pub url: String,
+    pub events: Vec<HookEventKind>,
+    pub timeout_seconds: u64,
+    pub retries: u32,
+    pub secret: Option<String>
+}
+
+impl Default for WebhookHook {
+    fn default() -> Self {
+        WebhookHook {
+            url: String::new(),
+            events: vec![],
+            timeout_seconds: 10,
+            retries: 2,
+            secret: None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct HooksConfig {
+    pub commands: Vec<CommandHook>,
+    pub webhooks: Vec<WebhookHook>
+}
+
+impl HooksConfig {
+    pub fn is_empty(self: &Self) -> bool {
+        self.commands.is_empty() && self.webhooks.is_empty()
+    }
+
+    /// How long running all the hooks for a single event may take at most,
+    /// given their timeouts (and the waits between the webhook retries).
+
+    fn maximum_event_duration(self: &Self) -> Duration {
+        let commands_seconds: u64 = self.commands.iter().map(|hook| hook.timeout_seconds).sum();
+
+        let webhooks_seconds: u64 = self.webhooks.iter().map(|hook| {
+            let retries = hook.retries as u64;
+            hook.timeout_seconds * (retries + 1) + retries * (retries + 1) / 2
+        }).sum();
+
+        Duration::from_secs(commands_seconds + webhooks_seconds + 1)
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct HookTrackInfo {
+    pub artist_name: String,
+    pub song_name: String,
+    pub album_name: Option<String>,
+    pub track_key: String,
+    pub release_year: Option<String>,
+    pub genre: Option<String>,
+    pub source: Option<String> // The capture source, when listening
+}
+
+enum HookEvent {
+    SongRecognized(HookTrackInfo, Value),
+    NoMatch,
+    NetworkStatus(bool)
+}
+
+impl HookEvent {
+    fn kind(self: &Self) -> HookEventKind {
+        match self {
+            HookEvent::SongRecognized(_, _) => HookEventKind::SongRecognized,
+            HookEvent::NoMatch => HookEventKind::NoMatch,
+            HookEvent::NetworkStatus(_) => HookEventKind::NetworkStatus
+        }
+    }
+
+    fn to_json(self: &Self) -> Value {
+        let mut payload = json!({
+            "event": self.kind().name(),
+            "timestamp": Local::now().to_rfc3339()
+        });
+
+        match self {
+            HookEvent::SongRecognized(track, shazam_json) => {
+                payload["track"] = json!(track);
+                payload["shazam_json"] = shazam_json.clone();
+            },
+            HookEvent::NetworkStatus(reachable) => {
+                payload["network_reachable"] = json!(reachable);
+            },
+            HookEvent::NoMatch => { }
+        };
+
+        payload
+    }
+
+    fn to_environment(self: &Self, payload: &str) -> Vec<(&'static str, String)> {
+        let mut environment = vec![
+            ("SONGREC_EVENT", self.kind().name().to_string()),
+            ("SONGREC_JSON", payload.to_string())
+        ];
+
+        match self {
+            HookEvent::SongRecognized(track, _) => {
+                environment.push(("SONGREC_ARTIST", track.artist_name.clone()));
+                environment.push(("SONGREC_SONG", track.song_name.clone()));
+                environment.push(("SONGREC_ALBUM", track.album_name.clone().unwrap_or_default()));
+                environment.push(("SONGREC_TRACK_KEY", track.track_key.clone()));
+                environment.push(("SONGREC_RELEASE_YEAR", track.release_year.clone().unwrap_or_default()));
+                environment.push(("SONGREC_GENRE", track.genre.clone().unwrap_or_default()));
+                environment.push(("SONGREC_SOURCE", track.source.clone().unwrap_or_default()));
+            },
+            HookEvent::NetworkStatus(reachable) => {
+                environment.push(("SONGREC_NETWORK_REACHABLE", reachable.to_string()));
+            },
+            HookEvent::NoMatch => { }
+        };
+
+        environment
+    }
+}
+
+fn hook_wants_event(events: &[HookEventKind], kind: HookEventKind) -> bool {
+    events.is_empty() || events.contains(&kind)
+}
+
+/// The hook runner is owned by the thread receiving the GUIMessage's (the
+/// GTK or CLI main loop), and forwards events to a worker thread, so that
+/// slow commands or webhooks never block the interface.
+///
+/// When it is dropped, it waits for the hooks of the events already sent to
+/// run (as one-shot commands exit right after a recognition), for no longer
+/// than the hook timeouts allow for each of these events.
+
+pub struct HookRunner {
+    hooks_tx: Option<mpsc::Sender<HookEvent>>,
+    progress_rx: mpsc::Receiver<()>, // Receives a message for each event processed by the worker thread, disconnected when it ends
+    maximum_event_duration: Duration,
+    last_network_status: Option<bool>
+}
+
+impl HookRunner {
+
+    /// Returns None when no hook is configured, so that no worker thread is
+    /// spawned in this case.
+
+    pub fn new(config: HooksConfig) -> Option<Self> {
+        if config.is_empty() {
+            return None;
+        }
+
+        let (hooks_tx, hooks_rx) = mpsc::channel();
+        let (progress_tx, progress_rx) = mpsc::channel();
+
+        let maximum_event_duration = config.maximum_event_duration();
+
+        thread::spawn(move || {
+            hooks_thread(hooks_rx, progress_tx, config);
+        });
+
+        Some(HookRunner {
+            hooks_tx: Some(hooks_tx),
+            progress_rx,
+            maximum_event_duration,
+            last_network_status: None
+        })
+    }
+
+    pub fn song_recognized(self: &mut Self, message: &SongRecognizedMessage) {
+        let track = HookTrackInfo {
+            artist_name: message.artist_name.clone(),
+            song_name: message.song_name.clone(),
+            album_name: message.album_name.clone(),
+            track_key: message.track_key.clone(),
+            release_year: message.release_year.clone(),
+            genre: message.genre.clone(),
+            source: message.source_id.clone()
+        };
+
+        let shazam_json = serde_json::from_str(&message.shazam_json).unwrap_or(Value::Null);
+
+        self.send(HookEvent::SongRecognized(track, shazam_json));
+    }
+
+    pub fn no_match(self: &mut Self) {
+        self.send(HookEvent::NoMatch);
+    }
+
+    /// The network status is reported after each recognition attempt, only
+    /// run hooks when it actually changes.
+
+    pub fn network_status(self: &mut Self, reachable: bool) {
+        if self.last_network_status != Some(reachable) {
+            self.last_network_status = Some(reachable);
+
+            self.send(HookEvent::NetworkStatus(reachable));
+        }
+    }
+
+    /// Hooks are a side feature, so the interface keeps running if their
+    /// worker thread has died (because of a panic).
+
+    fn send(self: &Self, event: HookEvent) {
+        let sent = match &self.hooks_tx {
+            Some(hooks_tx) => hooks_tx.send(event).is_ok(),
+            None => false
+        };
+
+        if !sent {
+            eprintln!("{}", gettext("The event hooks have stopped working, ignoring the event"));
+        }
+    }
+}
+
+impl Drop for HookRunner {
+    fn drop(self: &mut Self) {
+
+        // The worker thread ends once it has processed the remaining events
+
+        self.hooks_tx = None;
+
+        loop {
+            match self.progress_rx.recv_timeout(self.maximum_event_duration) {
+                Ok(()) => { },
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    break;
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    eprintln!("{}", gettext("The event hooks are taking too long, not waiting for them"));
+                    break;
+                }
+            }
+        }
+
+    }
+}
+
+fn hooks_thread(hooks_rx: mpsc::Receiver<HookEvent>, progress_tx: mpsc::Sender<()>, config: HooksConfig) {
+
+    for event in hooks_rx.iter() {
+
+        let kind = event.kind();
+        let payload = event.to_json().to_string();
+
+        for hook in config.commands.iter().filter(|hook| hook_wants_event(&hook.events, kind)) {
+            if let Err(error) = run_command_hook(hook, &event.to_environment(&payload)) {
+                eprintln!("{} \"{}\": {}", gettext("Error when running the hook command"), hook.command, error);
+            }
+        }
+
+        for hook in config.webhooks.iter().filter(|hook| hook_wants_event(&hook.events, kind)) {
+            if let Err(error) = run_webhook(hook, &payload) {
+                eprintln!("{} \"{}\": {}", gettext("Error when calling the webhook"), hook.url, error);
+            }
+        }
+
+        progress_tx.send(()).ok();
+    }
+
+}
+
+fn run_command_hook(hook: &CommandHook, environment: &[(&'static str, String)]) -> Result<(), Box<dyn Error>> {
+
+    #[cfg(not(windows))]
+    let mut command = Command::new("sh");
+    #[cfg(not(windows))]
+    command.arg("-c").arg(&hook.command);
+
+    #[cfg(windows)]
+    let mut command = Command::new("cmd");
+    #[cfg(windows)]
+    command.arg("/C").arg(&hook.command).creation_flags(0x00000008); // Set "CREATE_NO_WINDOW" on Windows
+
+    // Don't let the hook pollute the standard output, which may be parsed
+    // by other programs when using the command-line interface
+
+    command.envs(environment.iter().map(|(key, value)| (*key, value)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null());
+
+    let mut child = command.spawn()?;
+
+    let deadline = Instant::now() + Duration::from_secs(hook.timeout_seconds);
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if !status.success() {
+                return Err(format!("{} {}", gettext("Hook command exited with"), status).into());
+            }
+            return Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(gettext("Hook command timed out").into());
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn sign_payload(secret: &str, payload: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(payload.as_bytes());
+
+    let digest: Vec<String> = mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    format!("sha256={}", digest.join(""))
+}
+
+fn run_webhook(hook: &WebhookHook, payload: &str) -> Result<(), Box<dyn Error>> {
+
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(hook.timeout_seconds))
+        .build()?;
+
+    let mut last_error: Box<dyn Error> = gettext("No attempt was made").into();
+
+    for attempt in 0..=hook.retries {
+
+        if attempt > 0 {
+            thread::sleep(Duration::from_secs(attempt as u64));
+        }
+
+        let mut request = client.post(&hook.url)
+            .header("Content-Type", "application/json")
+            .body(payload.to_string());
+
+        if let Some(secret) = &hook.secret {
+            request = request.header("X-SongRec-Signature", sign_payload(secret, payload));
+        }
+
+        match request.send() {
+            Ok(response) if response.status().is_success() => {
+                return Ok(());
+            },
+            Ok(response) => {
+                last_error = format!("{} {}", gettext("Webhook returned HTTP status"), response.status()).into();
+            },
+            Err(error) => {
+                last_error = Box::new(error);
+            }
+        };
+    }
+
+    Err(last_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn make_track() -> HookTrackInfo {
+        HookTrackInfo {
+            artist_name: "Stupeflip".to_string(),
+            song_name: "Stupeflip Vite!!!".to_string(),
+            album_name: None,
+            track_key: "48374157".to_string(),
+            release_year: Some("2011".to_string()),
+            genre: None,
+            source: Some("monitor".to_string())
+        }
+    }
+
+    #[test]
+    fn payload_signatures() {
+        // From RFC 4231 (test case 2)
+        assert_eq!(sign_payload("Jefe", "what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+
+        assert_ne!(sign_payload("Jefe", "{}"), sign_payload("Jeff", "{}"));
+    }
+
+    #[test]
+    fn command_environments() {
+        let event = HookEvent::SongRecognized(make_track(), json!({ "track": { "key": "48374157" } }));
+        let payload = event.to_json().to_string();
+        let environment: HashMap<&str, String> = event.to_environment(&payload).into_iter().collect();
+
+        assert_eq!(environment["SONGREC_EVENT"], "song_recognized");
+        assert_eq!(environment["SONGREC_ARTIST"], "Stupeflip");
+        assert_eq!(environment["SONGREC_SONG"], "Stupeflip Vite!!!");
+        assert_eq!(environment["SONGREC_ALBUM"], "");
+        assert_eq!(environment["SONGREC_TRACK_KEY"], "48374157");
+        assert_eq!(environment["SONGREC_RELEASE_YEAR"], "2011");
+        assert_eq!(environment["SONGREC_GENRE"], "");
+        assert_eq!(environment["SONGREC_SOURCE"], "monitor");
+
+        let json: Value = serde_json::from_str(&environment["SONGREC_JSON"]).unwrap();
+        assert_eq!(json["event"], "song_recognized");
+        assert_eq!(json["track"]["artist_name"], "Stupeflip");
+        assert_eq!(json["shazam_json"]["track"]["key"], "48374157");
+
+        let event = HookEvent::NetworkStatus(false);
+        let environment: HashMap<&str, String> = event.to_environment(&event.to_json().to_string()).into_iter().collect();
+
+        assert_eq!(environment["SONGREC_EVENT"], "network_status");
+        assert_eq!(environment["SONGREC_NETWORK_REACHABLE"], "false");
+        assert!(!environment.contains_key("SONGREC_ARTIST"));
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn commands_run_before_exiting() {
+        let directory = tempfile::tempdir().unwrap();
+        let output_path = directory.path().join("output.txt");
+
+        let mut runner = HookRunner::new(HooksConfig {
+            commands: vec![CommandHook {
+                command: format!("sleep 0.5; echo \"$SONGREC_EVENT $SONGREC_ARTIST\" >> '{}'", output_path.display()),
+                events: vec![HookEventKind::SongRecognized],
+                ..CommandHook::default()
+            }],
+            webhooks: vec![]
+        }).unwrap();
+
+        runner.send(HookEvent::SongRecognized(make_track(), Value::Null));
+        runner.no_match(); // Filtered out
+        runner.send(HookEvent::SongRecognized(make_track(), Value::Null));
+
+        drop(runner);
+
+        assert_eq!(std::fs::read_to_string(&output_path).unwrap(), "song_recognized Stupeflip\n".repeat(2));
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn commands_time_out() {
+        let hook = CommandHook {
+            command: "sleep 10".to_string(),
+            timeout_seconds: 1,
+            ..CommandHook::default()
+        };
+
+        let start = Instant::now();
+
+        assert!(run_command_hook(&hook, &[]).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+}
@@ -1,52 +1,396 @@
-use std::panic;
-use std::sync::Arc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
 
-use mpris_player::{MprisPlayer, PlaybackStatus, Metadata};
+use dbus::{BusType, Connection, NameFlag, Path, SignalArgs};
+use dbus::arg::{RefArg, Variant};
+use dbus::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged;
+use dbus::tree::{Access, Factory};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
 
 use crate::core::thread_messages::SongRecognizedMessage;
 
-fn init_player(p: Arc<MprisPlayer>) -> Arc<MprisPlayer> {
-    p.set_can_quit(false);
-    p.set_can_raise(false);
-    p.set_can_set_fullscreen(false);
+/// This module exposes the currently recognized song over the MPRIS D-Bus
+/// interface (https://specifications.freedesktop.org/mpris-spec/latest/),
+/// so that desktop environments and applets can display it, and so that
+/// media keys can be used to start or stop listening.
+///
+/// The D-Bus connection is owned by a dedicated thread, which receives
+/// updates from the GUI or CLI main loop through a standard channel.
 
-    p.set_can_control(false);
-    p.set_can_seek(false);
-    p.set_can_go_next(false);
-    p.set_can_go_previous(false);
-    p.set_can_play(true);
-    p.set_can_pause(false);
-    p.set_playback_status(PlaybackStatus::Playing);
+const MPRIS_OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const MPRIS_PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+const TRACK_ID_PREFIX: &str = "/com/github/marinm/songrec/track/";
 
-    p
+// Characters to escape in the path part of a "file://" URL
+const PATH_ESCAPED_CHARACTERS: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'<').add(b'>').add(b'?').add(b'`').add(b'{').add(b'}');
+
+/// Requests received from MPRIS clients. "Play", "Pause", "PlayPause" and
+/// "Stop" are all mapped to starting or stopping listening.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MprisControl {
+    StartListening,
+    StopListening
 }
 
-pub fn get_player() -> Option<Arc<MprisPlayer>> {
-    // MprisPlayer::new may panic if DBus is unavailable,
-    // so we need to mess around with panic::catch_unwind
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlaybackStatus {
+    Playing,
+    Paused,
+    Stopped
+}
 
-    let prev_hook = panic::take_hook();
-    panic::set_hook(Box::new(|_| {}));
-    let player = panic::catch_unwind(|| MprisPlayer::new(
-        "SongRec".to_string(),
-        "SongRec".to_string(),
-        "com.github.marinm.songrec.desktop".to_string()
-    ));
-    panic::set_hook(prev_hook);
+impl PlaybackStatus {
+    fn value(self: &Self) -> &'static str {
+        match self {
+            PlaybackStatus::Playing => "Playing",
+            PlaybackStatus::Paused => "Paused",
+            PlaybackStatus::Stopped => "Stopped"
+        }
+    }
+}
 
-    player.map(init_player).ok()
+#[derive(Clone, Debug, Default)]
+struct TrackMetadata {
+    track_id: Option<String>,
+    title: Option<String>,
+    artists: Vec<String>,
+    album: Option<String>,
+    genres: Vec<String>,
+    art_url: Option<String>,
+    url: Option<String>,
+    content_created: Option<String>
 }
 
-pub fn update_song(p: &MprisPlayer, m: &SongRecognizedMessage) {
-    let mut metadata = Metadata::new();
-    metadata.title = Some(m.song_name.clone());
-    metadata.artist = Some(vec![m.artist_name.clone()]);
-    metadata.album = m.album_name.clone();
-    if let Some(ref genre) = m.genre { 
-        metadata.genre = Some(vec![genre.clone()]);
+impl TrackMetadata {
+    fn to_dbus_dict(self: &Self) -> HashMap<String, Variant<Box<dyn RefArg>>> {
+        let mut dict: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+
+        // "mpris:trackid" is mandatory, and should be a valid D-Bus object path
+
+        let track_id = match &self.track_id {
+            Some(track_id) => Path::new(track_id.to_string()).unwrap_or_else(|_| Path::new("/org/mpris/MediaPlayer2/TrackList/NoTrack").unwrap()),
+            None => Path::new("/org/mpris/MediaPlayer2/TrackList/NoTrack").unwrap()
+        };
+        dict.insert("mpris:trackid".to_string(), Variant(Box::new(track_id)));
+
+        if let Some(ref title) = self.title {
+            dict.insert("xesam:title".to_string(), Variant(Box::new(title.clone())));
+        }
+        if !self.artists.is_empty() {
+            dict.insert("xesam:artist".to_string(), Variant(Box::new(self.artists.clone())));
+        }
+        if let Some(ref album) = self.album {
+            dict.insert("xesam:album".to_string(), Variant(Box::new(album.clone())));
+        }
+        if !self.genres.is_empty() {
+            dict.insert("xesam:genre".to_string(), Variant(Box::new(self.genres.clone())));
+        }
+        if let Some(ref art_url) = self.art_url {
+            dict.insert("mpris:artUrl".to_string(), Variant(Box::new(art_url.clone())));
+        }
+        if let Some(ref url) = self.url {
+            dict.insert("xesam:url".to_string(), Variant(Box::new(url.clone())));
+        }
+        if let Some(ref content_created) = self.content_created {
+            dict.insert("xesam:contentCreated".to_string(), Variant(Box::new(content_created.clone())));
+        }
+
+        dict
     }
-    if let Some(ref buf) = m.cover_image { 
-        metadata.art_url = Some(format!("data:image/jpeg;base64,{}", base64::encode(buf)));
+}
+
+enum MprisUpdate {
+    Listening(bool),
+    NetworkReachable(bool),
+    Metadata(TrackMetadata)
+}
+
+struct MprisState {
+    listening: bool,
+    network_reachable: bool,
+    metadata: TrackMetadata
+}
+
+impl MprisState {
+    fn playback_status(self: &Self) -> PlaybackStatus {
+        if !self.listening {
+            PlaybackStatus::Stopped
+        }
+        else if !self.network_reachable {
+            PlaybackStatus::Paused
+        }
+        else {
+            PlaybackStatus::Playing
+        }
     }
-    p.set_metadata(metadata);
+}
+
+/// Handle used to update the state exposed over MPRIS. Dropping every copy
+/// of it unregisters the MPRIS service.
+
+#[derive(Clone)]
+pub struct MprisPlayer {
+    mpris_tx: mpsc::Sender<MprisUpdate>
+}
+
+impl MprisPlayer {
+
+    /// Whether SongRec is currently listening to the microphone or not
+    /// ("Playing" or "Stopped" from the point of view of MPRIS).
+
+    pub fn set_listening(self: &Self, listening: bool) {
+        self.mpris_tx.send(MprisUpdate::Listening(listening)).ok();
+    }
+
+    /// An unreachable network is exposed as the "Paused" status.
+
+    pub fn set_network_reachable(self: &Self, reachable: bool) {
+        self.mpris_tx.send(MprisUpdate::NetworkReachable(reachable)).ok();
+    }
+
+}
+
+/// Register the MPRIS service on the session bus, returning None if D-Bus
+/// is not available. The control callback is called from the MPRIS thread
+/// when a client asks to start or stop listening.
+
+pub fn get_player<F>(control_callback: F) -> Option<MprisPlayer>
+    where F: Fn(MprisControl) + Send + 'static {
+
+    let (mpris_tx, mpris_rx) = mpsc::channel();
+    let (ready_tx, ready_rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let connection = match register_connection() {
+            Ok(connection) => connection,
+            Err(_) => {
+                ready_tx.send(false).unwrap();
+                return;
+            }
+        };
+        ready_tx.send(true).unwrap();
+
+        mpris_thread(connection, mpris_rx, control_callback);
+    });
+
+    match ready_rx.recv() {
+        Ok(true) => Some(MprisPlayer { mpris_tx }),
+        _ => None
+    }
+}
+
+fn register_connection() -> Result<Connection, dbus::Error> {
+    let connection = Connection::get_private(BusType::Session)?;
+    connection.register_name("org.mpris.MediaPlayer2.SongRec", NameFlag::DoNotQueue as u32)?;
+    Ok(connection)
+}
+
+fn mpris_thread<F>(connection: Connection, mpris_rx: mpsc::Receiver<MprisUpdate>, control_callback: F)
+    where F: Fn(MprisControl) + 'static {
+
+    let state = Rc::new(RefCell::new(MprisState {
+        listening: false,
+        network_reachable: true,
+        metadata: TrackMetadata::default()
+    }));
+
+    // Method calls from MPRIS clients are passed to the control callback
+    // from the loop below. The state only changes once listening has
+    // actually started or stopped (which may not happen, for example when
+    // there is no audio device), as reported through "set_listening"
+
+    let pending_controls: Rc<RefCell<Vec<MprisControl>>> = Rc::new(RefCell::new(vec![]));
+
+    let factory = Factory::new_fn::<()>();
+
+    let control_method = |name: &'static str, control: Option<MprisControl>| {
+        let pending_controls = pending_controls.clone();
+        let state = state.clone();
+
+        factory.method(name, (), move |method_info| {
+            // "PlayPause" toggles listening depending on the current state
+
+            let control = control.unwrap_or(match state.borrow().listening {
+                true => MprisControl::StopListening,
+                false => MprisControl::StartListening
+            });
+            pending_controls.borrow_mut().push(control);
+
+            Ok(vec![method_info.msg.method_return()])
+        })
+    };
+
+    let no_op_method = |name: &'static str| {
+        factory.method(name, (), |method_info| Ok(vec![method_info.msg.method_return()]))
+    };
+
+    let state_2 = state.clone();
+    let state_3 = state.clone();
+
+    let root_interface = factory.interface("org.mpris.MediaPlayer2", ())
+        .add_m(no_op_method("Raise"))
+        .add_m(no_op_method("Quit"))
+        .add_p(factory.property::<bool, _>("CanQuit", ()).on_get(|i, _| { i.append(false); Ok(()) }))
+        .add_p(factory.property::<bool, _>("CanRaise", ()).on_get(|i, _| { i.append(false); Ok(()) }))
+        .add_p(factory.property::<bool, _>("HasTrackList", ()).on_get(|i, _| { i.append(false); Ok(()) }))
+        .add_p(factory.property::<&str, _>("Identity", ()).on_get(|i, _| { i.append("SongRec"); Ok(()) }))
+        .add_p(factory.property::<&str, _>("DesktopEntry", ()).on_get(|i, _| { i.append("com.github.marinm.songrec"); Ok(()) }))
+        .add_p(factory.property::<Vec<&str>, _>("SupportedUriSchemes", ()).on_get(|i, _| { i.append(Vec::<&str>::new()); Ok(()) }))
+        .add_p(factory.property::<Vec<&str>, _>("SupportedMimeTypes", ()).on_get(|i, _| { i.append(Vec::<&str>::new()); Ok(()) }));
+
+    let player_interface = factory.interface(MPRIS_PLAYER_INTERFACE, ())
+        .add_m(control_method("Play", Some(MprisControl::StartListening)))
+        .add_m(control_method("Pause", Some(MprisControl::StopListening)))
+        .add_m(control_method("Stop", Some(MprisControl::StopListening)))
+        .add_m(control_method("PlayPause", None))
+        .add_m(no_op_method("Next"))
+        .add_m(no_op_method("Previous"))
+        .add_m(no_op_method("Seek").inarg::<i64, _>("Offset"))
+        .add_m(no_op_method("SetPosition").inarg::<Path, _>("TrackId").inarg::<i64, _>("Position"))
+        .add_m(no_op_method("OpenUri").inarg::<&str, _>("Uri"))
+        .add_p(factory.property::<&str, _>("PlaybackStatus", ()).on_get(move |i, _| {
+            i.append(state_2.borrow().playback_status().value());
+            Ok(())
+        }))
+        .add_p(factory.property::<HashMap<&str, Variant<Box<dyn RefArg>>>, _>("Metadata", ()).on_get(move |i, _| {
+            i.append(state_3.borrow().metadata.to_dbus_dict());
+            Ok(())
+        }))
+        .add_p(factory.property::<f64, _>("Rate", ()).access(Access::Read).on_get(|i, _| { i.append(1.0); Ok(()) }))
+        .add_p(factory.property::<f64, _>("MinimumRate", ()).on_get(|i, _| { i.append(1.0); Ok(()) }))
+        .add_p(factory.property::<f64, _>("MaximumRate", ()).on_get(|i, _| { i.append(1.0); Ok(()) }))
+        .add_p(factory.property::<f64, _>("Volume", ()).access(Access::Read).on_get(|i, _| { i.append(1.0); Ok(()) }))
+        .add_p(factory.property::<i64, _>("Position", ()).on_get(|i, _| { i.append(0i64); Ok(()) }))
+        .add_p(factory.property::<bool, _>("CanGoNext", ()).on_get(|i, _| { i.append(false); Ok(()) }))
+        .add_p(factory.property::<bool, _>("CanGoPrevious", ()).on_get(|i, _| { i.append(false); Ok(()) }))
+        .add_p(factory.property::<bool, _>("CanPlay", ()).on_get(|i, _| { i.append(true); Ok(()) }))
+        .add_p(factory.property::<bool, _>("CanPause", ()).on_get(|i, _| { i.append(true); Ok(()) }))
+        .add_p(factory.property::<bool, _>("CanSeek", ()).on_get(|i, _| { i.append(false); Ok(()) }))
+        .add_p(factory.property::<bool, _>("CanControl", ()).on_get(|i, _| { i.append(true); Ok(()) }));
+
+    let tree = factory.tree(()).add(
+        factory.object_path(MPRIS_OBJECT_PATH, ())
+            .introspectable()
+            .add(root_interface)
+            .add(player_interface)
+    );
+
+    if tree.set_registered(&connection, true).is_err() {
+        return;
+    }
+    connection.add_handler(tree);
+
+    loop {
+        connection.incoming(100).next();
+
+        let mut status_changed = false;
+        let mut metadata_changed = false;
+
+        for control in pending_controls.borrow_mut().drain(..) {
+            control_callback(control);
+        }
+
+        loop {
+            match mpris_rx.try_recv() {
+                Ok(MprisUpdate::Listening(listening)) => {
+                    state.borrow_mut().listening = listening;
+                    status_changed = true;
+                },
+                Ok(MprisUpdate::NetworkReachable(reachable)) => {
+                    state.borrow_mut().network_reachable = reachable;
+                    status_changed = true;
+                },
+                Ok(MprisUpdate::Metadata(metadata)) => {
+                    state.borrow_mut().metadata = metadata;
+                    metadata_changed = true;
+                },
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => return
+            };
+        }
+
+        let mut changed_properties: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+
+        if status_changed {
+            changed_properties.insert("PlaybackStatus".to_string(), Variant(Box::new(state.borrow().playback_status().value().to_string())));
+        }
+        if metadata_changed {
+            changed_properties.insert("Metadata".to_string(), Variant(Box::new(state.borrow().metadata.to_dbus_dict())));
+        }
+
+        if !changed_properties.is_empty() {
+            let signal = PropertiesPropertiesChanged {
+                interface_name: MPRIS_PLAYER_INTERFACE.to_string(),
+                changed_properties,
+                invalidated_properties: vec![]
+            };
+
+            connection.send(signal.to_emit_message(&Path::new(MPRIS_OBJECT_PATH).unwrap())).ok();
+        }
+    }
+}
+
+/// Write the cover art to a file of the cache directory, rather than passing
+/// it as a (possibly multi-megabyte) data URI over D-Bus. Only the cover of
+/// the last recognized song is kept.
+
+fn write_cover_art_to_cache(track_key: &str, cover_image: &[u8]) -> Option<String> {
+    let mut cache_directory = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(directory) => PathBuf::from(directory),
+        None => match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".cache"),
+            None => std::env::temp_dir()
+        }
+    };
+    cache_directory.push("songrec");
+    cache_directory.push("covers");
+
+    fs::create_dir_all(&cache_directory).ok()?;
+
+    if let Ok(entries) = fs::read_dir(&cache_directory) {
+        for entry in entries.flatten() {
+            fs::remove_file(entry.path()).ok();
+        }
+    }
+
+    let file_name: String = track_key.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+    let cover_path = cache_directory.join(format!("{}.jpg", file_name));
+
+    fs::write(&cover_path, cover_image).ok()?;
+
+    let cover_path_string = cover_path.to_str()?.replace("\\", "/");
+
+    Some(format!("file://{}{}",
+        if cover_path_string.starts_with('/') { "" } else { "/" },
+        utf8_percent_encode(&cover_path_string, PATH_ESCAPED_CHARACTERS)))
+}
+
+pub fn update_song(p: &MprisPlayer, m: &SongRecognizedMessage) {
+    let track_id_suffix: String = m.track_key.chars().filter(|c| c.is_ascii_alphanumeric() || *c == '_').collect();
+
+    let metadata = TrackMetadata {
+        track_id: if track_id_suffix.is_empty() { None } else { Some(format!("{}{}", TRACK_ID_PREFIX, track_id_suffix)) },
+        title: Some(m.song_name.clone()),
+        artists: vec![m.artist_name.clone()],
+        album: m.album_name.clone(),
+        genres: m.genre.iter().cloned().collect(),
+        art_url: m.cover_image.as_ref().and_then(|buf| write_cover_art_to_cache(&m.track_key, buf)),
+        url: m.track_url.clone(),
+
+        // Note: "mpris:length" is not exposed, as Shazam doesn't return the
+        // duration of recognized tracks
+
+        // Shazam only provides the release year
+        content_created: m.release_year.as_ref()
+            .filter(|year| year.len() == 4 && year.chars().all(|c| c.is_ascii_digit()))
+            .map(|year| format!("{}-01-01T00:00:00Z", year))
+    };
+
+    p.mpris_tx.send(MprisUpdate::Metadata(metadata)).ok();
 }
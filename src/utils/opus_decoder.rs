@@ -0,0 +1,127 @@
+use std::convert::TryFrom;
+use std::sync::Mutex;
+use symphonia::core::audio::{AsAudioBufferRef, AudioBuffer, AudioBufferRef, Signal, SignalSpec};
+use symphonia::core::codecs::{CodecDescriptor, CodecParameters, Decoder, DecoderOptions, FinalizeResult, CODEC_TYPE_OPUS};
+use symphonia::core::errors::{decode_error, unsupported_error, Result};
+use symphonia::core::formats::Packet;
+use symphonia::core::support_codec;
+use audiopus::coder::{Decoder as LibopusDecoder, GenericCtl};
+use audiopus::packet::Packet as LibopusPacket;
+use audiopus::{Channels, MutSignals, SampleRate};
+
+/// This module provides a Symphonia decoder for Opus (which Symphonia has no
+/// decoder for), built upon libopus, for the "opus" cargo feature. The Opus
+/// packets themselves are read by the Ogg and Matroska/WebM demuxers of
+/// Symphonia.
+///
+/// Only mono and stereo streams are supported, which covers the Opus files
+/// found in practice (surround streams still go through FFMpeg).
+
+// Opus always decodes at 48 KHz, and its packets hold at most 120 ms of audio
+
+const OPUS_SAMPLE_RATE: u32 = 48000;
+const MAXIMUM_FRAMES_PER_PACKET: usize = 5760;
+
+pub struct OpusDecoder {
+    params: CodecParameters,
+    decoder: Mutex<LibopusDecoder>, // libopus decoders aren't Sync, which Symphonia requires
+    channel_count: usize,
+    samples: Vec<i16>, // Interleaved samples of the last decoded packet
+    buffer: AudioBuffer<i16>,
+    remaining_pre_skip: usize // Frames still to drop at the beginning of the stream
+}
+
+impl Decoder for OpusDecoder {
+    fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<Self> {
+        let spec_channels = match params.channels {
+            Some(channels) => channels,
+            None => { return unsupported_error("opus: the channel layout is missing"); }
+        };
+
+        let channels = match spec_channels.count() {
+            1 => Channels::Mono,
+            2 => Channels::Stereo,
+            _ => { return unsupported_error("opus: only mono and stereo streams are supported"); }
+        };
+
+        let decoder = match LibopusDecoder::new(SampleRate::Hz48000, channels) {
+            Ok(decoder) => decoder,
+            Err(_) => { return unsupported_error("opus: unable to create a decoder"); }
+        };
+
+        Ok(OpusDecoder {
+            params: params.clone(),
+            decoder: Mutex::new(decoder),
+            channel_count: spec_channels.count(),
+            samples: vec![0; MAXIMUM_FRAMES_PER_PACKET * spec_channels.count()],
+            buffer: AudioBuffer::new(MAXIMUM_FRAMES_PER_PACKET as u64, SignalSpec::new(OPUS_SAMPLE_RATE, spec_channels)),
+            remaining_pre_skip: params.delay.unwrap_or(0) as usize
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(CODEC_TYPE_OPUS, "opus", "Opus (libopus)")]
+    }
+
+    fn reset(self: &mut Self) {
+        if let Ok(mut decoder) = self.decoder.lock() {
+            let _ = decoder.reset_state();
+        }
+    }
+
+    fn codec_params(self: &Self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(self: &mut Self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+        self.buffer.clear();
+
+        let packet_data = match LibopusPacket::try_from(packet.buf()) {
+            Ok(packet_data) => packet_data,
+            Err(_) => { return decode_error("opus: empty packet"); }
+        };
+
+        let output = match MutSignals::try_from(&mut self.samples) {
+            Ok(output) => output,
+            Err(_) => { return decode_error("opus: invalid output buffer"); }
+        };
+
+        let frames = match self.decoder.lock() {
+            Ok(mut decoder) => decoder.decode(Some(packet_data), output, false),
+            Err(_) => { return decode_error("opus: the decoder is unusable"); }
+        };
+
+        let frames = match frames {
+            Ok(frames) => frames,
+            Err(_) => { return decode_error("opus: invalid packet"); }
+        };
+
+        // Drop the pre-skip frames, which are the priming output of the
+        // encoder rather than audio
+
+        let skipped_frames = frames.min(self.remaining_pre_skip);
+        self.remaining_pre_skip -= skipped_frames;
+
+        let channel_count = self.channel_count;
+
+        self.buffer.render_reserved(Some(frames - skipped_frames));
+
+        for channel in 0..channel_count {
+            let samples = &self.samples;
+
+            for (frame, sample) in self.buffer.chan_mut(channel).iter_mut().enumerate() {
+                *sample = samples[(skipped_frames + frame) * channel_count + channel];
+            }
+        }
+
+        Ok(self.buffer.as_audio_buffer_ref())
+    }
+
+    fn finalize(self: &mut Self) -> FinalizeResult {
+        FinalizeResult::default()
+    }
+
+    fn last_decoded(self: &Self) -> AudioBufferRef<'_> {
+        self.buffer.as_audio_buffer_ref()
+    }
+}
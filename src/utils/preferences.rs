@@ -6,12 +6,18 @@ use gettextrs::gettext;
 use std::io::{Read, Write};
 
 use crate::utils::filesystem_operations::obtain_preferences_file_path;
+use crate::utils::hooks::HooksConfig;
+use crate::core::capture_config::CaptureConfig;
+use crate::fingerprinting::preprocessing::SourcePreprocessing;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(default)]
 pub struct Preferences {
     pub enable_notifications: Option<bool>,
-    pub current_device_name: Option<String>
+    pub current_device_name: Option<String>,
+    pub hooks: Option<HooksConfig>,
+    pub capture: Option<CaptureConfig>,
+    pub preprocessing: Option<SourcePreprocessing>
 }
 
 
@@ -19,7 +25,10 @@ impl Default for Preferences {
     fn default() -> Self {
         Preferences {
             enable_notifications: Some(true),
-            current_device_name: None
+            current_device_name: None,
+            hooks: None,
+            capture: None,
+            preprocessing: None
         }
     }
 }
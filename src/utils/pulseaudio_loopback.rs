@@ -1,7 +1,18 @@
-use std::process::Command;
+use std::error::Error;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use gettextrs::gettext;
 use serde::Deserialize;
 
+#[cfg(feature = "pulseaudio")]
+use std::cell::RefCell;
+#[cfg(feature = "pulseaudio")]
+use std::rc::Rc;
+#[cfg(feature = "pulseaudio")]
+use libpulse_binding::{callbacks::ListResult, context, mainloop::standard::{IterateResult, Mainloop}, operation};
+
 // Structures useful for passing arguments to the functions below
 
 enum PactlDevicesType {
@@ -42,6 +53,16 @@ struct PactlClientTSVInfo { // Parses the TSV output from "pactl list short clie
     pub binary_process_name: String
 }
 
+#[allow(dead_code)]
+#[derive(Deserialize)]
+struct PactlSinkInputTSVInfo { // Parses the TSV output from "pactl list short sink-inputs"
+    pub index: u64,
+    pub sink_index: u64,
+    pub client_index_or_dash: String,
+    pub driver_name: String,
+    pub sample_spec: String
+}
+
 #[allow(dead_code)]
 #[derive(Deserialize)]
 struct PactlSourceOutputTSVInfo { // Parses the TSV output from "pactl list short source-outputs"
@@ -52,6 +73,121 @@ struct PactlSourceOutputTSVInfo { // Parses the TSV output from "pactl list shor
     pub sample_spec: String
 }
 
+/// A PulseAudio source (or PipeWire node exposed through "pipewire-pulse"),
+/// including the monitors of the outputs, as listed by the functions below.
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PulseaudioSource {
+    pub name: String,
+    pub description: String,
+    pub is_monitor: bool
+}
+
+/// The prefix used for distinguishing PulseAudio sources from CPAL devices,
+/// in the device list of the GUI and for the "--audio-device" option.
+
+pub const PULSEAUDIO_DEVICE_PREFIX: &str = "PulseAudio: ";
+
+impl PulseaudioSource {
+    pub fn device_name(self: &Self) -> String {
+        format!("{}{}", PULSEAUDIO_DEVICE_PREFIX, self.description)
+    }
+}
+
+/// An application stream playing through PulseAudio, as listed by the
+/// functions below. The application name is the name of its binary.
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PulseaudioSinkInput {
+    pub index: u64,
+    pub sink_index: u64,
+    pub application_name: String
+}
+
+/// Notices sources, sinks, sound cards and application streams appearing or
+/// disappearing, through a single "pactl subscribe" process kept running
+/// (which is stopped when this is dropped).
+
+pub struct PulseaudioEvents {
+    pactl_process: Child,
+    devices_changed: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool> // Whether "pactl subscribe" has exited, for example without a server
+}
+
+impl PulseaudioEvents {
+    
+    pub fn start() -> Option<Self> {
+        let mut pactl_process = Command::new("pactl")
+            .arg("subscribe")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn().ok()?;
+        
+        let pactl_output = pactl_process.stdout.take()?;
+        
+        let devices_changed = Arc::new(AtomicBool::new(false));
+        let devices_changed_2 = devices_changed.clone();
+        
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stopped_2 = stopped.clone();
+        
+        std::thread::spawn(move || {
+            for line in BufReader::new(pactl_output).lines() {
+                match line {
+                    Ok(line) if is_device_change_event(&line) => devices_changed_2.store(true, Ordering::SeqCst),
+                    Ok(_) => { },
+                    Err(_) => break
+                };
+            }
+            
+            // The server went away (it may be restarted with other devices)
+            
+            devices_changed_2.store(true, Ordering::SeqCst);
+            stopped_2.store(true, Ordering::SeqCst);
+        });
+        
+        Some(PulseaudioEvents {
+            pactl_process,
+            devices_changed,
+            stopped
+        })
+    }
+    
+    /// Whether devices have changed since this was last called, None once
+    /// this can't be known anymore.
+    
+    pub fn take_devices_changed(self: &Self) -> Option<bool> {
+        match (self.devices_changed.swap(false, Ordering::SeqCst), self.stopped.load(Ordering::SeqCst)) {
+            (true, _) => Some(true),
+            (false, true) => None,
+            (false, false) => Some(false)
+        }
+    }
+    
+}
+
+impl Drop for PulseaudioEvents {
+    fn drop(self: &mut Self) {
+        self.pactl_process.kill().ok();
+        self.pactl_process.wait().ok();
+    }
+}
+
+/// Parse a line from "pactl subscribe", such as "Event 'new' on source #5".
+
+fn is_device_change_event(line: &str) -> bool {
+    let mut words = line.split_whitespace();
+    
+    match (words.next(), words.next(), words.next(), words.next()) {
+        (Some("Event"), Some(event_type), Some("on"), Some(facility)) => {
+            (event_type == "'new'" || event_type == "'remove'") &&
+            ["source", "sink", "sink-input", "card"].contains(&facility)
+        },
+        _ => false
+    }
+}
+
 /**
  * The struct below handles setting the audio input source of the application
  * to "Monitor of Built-in Analog Audio Stereo" instead of "Built-in Analog Audio
@@ -91,6 +227,96 @@ pub struct PulseaudioLoopback;
 
 impl PulseaudioLoopback {
     
+    /// List the PulseAudio sources, natively when compiled with the
+    /// "pulseaudio" feature, and through "pactl" otherwise (or if connecting
+    /// to the server natively failed).
+    
+    pub fn list_sources() -> Option<Vec<PulseaudioSource>> {
+        Some(Self::list_indexed_sources()?.into_iter().map(|(_, source)| source).collect())
+    }
+    
+    /// The sources along with their indexes, which recording streams refer
+    /// to.
+    
+    fn list_indexed_sources() -> Option<Vec<(u64, PulseaudioSource)>> {
+        #[cfg(feature = "pulseaudio")]
+        {
+            if let Some(sources) = Self::list_sources_natively() {
+                return Some(sources);
+            }
+        }
+        
+        Self::list_sources_with_pactl()
+    }
+    
+    #[cfg(feature = "pulseaudio")]
+    fn connect_natively() -> Option<(Mainloop, context::Context)> {
+        let mut mainloop = Mainloop::new()?;
+        let mut context = context::Context::new(&mainloop, "SongRec")?;
+        
+        context.connect(None, context::FlagSet::NOFLAGS, None).ok()?;
+        
+        // Wait for the connection to the server to be established
+        
+        loop {
+            match mainloop.iterate(true) {
+                IterateResult::Success(_) => { },
+                _ => { return None }
+            };
+            match context.get_state() {
+                context::State::Ready => { break },
+                context::State::Failed | context::State::Terminated => { return None },
+                _ => { }
+            };
+        }
+        
+        Some((mainloop, context))
+    }
+    
+    #[cfg(feature = "pulseaudio")]
+    fn list_sources_natively() -> Option<Vec<(u64, PulseaudioSource)>> {
+        let (mut mainloop, mut context) = Self::connect_natively()?;
+        
+        let sources: Rc<RefCell<Vec<(u64, PulseaudioSource)>>> = Rc::new(RefCell::new(vec![]));
+        let sources_2 = sources.clone();
+        
+        let operation = context.introspect().get_source_info_list(move |result| {
+            if let ListResult::Item(info) = result {
+                let name = info.name.as_ref().map(|name| name.to_string()).unwrap_or_default();
+                
+                sources_2.borrow_mut().push((info.index as u64, PulseaudioSource {
+                    description: info.description.as_ref().map(|description| description.to_string()).unwrap_or_else(|| name.clone()),
+                    is_monitor: info.monitor_of_sink.is_some(),
+                    name
+                }));
+            }
+        });
+        
+        while operation.get_state() == operation::State::Running {
+            match mainloop.iterate(true) {
+                IterateResult::Success(_) => { },
+                _ => { return None }
+            };
+        }
+        
+        context.disconnect();
+        
+        let sources = sources.borrow().clone();
+        Some(sources)
+    }
+    
+    fn list_sources_with_pactl() -> Option<Vec<(u64, PulseaudioSource)>> {
+        let sources = Self::get_pactl_devices_info(PactlDevicesType::Sources)?;
+        
+        // "pactl list short sources" doesn't provide descriptions
+        
+        Some(sources.into_iter().map(|source| (source.index, PulseaudioSource {
+            description: source.name.clone(),
+            is_monitor: source.is_monitor,
+            name: source.name
+        })).collect())
+    }
+    
     pub fn check_whether_pactl_is_available() -> bool {
         match Command::new("pactl").args(&["list", "short", "source-outputs"]).output() {
             Ok(output) => output.status.success(),
@@ -100,6 +326,200 @@ impl PulseaudioLoopback {
         }
     }
     
+    /// List the application streams, natively when compiled with the
+    /// "pulseaudio" feature, and through "pactl" otherwise.
+    
+    pub fn list_sink_inputs() -> Option<Vec<PulseaudioSinkInput>> {
+        #[cfg(feature = "pulseaudio")]
+        {
+            if let Some(sink_inputs) = Self::list_sink_inputs_natively() {
+                return Some(sink_inputs);
+            }
+        }
+        
+        Self::list_sink_inputs_with_pactl()
+    }
+    
+    #[cfg(feature = "pulseaudio")]
+    fn list_sink_inputs_natively() -> Option<Vec<PulseaudioSinkInput>> {
+        let (mut mainloop, mut context) = Self::connect_natively()?;
+        
+        let sink_inputs: Rc<RefCell<Vec<PulseaudioSinkInput>>> = Rc::new(RefCell::new(vec![]));
+        let sink_inputs_2 = sink_inputs.clone();
+        
+        let operation = context.introspect().get_sink_input_info_list(move |result| {
+            if let ListResult::Item(info) = result {
+                let application_name = info.proplist.get_str("application.process.binary")
+                    .or_else(|| info.proplist.get_str("application.name"));
+                
+                if let Some(application_name) = application_name {
+                    sink_inputs_2.borrow_mut().push(PulseaudioSinkInput {
+                        index: info.index as u64,
+                        sink_index: info.sink as u64,
+                        application_name: application_name
+                    });
+                }
+            }
+        });
+        
+        while operation.get_state() == operation::State::Running {
+            match mainloop.iterate(true) {
+                IterateResult::Success(_) => { },
+                _ => { return None }
+            };
+        }
+        
+        context.disconnect();
+        
+        let sink_inputs = sink_inputs.borrow().clone();
+        Some(sink_inputs)
+    }
+    
+    fn list_sink_inputs_with_pactl() -> Option<Vec<PulseaudioSinkInput>> {
+        let tsv_output = match Command::new("pactl").args(["list", "short", "sink-inputs"]).output() {
+            Ok(output) => output.stdout,
+            _ => { return None }
+        };
+        
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .has_headers(false)
+            .from_reader(tsv_output.as_slice());
+        
+        let mut sink_inputs: Vec<PulseaudioSinkInput> = vec![];
+        
+        for result in reader.deserialize() {
+            let record: PactlSinkInputTSVInfo = match result {
+                Ok(data) => data,
+                Err(error) => {
+                    eprintln!("{} \"pactl list short sink-inputs\": {:?}", gettext("Note: Could not parse TSV output from"), error);
+                    return None
+                }
+            };
+            
+            if let Ok(client_index) = record.client_index_or_dash.parse::<u64>() {
+                if let Some(process_name) = Self::get_pactl_client_process_name_from_index(client_index) {
+                    sink_inputs.push(PulseaudioSinkInput {
+                        index: record.index,
+                        sink_index: record.sink_index,
+                        application_name: process_name
+                    });
+                }
+            }
+        }
+        
+        Some(sink_inputs)
+    }
+    
+    /// List the indexes of the recording streams of SongRec (its "source
+    /// outputs"), natively when compiled with the "pulseaudio" feature, and
+    /// through "pactl" otherwise.
+    
+    pub fn list_songrec_source_outputs() -> Option<Vec<u64>> {
+        Some(Self::list_songrec_source_outputs_with_sources()?.into_iter().map(|(index, _)| index).collect())
+    }
+    
+    /// The indexes of the recording streams of SongRec, along with the
+    /// indexes of the sources they record from.
+    
+    fn list_songrec_source_outputs_with_sources() -> Option<Vec<(u64, u64)>> {
+        #[cfg(feature = "pulseaudio")]
+        {
+            if let Some(source_outputs) = Self::list_songrec_source_outputs_natively() {
+                return Some(source_outputs);
+            }
+        }
+        
+        let source_outputs = Self::get_pactl_devices_info(PactlDevicesType::SourceOutputs)?;
+        
+        Some(source_outputs.iter()
+            .filter(|source_output| source_output.name == "songrec")
+            .filter_map(|source_output| Some((source_output.index, source_output.source_index?)))
+            .collect())
+    }
+    
+    #[cfg(feature = "pulseaudio")]
+    fn list_songrec_source_outputs_natively() -> Option<Vec<(u64, u64)>> {
+        let (mut mainloop, mut context) = Self::connect_natively()?;
+        
+        let process_id = std::process::id().to_string();
+        
+        let source_outputs: Rc<RefCell<Vec<(u64, u64)>>> = Rc::new(RefCell::new(vec![]));
+        let source_outputs_2 = source_outputs.clone();
+        
+        let operation = context.introspect().get_source_output_info_list(move |result| {
+            if let ListResult::Item(info) = result {
+                if info.proplist.get_str("application.process.id").as_deref() == Some(process_id.as_str()) {
+                    source_outputs_2.borrow_mut().push((info.index as u64, info.source as u64));
+                }
+            }
+        });
+        
+        while operation.get_state() == operation::State::Running {
+            match mainloop.iterate(true) {
+                IterateResult::Success(_) => { },
+                _ => { return None }
+            };
+        }
+        
+        context.disconnect();
+        
+        let source_outputs = source_outputs.borrow().clone();
+        Some(source_outputs)
+    }
+    
+    /// Move a recording stream to the given source, natively when compiled
+    /// with the "pulseaudio" feature, and through "pactl" otherwise.
+    
+    pub fn move_source_output(source_output_index: u64, source_name: &str) -> Result<(), Box<dyn Error>> {
+        #[cfg(feature = "pulseaudio")]
+        {
+            if let Some(moved) = Self::move_source_output_natively(source_output_index, source_name) {
+                return match moved {
+                    true => Ok(()),
+                    false => Err(format!("{} {}", gettext("Unable to move the recording stream to"), source_name).into())
+                };
+            }
+        }
+        
+        let status = Command::new("pactl")
+            .args([
+                "move-source-output",
+                &format!("{}", source_output_index),
+                source_name
+            ]).status()?;
+        
+        if !status.success() {
+            return Err(format!("{} {}", gettext("Unable to move the recording stream to"), source_name).into());
+        }
+        
+        Ok(())
+    }
+    
+    #[cfg(feature = "pulseaudio")]
+    fn move_source_output_natively(source_output_index: u64, source_name: &str) -> Option<bool> {
+        let (mut mainloop, mut context) = Self::connect_natively()?;
+        
+        let moved: Rc<RefCell<bool>> = Rc::new(RefCell::new(false));
+        let moved_2 = moved.clone();
+        
+        let operation = context.introspect().move_source_output_by_name(source_output_index as u32, source_name, Some(Box::new(move |success| {
+            *moved_2.borrow_mut() = success;
+        })));
+        
+        while operation.get_state() == operation::State::Running {
+            match mainloop.iterate(true) {
+                IterateResult::Success(_) => { },
+                _ => { return None }
+            };
+        }
+        
+        context.disconnect();
+        
+        let moved = *moved.borrow();
+        Some(moved)
+    }
+    
     fn get_pactl_client_process_name_from_index(client_index: u64) -> Option<String> {
         
         let tsv_output = match Command::new("pactl").args(&["list", "short", "clients"]).output() {
@@ -196,154 +616,76 @@ impl PulseaudioLoopback {
         Some(output_items)
     }
     
-    fn get_songrec_source_output_index() -> Option<u64>
-    {    
-        let source_outputs = match Self::get_pactl_devices_info(PactlDevicesType::SourceOutputs) {
-            Some(result) => result,
-            _ => { return None }
-        };
-        
-        for source_output in source_outputs.iter() {
-            
-            if source_output.name == "songrec" {
-                return Some(source_output.index);
-            }
-        }
-        
-        None
-    }
+    /// The first monitor source (the output of the default sound card,
+    /// usually), or the first other source (its microphone).
     
-    fn get_audio_monitor_source_index() -> Option<u64>
-    {
-        let sources = match Self::get_pactl_devices_info(PactlDevicesType::Sources) {
-            Some(result) => result,
-            _ => { return None }
-        };
-        
-        for source in sources.iter() {
-            if source.is_monitor {
-                return Some(source.index);
-            }
-        }
-        
-        None
-
+    fn get_first_source(is_monitor: bool) -> Option<(u64, PulseaudioSource)> {
+        Self::list_indexed_sources()?.into_iter().find(|(_, source)| source.is_monitor == is_monitor)
     }
     
-    fn get_audio_monitor_source_name() -> Option<String>
-    {
-        let sources = match Self::get_pactl_devices_info(PactlDevicesType::Sources) {
-            Some(result) => result,
-            _ => { return None }
-        };
-        
-        for source in sources.iter() {
-            if source.is_monitor {
-                return Some(source.name.clone());
-            }
-        }
-        
-        None
-
-    }
-    
-    fn get_audio_non_monitor_source_index() -> Option<u64> {
-        let sources = match Self::get_pactl_devices_info(PactlDevicesType::Sources) {
-            Some(result) => result,
-            _ => { return None }
-        };
-        
-        for source in sources.iter() {
-            if !source.is_monitor {
-                return Some(source.index);
-            }
-        }
-        
-        None
-    }
+    /// The source the first recording stream of SongRec records from.
     
-    fn get_audio_non_monitor_source_name() -> Option<String> {
-        let sources = match Self::get_pactl_devices_info(PactlDevicesType::Sources) {
-            Some(result) => result,
-            _ => { return None }
-        };
-        
-        for source in sources.iter() {
-            if !source.is_monitor {
-                return Some(source.name.clone());
-            }
-        }
-        
-        None
+    fn get_songrec_source_index() -> Option<u64> {
+        Self::list_songrec_source_outputs_with_sources()?.first().map(|(_, source_index)| *source_index)
     }
     
     /// This function check whether SongRec is currently plugged towards what
     /// we identity as the default input or output
     
     pub fn get_whether_audio_source_is_known() -> Option<bool> {
-        let source_outputs = match Self::get_pactl_devices_info(PactlDevicesType::SourceOutputs) {
-            Some(result) => result,
-            _ => { return None }
-        };
+        let source_index = Self::get_songrec_source_index()?;
         
-        for source_output in source_outputs.iter() {
-            if source_output.name == "songrec" {
-                if let Some(source_index) = source_output.source_index {
-                    if let Some(audio_monitor_source_index) = Self::get_audio_monitor_source_index() {
-                        if let Some(audio_non_monitor_source_index) = Self::get_audio_non_monitor_source_index() {
-                            return Some(source_index == audio_monitor_source_index || source_index == audio_non_monitor_source_index);
-                         }
-                    }
-                }
-            }
-        }
+        let (monitor_source_index, _) = Self::get_first_source(true)?;
+        let (non_monitor_source_index, _) = Self::get_first_source(false)?;
         
-        None
+        Some(source_index == monitor_source_index || source_index == non_monitor_source_index)
     }
     
     /// This function check whether SongRec is currently plugged towards what
     /// we identity as the default output (the PulseAudio monitor device)
     
     pub fn get_whether_audio_source_is_monitor() -> Option<bool> {
-        let source_outputs = match Self::get_pactl_devices_info(PactlDevicesType::SourceOutputs) {
-            Some(result) => result,
-            _ => { return None }
-        };
+        let source_index = Self::get_songrec_source_index()?;
         
-        for source_output in source_outputs.iter() {
-            if source_output.name == "songrec" {
-                if let Some(source_index) = source_output.source_index {
-                    if let Some(audio_monitor_source_index) = Self::get_audio_monitor_source_index() {
-                        return Some(source_index == audio_monitor_source_index);
-                    }
-                }
-            }
-        }
+        let (monitor_source_index, _) = Self::get_first_source(true)?;
         
-        None
+        Some(source_index == monitor_source_index)
     }
     
     /// This is used to apply toggling the "Recognize from my speakers instead
     /// of microphone" checkbox of the UI
     
-    pub fn set_whether_audio_source_is_monitor(is_monitor: bool) {
+    pub fn set_whether_audio_source_is_monitor(is_monitor: bool) -> Result<(), Box<dyn Error>> {
         
-        let pulseaudio_source = match is_monitor {
-            true => Self::get_audio_monitor_source_name(),
-            false => Self::get_audio_non_monitor_source_name()
+        let source = match Self::get_first_source(is_monitor) {
+            Some((_, source)) => source,
+            None => { return Err(gettext("No matching PulseAudio source was found").into()) }
         };
         
-        if let Some(songrec_index) = Self::get_songrec_source_output_index() {
-            
-            if let Some(pulseaudio_source_name) = pulseaudio_source {
-            
-                Command::new("pactl")
-                    .args(&[
-                        "move-source-output",
-                        &format!("{}", songrec_index),
-                        &pulseaudio_source_name
-                    ]).status().unwrap();
-            }
-        }
+        let source_output_index = match Self::list_songrec_source_outputs().unwrap_or_default().first() {
+            Some(source_output_index) => *source_output_index,
+            None => { return Err(gettext("SongRec isn't recording through PulseAudio").into()) }
+        };
+        
+        Self::move_source_output(source_output_index, &source.name)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_change_events() {
+        assert!(is_device_change_event("Event 'new' on source #5"));
+        assert!(is_device_change_event("Event 'remove' on sink-input #12"));
+        assert!(is_device_change_event("Event 'new' on card #0"));
+
+        // Volume changes and recording streams (including ours) are ignored
+        assert!(!is_device_change_event("Event 'change' on source #5"));
+        assert!(!is_device_change_event("Event 'new' on source-output #3"));
+        assert!(!is_device_change_event("Event 'remove' on client #40"));
+        assert!(!is_device_change_event(""));
+    }
+
+}
@@ -0,0 +1,410 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+use gettextrs::gettext;
+use reqwest::Url;
+
+/// This module downloads a bounded amount of audio from an HTTP(S) URL, so
+/// that it can be recognized like a local file: a file served over HTTP, a
+/// live stream (such as a web radio), an M3U playlist pointing to either, or
+/// an HLS (.M3U8) playlist, the segments of which are concatenated.
+///
+/// Downloads stop after "max_bytes" bytes, or after "max_seconds" seconds of
+/// audio for HLS playlists (from the durations of their segments), or of
+/// downloading for other URLs (which, for live streams, amounts to about as
+/// much audio). A file served over HTTP should be small enough to fit in the
+/// byte limit, as most formats can't be decoded when truncated.
+
+const READ_TIMEOUT_SECONDS: u64 = 30;
+
+const MAXIMUM_PLAYLIST_BYTES: u64 = 1024 * 1024;
+const MAXIMUM_PLAYLIST_DEPTH: usize = 4; // For M3U playlists pointing to HLS master playlists pointing to media playlists
+
+const CHUNK_BYTES: usize = 64 * 1024;
+
+static USER_AGENT: &str = concat!("SongRec/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Clone, Debug)]
+pub struct DownloadLimits {
+    pub max_bytes: u64,
+    pub max_seconds: u64
+}
+
+impl Default for DownloadLimits {
+    fn default() -> Self {
+        DownloadLimits {
+            max_bytes: 16 * 1024 * 1024,
+            max_seconds: 20 // Shazam is sent the 12 seconds at the middle of the audio
+        }
+    }
+}
+
+enum Playlist {
+    Redirect(Url), // An M3U playlist entry, or the chosen variant of an HLS master playlist
+    Segments(Option<Url>, Vec<(Url, f32)>) // The initialization segment of an HLS media playlist if any, and its segments with their duration
+}
+
+pub fn is_url(input: &str) -> bool {
+
+    let input = input.to_lowercase();
+
+    input.starts_with("http://") || input.starts_with("https://")
+
+}
+
+/// Download audio from the given URL to a temporary file, which is removed
+/// when the returned path is dropped.
+
+pub fn download_audio(url: &str, limits: &DownloadLimits) -> Result<tempfile::TempPath, Box<dyn Error>> {
+
+    let client = reqwest::blocking::Client::builder()
+        .user_agent(USER_AGENT)
+        .timeout(Duration::from_secs(READ_TIMEOUT_SECONDS)) // For each read, so that streams can be read for longer
+        .build()?;
+
+    let mut url = Url::parse(url)?;
+
+    for _ in 0..MAXIMUM_PLAYLIST_DEPTH {
+
+        let mut response = client.get(url.clone()).send()?.error_for_status()?;
+
+        let content_type = response.headers().get(reqwest::header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .unwrap_or("")
+            .to_lowercase();
+
+        let extension = get_extension(&url);
+
+        let is_playlist = content_type.contains("mpegurl") || extension.as_deref() == Some("m3u") || extension.as_deref() == Some("m3u8");
+
+        if !is_playlist {
+            let (mut file, file_path) = create_temporary_file(extension.as_deref())?;
+
+            let written_bytes = copy_bounded(&mut response, &mut file, limits.max_bytes, Some((Instant::now(), limits.max_seconds)))?;
+
+            return match written_bytes {
+                0 => Err(gettext("No audio was received from this URL").into()),
+                _ => Ok(file_path)
+            };
+        }
+
+        let mut playlist: Vec<u8> = vec![];
+
+        copy_bounded(&mut response, &mut playlist, MAXIMUM_PLAYLIST_BYTES, None)?;
+
+        match parse_playlist(&String::from_utf8_lossy(&playlist), &url)? {
+            Playlist::Redirect(next_url) => {
+                url = next_url;
+            },
+            Playlist::Segments(initialization_segment, segments) => {
+                return download_segments(&client, initialization_segment, &segments, limits);
+            }
+        }
+
+    }
+
+    Err(gettext("Too many nested playlists at this URL").into())
+
+}
+
+/// Download the segments of an HLS media playlist, from the oldest one, and
+/// concatenate them (with the initialization segment first, for fragmented
+/// MP4 streams).
+
+fn download_segments(client: &reqwest::blocking::Client, initialization_segment: Option<Url>, segments: &[(Url, f32)], limits: &DownloadLimits) -> Result<tempfile::TempPath, Box<dyn Error>> {
+
+    let extension = segments.first().and_then(|(segment_url, _)| get_extension(segment_url));
+
+    let (mut file, file_path) = create_temporary_file(extension.as_deref())?;
+
+    let mut written_bytes: u64 = 0;
+
+    // Segments are served faster than real time, but a slow server should
+    // not make the download last longer than for other URLs
+    let download_start = Instant::now();
+    let deadline = Some((download_start, limits.max_seconds));
+
+    if let Some(initialization_segment) = initialization_segment {
+        let mut response = client.get(initialization_segment).send()?.error_for_status()?;
+
+        written_bytes += copy_bounded(&mut response, &mut file, limits.max_bytes, deadline)?;
+    }
+
+    let mut downloaded_seconds: f32 = 0.0;
+
+    for (segment_url, duration_seconds) in segments {
+
+        if written_bytes >= limits.max_bytes || downloaded_seconds >= limits.max_seconds as f32 ||
+            download_start.elapsed().as_secs() >= limits.max_seconds {
+            break;
+        }
+
+        let mut response = client.get(segment_url.clone()).send()?.error_for_status()?;
+
+        written_bytes += copy_bounded(&mut response, &mut file, limits.max_bytes - written_bytes, deadline)?;
+        downloaded_seconds += duration_seconds;
+
+    }
+
+    match downloaded_seconds > 0.0 {
+        true => Ok(file_path),
+        false => Err(gettext("The playlist at this URL contains no segment").into())
+    }
+
+}
+
+/// Copy from a reader until its end, or until the given number of bytes or
+/// the given duration (from the given start) is reached. Returns the number
+/// of bytes copied.
+
+fn copy_bounded<R: Read, W: Write>(reader: &mut R, writer: &mut W, max_bytes: u64, deadline: Option<(Instant, u64)>) -> Result<u64, Box<dyn Error>> {
+
+    let mut buffer = vec![0u8; CHUNK_BYTES];
+
+    let mut copied_bytes: u64 = 0;
+
+    while copied_bytes < max_bytes {
+
+        if let Some((start, max_seconds)) = deadline {
+            if start.elapsed().as_secs() >= max_seconds {
+                break;
+            }
+        }
+
+        let length = reader.read(&mut buffer)?;
+
+        if length == 0 {
+            break;
+        }
+
+        let length = length.min((max_bytes - copied_bytes) as usize);
+
+        writer.write_all(&buffer[..length])?;
+        copied_bytes += length as u64;
+
+    }
+
+    Ok(copied_bytes)
+
+}
+
+/// Parse an M3U playlist (taking its first entry), or an HLS master playlist
+/// (taking its audio rendition if any, or its variant stream with the lowest
+/// bandwidth otherwise, which is often audio only), or an HLS media playlist.
+
+fn parse_playlist(playlist: &str, base_url: &Url) -> Result<Playlist, Box<dyn Error>> {
+
+    let lines: Vec<&str> = playlist.lines().map(str::trim).filter(|line| !line.is_empty()).collect();
+
+    let mut initialization_segment: Option<Url> = None;
+    let mut segments: Vec<(Url, f32)> = vec![];
+    let mut is_media_playlist = false;
+
+    let mut best_variant: Option<(u64, Url)> = None;
+    let mut audio_rendition: Option<Url> = None;
+
+    let mut segment_duration: f32 = 0.0;
+    let mut variant_bandwidth: Option<u64> = None;
+
+    for line in lines {
+
+        // Extended M3U playlists of web radios use "#EXTINF" too, so only
+        // HLS tags tell that the entries are segments
+        if let Some(attributes) = line.strip_prefix("#EXTINF:") {
+            segment_duration = attributes.split(',').next().and_then(|duration| duration.trim().parse().ok()).unwrap_or(0.0);
+        }
+        else if line.starts_with("#EXT-X-TARGETDURATION") || line.starts_with("#EXT-X-VERSION") {
+            is_media_playlist = true;
+        }
+        else if let Some(attributes) = line.strip_prefix("#EXT-X-KEY:") {
+            if !attributes.contains("METHOD=NONE") {
+                return Err(gettext("The HLS stream at this URL is encrypted").into());
+            }
+        }
+        else if let Some(attributes) = line.strip_prefix("#EXT-X-MAP:") {
+            if let Some(uri) = get_attribute(attributes, "URI") {
+                initialization_segment = Some(base_url.join(&uri)?);
+            }
+        }
+        else if let Some(attributes) = line.strip_prefix("#EXT-X-MEDIA:") {
+            if audio_rendition.is_none() && get_attribute(attributes, "TYPE").as_deref() == Some("AUDIO") {
+                if let Some(uri) = get_attribute(attributes, "URI") {
+                    audio_rendition = Some(base_url.join(&uri)?);
+                }
+            }
+        }
+        else if let Some(attributes) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+            variant_bandwidth = Some(get_attribute(attributes, "BANDWIDTH").and_then(|bandwidth| bandwidth.parse().ok()).unwrap_or(u64::MAX));
+        }
+        else if line.starts_with('#') {
+            continue;
+        }
+        else if let Some(bandwidth) = variant_bandwidth.take() {
+            if best_variant.as_ref().filter(|(best_bandwidth, _)| *best_bandwidth <= bandwidth).is_none() {
+                best_variant = Some((bandwidth, base_url.join(line)?));
+            }
+        }
+        else if is_media_playlist {
+            segments.push((base_url.join(line)?, segment_duration));
+            segment_duration = 0.0;
+        }
+        else {
+            return Ok(Playlist::Redirect(base_url.join(line)?));
+        }
+
+    }
+
+    if let Some(audio_rendition) = audio_rendition {
+        return Ok(Playlist::Redirect(audio_rendition));
+    }
+
+    if let Some((_, variant_url)) = best_variant {
+        return Ok(Playlist::Redirect(variant_url));
+    }
+
+    match is_media_playlist {
+        true => Ok(Playlist::Segments(initialization_segment, segments)),
+        false => Err(gettext("The playlist at this URL is empty").into())
+    }
+
+}
+
+/// Read an attribute from the attribute list of an HLS tag, such as
+/// 'TYPE=AUDIO,URI="audio.m3u8"'.
+
+fn get_attribute(attributes: &str, name: &str) -> Option<String> {
+
+    let mut remaining = attributes;
+
+    while !remaining.is_empty() {
+
+        let (key, rest) = remaining.split_at(remaining.find('=')?);
+        let rest = &rest[1..];
+
+        let (value, rest) = match rest.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (&quoted[..end], quoted[end + 1..].trim_start_matches(','))
+            },
+            None => match rest.find(',') {
+                Some(end) => (&rest[..end], &rest[end + 1..]),
+                None => (rest, "")
+            }
+        };
+
+        if key.trim() == name {
+            return Some(value.to_string());
+        }
+
+        remaining = rest;
+
+    }
+
+    None
+
+}
+
+/// The extension of the last path segment of an URL, to help recognizing the
+/// format of the downloaded file.
+
+fn get_extension(url: &Url) -> Option<String> {
+
+    let file_name = url.path_segments()?.next_back()?;
+
+    let extension = file_name.rsplit_once('.')?.1.to_lowercase();
+
+    match !extension.is_empty() && extension.len() <= 5 && extension.chars().all(|character| character.is_ascii_alphanumeric()) {
+        true => Some(extension),
+        false => None
+    }
+
+}
+
+fn create_temporary_file(extension: Option<&str>) -> Result<(File, tempfile::TempPath), Box<dyn Error>> {
+
+    let suffix = extension.map(|extension| format!(".{}", extension)).unwrap_or_default();
+
+    let (file, file_path) = tempfile::Builder::new().prefix("songrec-").suffix(&suffix).tempfile()?.into_parts();
+
+    Ok((file, file_path))
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(playlist: &str) -> Playlist {
+        parse_playlist(playlist, &Url::parse("http://example.com/radio/live.m3u8").unwrap()).unwrap()
+    }
+
+    fn get_redirect(playlist: &str) -> String {
+        match parse(playlist) {
+            Playlist::Redirect(url) => url.to_string(),
+            Playlist::Segments(..) => panic!("Expected a redirection")
+        }
+    }
+
+    #[test]
+    fn m3u_playlists() {
+        assert_eq!(get_redirect("http://stream.example.org/radio.mp3\n"), "http://stream.example.org/radio.mp3");
+
+        // Extended M3U playlists of web radios are not HLS playlists
+        assert_eq!(get_redirect("#EXTM3U\r\n#EXTINF:-1,Radio X\r\nhttp://stream.example.org/radio.mp3\r\n"), "http://stream.example.org/radio.mp3");
+        assert_eq!(get_redirect("#EXTM3U\n\n#EXTINF:-1,Radio X\nstream.aac\nother.aac\n"), "http://example.com/radio/stream.aac");
+
+        assert!(parse_playlist("#EXTM3U\n#EXTINF:-1,Radio X\n", &Url::parse("http://example.com/").unwrap()).is_err());
+    }
+
+    #[test]
+    fn master_playlists() {
+        assert_eq!(get_redirect("#EXTM3U\n#EXT-X-VERSION:3\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=256000,CODECS=\"mp4a.40.2\"\nhigh/index.m3u8\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=64000,CODECS=\"mp4a.40.5\"\nlow/index.m3u8\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=128000\nmedium/index.m3u8\n"), "http://example.com/radio/low/index.m3u8");
+
+        assert_eq!(get_redirect("#EXTM3U\n\
+            #EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",NAME=\"English\",URI=\"audio/en.m3u8\"\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=64000,AUDIO=\"audio\"\nvideo/index.m3u8\n"), "http://example.com/radio/audio/en.m3u8");
+    }
+
+    #[test]
+    fn media_playlists() {
+        let playlist = "#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-TARGETDURATION:6\n#EXT-X-MEDIA-SEQUENCE:1200\n\
+            #EXT-X-MAP:URI=\"init.mp4\"\n\
+            #EXTINF:6.0,\nsegment1200.m4s\n\
+            #EXTINF:5.5,title\nhttp://cdn.example.com/segment1201.m4s\n";
+
+        match parse(playlist) {
+            Playlist::Segments(initialization_segment, segments) => {
+                assert_eq!(initialization_segment.map(|url| url.to_string()).as_deref(), Some("http://example.com/radio/init.mp4"));
+                assert_eq!(segments.len(), 2);
+                assert_eq!(segments[0].0.as_str(), "http://example.com/radio/segment1200.m4s");
+                assert_eq!(segments[0].1, 6.0);
+                assert_eq!(segments[1].0.as_str(), "http://cdn.example.com/segment1201.m4s");
+                assert_eq!(segments[1].1, 5.5);
+            },
+            Playlist::Redirect(url) => panic!("Unexpected redirection to {}", url)
+        }
+
+        assert!(parse_playlist("#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXT-X-KEY:METHOD=AES-128,URI=\"key\"\n#EXTINF:6.0,\nsegment.ts\n",
+            &Url::parse("http://example.com/").unwrap()).is_err());
+    }
+
+    #[test]
+    fn attributes() {
+        let attributes = "TYPE=AUDIO,GROUP-ID=\"audio,stereo\",NAME=\"English\",DEFAULT=YES,URI=\"audio/en.m3u8\"";
+
+        assert_eq!(get_attribute(attributes, "TYPE").as_deref(), Some("AUDIO"));
+        assert_eq!(get_attribute(attributes, "GROUP-ID").as_deref(), Some("audio,stereo"));
+        assert_eq!(get_attribute(attributes, "DEFAULT").as_deref(), Some("YES"));
+        assert_eq!(get_attribute(attributes, "URI").as_deref(), Some("audio/en.m3u8"));
+        assert_eq!(get_attribute(attributes, "BANDWIDTH"), None);
+
+        assert_eq!(get_attribute("BANDWIDTH=64000", "BANDWIDTH").as_deref(), Some("64000"));
+        assert_eq!(get_attribute("METHOD=NONE", "URI"), None);
+        assert_eq!(get_attribute("", "URI"), None);
+    }
+
+}